@@ -0,0 +1,399 @@
+use apca::api::v2::{account_activities, order, position, positions};
+use apca::data::v2::{last_quote, last_trade};
+use apca::{ApiInfo, Client};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use num_decimal::Num;
+use std::collections::HashMap;
+
+use crate::broker::{
+    Broker, BrokerError, BrokerOrder, BrokerPosition, CashActivity, OrderClass, OrderId,
+    OrderRequest, OrderStatus, OrderType, Quote, ReplaceRequest, Side, SymbolDrain, TradeSink,
+    TradeUpdateSink,
+};
+use crate::cash_event::CashEventKind;
+use crate::lot::{OrderTimeInForce, PositionType};
+use crate::market_data_client::listen_for_trades;
+use crate::trade_update_client::listen_for_trade_updates;
+
+/// Broker implementation backed by the Alpaca trading API.
+pub struct AlpacaBroker {
+    api_info: ApiInfo,
+    client: Client,
+}
+
+impl AlpacaBroker {
+    pub fn new(api_info: ApiInfo) -> Self {
+        Self {
+            client: Client::new(api_info.clone()),
+            api_info,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(ApiInfo::from_env().unwrap())
+    }
+}
+
+#[async_trait]
+impl Broker for AlpacaBroker {
+    async fn place_order(&self, request: &OrderRequest) -> Result<BrokerOrder, BrokerError> {
+        let request = order::OrderReqInit {
+            client_order_id: request.client_order_id.clone(),
+            class: request.class.into(),
+            type_: request.type_.into(),
+            limit_price: request.limit_price.clone(),
+            stop_price: request.stop_price.clone(),
+            stop_loss: request.stop_loss.clone().map(order::StopLoss::Stop),
+            take_profit: request.take_profit.clone().map(order::TakeProfit::Limit),
+            trail_price: request.trail_price.clone(),
+            trail_percent: request.trail_percent.clone(),
+            time_in_force: request
+                .time_in_force
+                .map(order::TimeInForce::from)
+                .unwrap_or(order::TimeInForce::UntilCanceled),
+            ..Default::default()
+        }
+        .init(
+            request.sym.clone(),
+            request.side.into(),
+            order::Amount::quantity(request.qty.clone()),
+        );
+
+        self.client
+            .issue::<order::Post>(&request)
+            .await
+            .map(BrokerOrder::from)
+            .map_err(post_error)
+    }
+
+    async fn cancel_order(&self, id: &OrderId) -> Result<(), BrokerError> {
+        self.client
+            .issue::<order::Delete>(&apca_id(id)?)
+            .await
+            .map_err(|e| BrokerError::Other(e.to_string()))
+    }
+
+    async fn replace_order(
+        &self,
+        id: &OrderId,
+        request: &ReplaceRequest,
+    ) -> Result<BrokerOrder, BrokerError> {
+        let change = order::ChangeReq {
+            quantity: request.qty.clone(),
+            time_in_force: request.time_in_force.map(order::TimeInForce::from),
+            limit_price: request.limit_price.clone(),
+            stop_price: request.stop_price.clone(),
+            trail: request.trail.clone(),
+            ..Default::default()
+        };
+        self.client
+            .issue::<order::Patch>(&(apca_id(id)?, change))
+            .await
+            .map(BrokerOrder::from)
+            .map_err(|e| BrokerError::Rejected(e.to_string()))
+    }
+
+    async fn get_order(&self, id: &OrderId) -> Result<BrokerOrder, BrokerError> {
+        self.client
+            .issue::<order::Get>(&apca_id(id)?)
+            .await
+            .map(BrokerOrder::from)
+            .map_err(get_error)
+    }
+
+    async fn get_order_by_client_id(&self, client_id: &str) -> Result<BrokerOrder, BrokerError> {
+        self.client
+            .issue::<order::GetByClientId>(&client_id.to_string())
+            .await
+            .map(BrokerOrder::from)
+            .map_err(get_error)
+    }
+
+    async fn list_positions(&self) -> Result<Vec<BrokerPosition>, BrokerError> {
+        let positions = self
+            .client
+            .issue::<positions::Get>(&())
+            .await
+            .map_err(|e| BrokerError::Other(e.to_string()))?;
+        Ok(positions.into_iter().map(BrokerPosition::from).collect())
+    }
+
+    async fn latest_trades(&self, syms: &[String]) -> Result<HashMap<String, Num>, BrokerError> {
+        if syms.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let request = last_trade::LastTradeRequest::new(syms.to_vec());
+        let trades = self
+            .client
+            .issue::<last_trade::Get>(&request)
+            .await
+            .map_err(|e| BrokerError::Other(e.to_string()))?;
+        Ok(trades
+            .into_iter()
+            .map(|trade| (trade.symbol, trade.price))
+            .collect())
+    }
+
+    async fn latest_quote(&self, sym: &str) -> Result<Quote, BrokerError> {
+        let request = last_quote::LastQuoteReq::new(vec![sym.to_string()]);
+        let quote = self
+            .client
+            .issue::<last_quote::Get>(&request)
+            .await
+            .map_err(|e| BrokerError::Other(e.to_string()))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| BrokerError::NotFound(format!("No quote for {}", sym)))?;
+        Ok(Quote {
+            sym: quote.symbol,
+            bid: Some(quote.bid_price),
+            ask: Some(quote.ask_price),
+            timestamp: quote.time,
+        })
+    }
+
+    async fn cash_activities(
+        &self,
+        after: Option<DateTime<Utc>>,
+    ) -> Result<Vec<CashActivity>, BrokerError> {
+        let mut activities = vec![];
+        let mut request = account_activities::ActivityReq {
+            types: vec![
+                account_activities::ActivityType::Dividend,
+                account_activities::ActivityType::Interest,
+            ],
+            direction: account_activities::Direction::Ascending,
+            after,
+            ..Default::default()
+        };
+        // the API pages through results, picking up after the last activity of the previous page
+        loop {
+            let page = self
+                .client
+                .issue::<account_activities::Get>(&request)
+                .await
+                .map_err(|e| BrokerError::Other(e.to_string()))?;
+            let last_id = match page.last() {
+                Some(last) => last.id().to_string(),
+                None => break,
+            };
+            activities.extend(page.into_iter().filter_map(cash_activity));
+            request.page_token = Some(last_id);
+        }
+        Ok(activities)
+    }
+
+    async fn stream_updates(&self, sink: TradeUpdateSink) -> Result<(), BrokerError> {
+        listen_for_trade_updates(&self.api_info, sink)
+            .await
+            .map_err(|e| BrokerError::Other(e.to_string()))
+    }
+
+    /// Trades come from the iex feed, or the one named in ZOOCARP_DATA_FEED.
+    async fn stream_trades(&self, syms: SymbolDrain, sink: TradeSink) -> Result<(), BrokerError> {
+        let feed = std::env::var("ZOOCARP_DATA_FEED").unwrap_or_else(|_| "iex".to_string());
+        listen_for_trades(&self.api_info, &feed, syms, sink)
+            .await
+            .map_err(|e| BrokerError::Other(e.to_string()))
+    }
+}
+
+// only non-trade dividend and interest activities are cash events
+fn cash_activity(activity: account_activities::Activity) -> Option<CashActivity> {
+    let activity = match activity {
+        account_activities::Activity::NonTrade(activity) => activity,
+        account_activities::Activity::Trade(_) => return None,
+    };
+    let kind = match activity.type_ {
+        account_activities::ActivityType::Dividend if activity.net_amount < Num::from(0) => {
+            CashEventKind::ShortDividend
+        }
+        account_activities::ActivityType::Dividend => CashEventKind::Dividend,
+        account_activities::ActivityType::Interest => CashEventKind::Interest,
+        _ => return None,
+    };
+    Some(CashActivity {
+        id: activity.id,
+        kind,
+        sym: activity.symbol,
+        qty: activity.quantity,
+        per_share_amount: activity.per_share_amount,
+        net_amount: activity.net_amount,
+        description: activity.description,
+        date: activity.date,
+    })
+}
+
+fn apca_id(id: &OrderId) -> Result<order::Id, BrokerError> {
+    match uuid::Uuid::parse_str(&id.0) {
+        Ok(uuid) => Ok(order::Id(uuid)),
+        Err(_) => Err(BrokerError::NotFound(format!("invalid order id {}", id))),
+    }
+}
+
+// extract helpful API error responses from the apca RequestError
+fn post_error(e: apca::RequestError<order::PostError>) -> BrokerError {
+    match e {
+        apca::RequestError::Endpoint(order::PostError::InvalidInput(Ok(api_error)))
+        | apca::RequestError::Endpoint(order::PostError::NotPermitted(Ok(api_error))) => {
+            BrokerError::Rejected(api_error.message)
+        }
+        _ => BrokerError::Rejected(e.to_string()),
+    }
+}
+
+// : Endpoint(NotFound(Ok(ApiError { code: 40410000, message: "order not found for e131881b-d6b0-4378-a5d5-cd419c4d3d34" })))
+fn get_error(e: apca::RequestError<order::GetError>) -> BrokerError {
+    match e {
+        apca::RequestError::Endpoint(order::GetError::NotFound(_)) => {
+            BrokerError::NotFound(e.to_string())
+        }
+        _ => BrokerError::Other(e.to_string()),
+    }
+}
+
+impl From<order::Order> for BrokerOrder {
+    fn from(order: order::Order) -> Self {
+        let qty = match &order.amount {
+            order::Amount::Quantity { quantity } => Some(quantity.clone()),
+            _ => None,
+        };
+        Self {
+            id: OrderId(order.id.as_hyphenated().to_string()),
+            client_order_id: order.client_order_id,
+            status: order.status.into(),
+            sym: order.symbol,
+            side: order.side.into(),
+            type_: order.type_.into(),
+            class: order.class.into(),
+            qty,
+            filled_quantity: order.filled_quantity,
+            average_fill_price: order.average_fill_price,
+            limit_price: order.limit_price,
+            stop_price: order.stop_price,
+            trail_price: order.trail_price,
+            trail_percent: order.trail_percent,
+            // apca's order has no high-water mark, it only comes with trade updates as `hwm`
+            high_water_mark: None,
+            created_at: order.created_at,
+            filled_at: order.filled_at,
+            legs: order.legs.into_iter().map(BrokerOrder::from).collect(),
+        }
+    }
+}
+
+impl From<position::Position> for BrokerPosition {
+    fn from(position: position::Position) -> Self {
+        Self {
+            sym: position.symbol,
+            qty: position.quantity,
+            side: match position.side {
+                position::Side::Long => PositionType::Long,
+                position::Side::Short => PositionType::Short,
+            },
+            avg_entry_price: position.average_entry_price,
+            current_price: position.current_price,
+            market_value: position.market_value,
+            unrealized_pl: position.unrealized_gain_total,
+        }
+    }
+}
+
+impl From<order::Status> for OrderStatus {
+    fn from(status: order::Status) -> Self {
+        match status {
+            order::Status::New => OrderStatus::New,
+            order::Status::Replaced => OrderStatus::Replaced,
+            order::Status::PartiallyFilled => OrderStatus::PartiallyFilled,
+            order::Status::Filled => OrderStatus::Filled,
+            order::Status::DoneForDay => OrderStatus::DoneForDay,
+            order::Status::Canceled => OrderStatus::Canceled,
+            order::Status::Expired => OrderStatus::Expired,
+            order::Status::Accepted => OrderStatus::Accepted,
+            order::Status::PendingNew => OrderStatus::PendingNew,
+            order::Status::AcceptedForBidding => OrderStatus::AcceptedForBidding,
+            order::Status::PendingCancel => OrderStatus::PendingCancel,
+            order::Status::PendingReplace => OrderStatus::PendingReplace,
+            order::Status::Stopped => OrderStatus::Stopped,
+            order::Status::Rejected => OrderStatus::Rejected,
+            order::Status::Suspended => OrderStatus::Suspended,
+            order::Status::Calculated => OrderStatus::Calculated,
+            order::Status::Held => OrderStatus::Held,
+            _ => OrderStatus::Unknown,
+        }
+    }
+}
+
+impl From<order::Side> for Side {
+    fn from(side: order::Side) -> Self {
+        match side {
+            order::Side::Buy => Side::Buy,
+            order::Side::Sell => Side::Sell,
+        }
+    }
+}
+
+impl From<Side> for order::Side {
+    fn from(side: Side) -> Self {
+        match side {
+            Side::Buy => order::Side::Buy,
+            Side::Sell => order::Side::Sell,
+        }
+    }
+}
+
+impl From<order::Type> for OrderType {
+    fn from(type_: order::Type) -> Self {
+        match type_ {
+            order::Type::Market => OrderType::Market,
+            order::Type::Limit => OrderType::Limit,
+            order::Type::Stop => OrderType::Stop,
+            order::Type::StopLimit => OrderType::StopLimit,
+            order::Type::TrailingStop => OrderType::TrailingStop,
+        }
+    }
+}
+
+impl From<OrderType> for order::Type {
+    fn from(type_: OrderType) -> Self {
+        match type_ {
+            OrderType::Market => order::Type::Market,
+            OrderType::Limit => order::Type::Limit,
+            OrderType::Stop => order::Type::Stop,
+            OrderType::StopLimit => order::Type::StopLimit,
+            OrderType::TrailingStop => order::Type::TrailingStop,
+        }
+    }
+}
+
+impl From<order::Class> for OrderClass {
+    fn from(class: order::Class) -> Self {
+        match class {
+            order::Class::Simple => OrderClass::Simple,
+            order::Class::Bracket => OrderClass::Bracket,
+            order::Class::OneCancelsOther => OrderClass::OneCancelsOther,
+            order::Class::OneTriggersOther => OrderClass::OneTriggersOther,
+        }
+    }
+}
+
+impl From<OrderClass> for order::Class {
+    fn from(class: OrderClass) -> Self {
+        match class {
+            OrderClass::Simple => order::Class::Simple,
+            OrderClass::Bracket => order::Class::Bracket,
+            OrderClass::OneCancelsOther => order::Class::OneCancelsOther,
+            OrderClass::OneTriggersOther => order::Class::OneTriggersOther,
+        }
+    }
+}
+
+impl From<OrderTimeInForce> for order::TimeInForce {
+    fn from(time_in_force: OrderTimeInForce) -> Self {
+        match time_in_force {
+            OrderTimeInForce::Day => order::TimeInForce::Day,
+            OrderTimeInForce::UntilCanceled => order::TimeInForce::UntilCanceled,
+        }
+    }
+}
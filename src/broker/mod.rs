@@ -0,0 +1,409 @@
+pub mod alpaca;
+pub mod simulator;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use num_decimal::Num;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use turbosql::{ToSql, ToSqlOutput};
+
+use crate::cash_event::CashEventKind;
+use crate::lot::{OrderTimeInForce, PositionType};
+use crate::sync_lots::TradeUpdate;
+
+pub type TradeUpdateSink = async_channel::Sender<TradeUpdate>;
+pub type TradeUpdateDrain = async_channel::Receiver<TradeUpdate>;
+pub type TradeSink = async_channel::Sender<Trade>;
+/// Symbols to stream trades of, each message replacing the ones before
+pub type SymbolDrain = async_channel::Receiver<Vec<String>>;
+
+/// ID of an order in the broker system.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct OrderId(pub String);
+
+impl ToSql for OrderId {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, turbosql::rusqlite::Error> {
+        Ok(ToSqlOutput::from(serde_json::json!(self).to_string()))
+    }
+}
+
+impl fmt::Display for OrderId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The status of an order in the broker system. Names follow the Alpaca wire format, which is also
+/// the format of the trade updates we parse.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    New,
+    Replaced,
+    PartiallyFilled,
+    Filled,
+    DoneForDay,
+    Canceled,
+    Expired,
+    Accepted,
+    PendingNew,
+    AcceptedForBidding,
+    PendingCancel,
+    PendingReplace,
+    Stopped,
+    Rejected,
+    Suspended,
+    Calculated,
+    Held,
+    #[serde(other)]
+    Unknown,
+}
+
+impl OrderStatus {
+    /// Whether the order will not see any further changes.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            OrderStatus::Replaced
+                | OrderStatus::Filled
+                | OrderStatus::Canceled
+                | OrderStatus::Expired
+                | OrderStatus::Rejected
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    #[default]
+    Buy,
+    Sell,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderType {
+    #[default]
+    Market,
+    Limit,
+    Stop,
+    StopLimit,
+    TrailingStop,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderClass {
+    #[default]
+    Simple,
+    Bracket,
+    #[serde(rename = "oco")]
+    OneCancelsOther,
+    #[serde(rename = "oto")]
+    OneTriggersOther,
+}
+
+fn vec_from_null<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(Option::<Vec<T>>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// An order as reported by the broker, including any legs.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BrokerOrder {
+    pub id: OrderId,
+    pub client_order_id: String,
+    pub status: OrderStatus,
+    #[serde(rename = "symbol")]
+    pub sym: String,
+    pub side: Side,
+    #[serde(rename = "type")]
+    pub type_: OrderType,
+    #[serde(rename = "order_class", default)]
+    pub class: OrderClass,
+    pub qty: Option<Num>,
+    #[serde(rename = "filled_qty")]
+    pub filled_quantity: Num,
+    #[serde(rename = "filled_avg_price")]
+    pub average_fill_price: Option<Num>,
+    pub limit_price: Option<Num>,
+    pub stop_price: Option<Num>,
+    /// Dollar offset of a trailing stop
+    #[serde(default)]
+    pub trail_price: Option<Num>,
+    /// Percent offset of a trailing stop
+    #[serde(default)]
+    pub trail_percent: Option<Num>,
+    /// Best price seen since a trailing stop was placed, the highest for a sell and the lowest for
+    /// a buy
+    #[serde(rename = "hwm", default)]
+    pub high_water_mark: Option<Num>,
+    pub created_at: DateTime<Utc>,
+    pub filled_at: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "vec_from_null")]
+    pub legs: Vec<BrokerOrder>,
+}
+
+impl BrokerOrder {
+    /// The order itself, without its legs, followed by the legs. For a one-cancels-other order
+    /// these are the two sibling exits.
+    pub fn flatten(&self) -> Vec<BrokerOrder> {
+        let mut parent = self.clone();
+        let legs = std::mem::take(&mut parent.legs);
+        let mut orders = vec![parent];
+        orders.extend(legs);
+        orders
+    }
+}
+
+/// A request for a new order. Bracket orders carry `take_profit` and `stop_loss` prices for the legs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OrderRequest {
+    pub client_order_id: Option<String>,
+    pub sym: String,
+    pub side: Side,
+    pub qty: Num,
+    pub class: OrderClass,
+    pub type_: OrderType,
+    pub time_in_force: Option<OrderTimeInForce>,
+    pub limit_price: Option<Num>,
+    pub stop_price: Option<Num>,
+    pub take_profit: Option<Num>,
+    pub stop_loss: Option<Num>,
+    /// Offset of a trailing stop order, either in dollars or in percent
+    pub trail_price: Option<Num>,
+    pub trail_percent: Option<Num>,
+}
+
+impl OrderRequest {
+    /// Check that the request carries the prices its type and class need. Brokers reject these
+    /// anyway, this lets us say why before anything is stored.
+    pub fn validate(&self) -> Result<(), BrokerError> {
+        let zero = Num::from(0);
+        if self.qty <= zero {
+            return Err(BrokerError::Rejected("qty must be > 0".into()));
+        }
+        match self.type_ {
+            // the limit of a one-cancels-other order is its take profit
+            OrderType::Limit | OrderType::StopLimit
+                if self.limit_price.is_none() && self.class != OrderClass::OneCancelsOther =>
+            {
+                return Err(BrokerError::Rejected("limit_price is required".into()));
+            }
+            OrderType::Stop | OrderType::StopLimit if self.stop_price.is_none() => {
+                return Err(BrokerError::Rejected("stop_price is required".into()));
+            }
+            OrderType::TrailingStop
+                if self.trail_price.is_some() == self.trail_percent.is_some() =>
+            {
+                return Err(BrokerError::Rejected(
+                    "one of trail_price or trail_percent is required".into(),
+                ));
+            }
+            _ => {}
+        }
+        match self.class {
+            OrderClass::Bracket | OrderClass::OneCancelsOther => {
+                match (&self.take_profit, &self.stop_loss) {
+                    (Some(take_profit), Some(stop_loss))
+                        if take_profit > &zero && stop_loss > &zero => {}
+                    _ => {
+                        return Err(BrokerError::Rejected(
+                            "bracket and oco orders require take_profit and stop_loss".into(),
+                        ))
+                    }
+                }
+            }
+            OrderClass::OneTriggersOther => match (&self.take_profit, &self.stop_loss) {
+                (Some(price), None) | (None, Some(price)) if price > &zero => {}
+                _ => {
+                    return Err(BrokerError::Rejected(
+                        "oto orders require one of take_profit or stop_loss".into(),
+                    ))
+                }
+            },
+            _ if self.take_profit.is_some() || self.stop_loss.is_some() => {
+                return Err(BrokerError::Rejected(
+                    "take_profit and stop_loss are only valid on bracket, oco and oto orders"
+                        .into(),
+                ));
+            }
+            _ => {}
+        }
+        if self.class == OrderClass::OneCancelsOther && self.type_ != OrderType::Limit {
+            return Err(BrokerError::Rejected(
+                "oco orders must be limit orders".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Changes to an existing open order. Fields left as `None` are unchanged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplaceRequest {
+    pub qty: Option<Num>,
+    pub time_in_force: Option<OrderTimeInForce>,
+    pub limit_price: Option<Num>,
+    pub stop_price: Option<Num>,
+    /// New offset of a trailing stop, in the unit it was placed with
+    pub trail: Option<Num>,
+}
+
+/// A position currently held at the broker.
+#[derive(Clone, Debug, Serialize)]
+pub struct BrokerPosition {
+    #[serde(rename = "symbol")]
+    pub sym: String,
+    pub qty: Num,
+    pub side: PositionType,
+    pub avg_entry_price: Num,
+    pub current_price: Option<Num>,
+    pub market_value: Option<Num>,
+    pub unrealized_pl: Option<Num>,
+}
+
+/// A trade printed on the market data stream.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Trade {
+    pub sym: String,
+    pub price: Num,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Best bid and ask for a symbol.
+#[derive(Clone, Debug, Serialize)]
+pub struct Quote {
+    #[serde(rename = "symbol")]
+    pub sym: String,
+    pub bid: Option<Num>,
+    pub ask: Option<Num>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Cash credited or debited to the account outside of trades: dividends and interest.
+#[derive(Clone, Debug, Serialize)]
+pub struct CashActivity {
+    /// ID of the activity in the broker system
+    pub id: String,
+    pub kind: CashEventKind,
+    /// Symbol the cash was paid on, None for interest
+    #[serde(rename = "symbol")]
+    pub sym: Option<String>,
+    /// Shares the cash was paid on
+    pub qty: Option<Num>,
+    pub per_share_amount: Option<Num>,
+    /// Positive when received, negative when paid
+    pub net_amount: Num,
+    pub description: Option<String>,
+    /// When the cash was paid, for a dividend its pay date. Alpaca does not report the ex-date or
+    /// record date of a dividend.
+    pub date: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub enum BrokerError {
+    /// The order does not exist on the broker system.
+    NotFound(String),
+    /// The broker refused the request, with the reason given.
+    Rejected(String),
+    Other(String),
+}
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BrokerError::NotFound(msg) => write!(f, "not found: {}", msg),
+            BrokerError::Rejected(msg) => write!(f, "{}", msg),
+            BrokerError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for BrokerError {}
+
+/// The operations zoocarp needs from a broker. Everything in and out is in the neutral order model
+/// so `Lot` never sees broker specific types.
+#[async_trait]
+pub trait Broker: Send + Sync {
+    async fn place_order(&self, request: &OrderRequest) -> Result<BrokerOrder, BrokerError>;
+
+    async fn cancel_order(&self, id: &OrderId) -> Result<(), BrokerError>;
+
+    async fn replace_order(
+        &self,
+        id: &OrderId,
+        request: &ReplaceRequest,
+    ) -> Result<BrokerOrder, BrokerError>;
+
+    async fn get_order(&self, id: &OrderId) -> Result<BrokerOrder, BrokerError>;
+
+    async fn get_order_by_client_id(&self, client_id: &str) -> Result<BrokerOrder, BrokerError>;
+
+    async fn list_positions(&self) -> Result<Vec<BrokerPosition>, BrokerError>;
+
+    /// Latest trade price for each of `syms`, in one request. Symbols without a trade are left
+    /// out of the result.
+    async fn latest_trades(&self, syms: &[String]) -> Result<HashMap<String, Num>, BrokerError>;
+
+    /// Latest quote for `sym`, NotFound if there is none.
+    async fn latest_quote(&self, sym: &str) -> Result<Quote, BrokerError>;
+
+    /// Dividend and interest activities on the account, oldest first, after `after` if given.
+    async fn cash_activities(
+        &self,
+        after: Option<DateTime<Utc>>,
+    ) -> Result<Vec<CashActivity>, BrokerError>;
+
+    /// Start forwarding trade updates for all orders to `sink`. Returns once the stream is
+    /// established; updates are delivered from a background task.
+    async fn stream_updates(&self, sink: TradeUpdateSink) -> Result<(), BrokerError>;
+
+    /// Start forwarding trades to `sink` for the symbols last received on `syms`, which can
+    /// change them at any time. Returns once the stream is established; trades are delivered
+    /// from a background task.
+    async fn stream_trades(&self, syms: SymbolDrain, sink: TradeSink) -> Result<(), BrokerError>;
+}
+
+#[test]
+fn test_validate_order_request() {
+    let bracket = OrderRequest {
+        sym: "TEST".to_string(),
+        side: Side::Buy,
+        qty: Num::from(100),
+        class: OrderClass::Bracket,
+        type_: OrderType::Limit,
+        limit_price: Some(Num::from(10)),
+        take_profit: Some(Num::from(12)),
+        stop_loss: Some(Num::from(9)),
+        ..Default::default()
+    };
+    assert!(bracket.validate().is_ok());
+
+    let mut request = bracket.clone();
+    request.take_profit = None;
+    assert!(request.validate().is_err());
+    request.take_profit = Some(Num::from(0));
+    assert!(request.validate().is_err());
+
+    let simple = OrderRequest {
+        class: OrderClass::Simple,
+        take_profit: None,
+        stop_loss: None,
+        ..bracket
+    };
+    assert!(simple.validate().is_ok());
+
+    let stop_entry = OrderRequest {
+        type_: OrderType::Stop,
+        limit_price: None,
+        ..simple
+    };
+    assert!(stop_entry.validate().is_err());
+}
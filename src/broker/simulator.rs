@@ -0,0 +1,714 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use num_decimal::Num;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::read_to_string;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::broker::{
+    Broker, BrokerError, BrokerOrder, BrokerPosition, CashActivity, OrderClass, OrderId,
+    OrderRequest, OrderStatus, OrderType, Quote, ReplaceRequest, Side, SymbolDrain, Trade,
+    TradeSink, TradeUpdateSink,
+};
+use crate::cash_event::CashEventKind;
+use crate::lot::PositionType;
+use crate::sync_lots::{LotUpdateEvent, TradeUpdate};
+
+/// A print from a replayed market data feed. Bid and ask are optional, when missing orders fill at
+/// the trade price.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Tick {
+    pub timestamp: DateTime<Utc>,
+    #[serde(rename = "symbol")]
+    pub sym: String,
+    pub price: Num,
+    pub bid: Option<Num>,
+    pub ask: Option<Num>,
+    /// Shares available at this print, unlimited if missing. Used to produce partial fills.
+    pub size: Option<Num>,
+}
+
+/// Read a feed file with one JSON encoded `Tick` per line.
+pub fn load_ticks(path: &str) -> Result<Vec<Tick>, Box<dyn Error>> {
+    let mut ticks = vec![];
+    for line in read_to_string(path)?.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        ticks.push(serde_json::from_str(line)?);
+    }
+    Ok(ticks)
+}
+
+#[derive(Default)]
+struct Holding {
+    qty: Num,
+    avg_entry_price: Num,
+}
+
+/// A subscriber to the trades of the symbols it last asked for.
+struct TradeFeed {
+    syms: SymbolDrain,
+    sink: TradeSink,
+    watched: HashSet<String>,
+}
+
+#[derive(Default)]
+struct Book {
+    orders: Vec<BrokerOrder>,
+    holdings: HashMap<String, Holding>,
+    last: HashMap<String, Tick>,
+    sinks: Vec<TradeUpdateSink>,
+    now: Option<DateTime<Utc>>,
+    cash: Vec<CashActivity>,
+    /// Reason to refuse the next order placed with
+    reject_next: Option<String>,
+    feeds: Vec<TradeFeed>,
+}
+
+/// An in-process paper trading broker. Orders are matched against ticks fed to `on_tick`, either
+/// directly or from a recorded feed with `replay`, and trade updates are emitted in the same shape
+/// as the Alpaca stream.
+#[derive(Default)]
+pub struct SimulatedBroker {
+    book: Mutex<Book>,
+}
+
+impl SimulatedBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Match all working orders for the tick's symbol against it.
+    pub fn on_tick(&self, tick: Tick) {
+        let mut book = self.book.lock().unwrap();
+        book.now = Some(tick.timestamp);
+
+        let mut updates = vec![];
+        let mut available = tick.size.clone();
+        for order in book.orders.iter_mut().filter(|o| o.sym == tick.sym) {
+            match_order(order, &tick, &mut available, &mut updates);
+        }
+        book.publish(Trade {
+            sym: tick.sym.clone(),
+            price: tick.price.clone(),
+            timestamp: tick.timestamp,
+        });
+        book.last.insert(tick.sym.clone(), tick);
+
+        for update in updates {
+            book.emit(update);
+        }
+    }
+
+    /// Pay a dividend of `per_share` on the shares of `sym` held now. A short holding owes it.
+    pub fn pay_dividend(&self, sym: &str, per_share: Num) {
+        let mut book = self.book.lock().unwrap();
+        let qty = match book.holdings.get(sym) {
+            Some(holding) if holding.qty != Num::from(0) => holding.qty.clone(),
+            _ => return,
+        };
+        let activity = CashActivity {
+            id: Uuid::new_v4().to_string(),
+            kind: if qty < Num::from(0) {
+                CashEventKind::ShortDividend
+            } else {
+                CashEventKind::Dividend
+            },
+            sym: Some(sym.to_string()),
+            net_amount: &qty * &per_share,
+            qty: Some(qty),
+            per_share_amount: Some(per_share),
+            description: None,
+            date: book.now(),
+        };
+        book.cash.push(activity);
+    }
+
+    /// Refuse the next order placed, the way the broker does for a halted symbol or shares it
+    /// cannot locate.
+    pub fn reject_next(&self, reason: &str) {
+        self.book.lock().unwrap().reject_next = Some(reason.to_string());
+    }
+
+    /// Feed recorded ticks to the matching engine, sleeping between them. A `speed` of 2.0 replays
+    /// twice as fast as recorded, 0.0 replays without pausing.
+    pub async fn replay(&self, ticks: Vec<Tick>, speed: f64) {
+        let mut previous: Option<DateTime<Utc>> = None;
+        for tick in ticks {
+            if let Some(previous) = previous {
+                if let Ok(gap) = (tick.timestamp - previous).to_std() {
+                    if speed > 0.0 {
+                        tokio::time::sleep(gap.div_f64(speed)).await;
+                    }
+                }
+            }
+            previous = Some(tick.timestamp);
+            self.on_tick(tick);
+        }
+        tracing::info!("simulator: replay finished");
+    }
+}
+
+impl Book {
+    fn now(&self) -> DateTime<Utc> {
+        self.now.unwrap_or_else(Utc::now)
+    }
+
+    /// Send a trade to the feeds watching its symbol, taking up any change to the symbols first.
+    fn publish(&mut self, trade: Trade) {
+        self.feeds.retain_mut(|feed| {
+            while let Ok(syms) = feed.syms.try_recv() {
+                feed.watched = syms.into_iter().collect();
+            }
+            !feed.watched.contains(&trade.sym) || feed.sink.try_send(trade.clone()).is_ok()
+        });
+    }
+
+    fn emit(&mut self, update: TradeUpdate) {
+        if let Some((qty, price)) = fill_delta(&update) {
+            self.apply_fill(&update.order, qty, price);
+        }
+        tracing::debug!(
+            "simulator: {:?} {} {:?}",
+            update.event,
+            update.order.id,
+            update.order.status
+        );
+        self.sinks
+            .retain(|sink| sink.try_send(update.clone()).is_ok());
+    }
+
+    fn apply_fill(&mut self, order: &BrokerOrder, qty: Num, price: Num) {
+        let signed = match order.side {
+            Side::Buy => qty,
+            Side::Sell => Num::from(0) - qty,
+        };
+        let holding = self.holdings.entry(order.sym.clone()).or_default();
+        let new_qty = &holding.qty + &signed;
+        let zero = Num::from(0);
+        if holding.qty == zero || (holding.qty > zero) != (new_qty > zero) {
+            // opened, or flipped from long to short or back
+            holding.avg_entry_price = price;
+        } else if (signed > zero) == (holding.qty > zero) {
+            // added to the position
+            holding.avg_entry_price =
+                (&holding.avg_entry_price * &holding.qty + &price * &signed) / &new_qty;
+        }
+        holding.qty = new_qty;
+    }
+}
+
+/// The quantity and price of the latest execution carried by a fill update.
+fn fill_delta(update: &TradeUpdate) -> Option<(Num, Num)> {
+    match update.event {
+        LotUpdateEvent::Fill | LotUpdateEvent::PartialFill => {
+            Some((update.qty.clone()?, update.price.clone()?))
+        }
+        _ => None,
+    }
+}
+
+fn is_working(status: OrderStatus) -> bool {
+    matches!(
+        status,
+        OrderStatus::New | OrderStatus::Accepted | OrderStatus::PartiallyFilled
+    )
+}
+
+/// Price the order would execute at on this tick, if it is marketable.
+fn execution_price(order: &BrokerOrder, tick: &Tick) -> Option<Num> {
+    let touch = match order.side {
+        Side::Buy => tick.ask.clone().unwrap_or_else(|| tick.price.clone()),
+        Side::Sell => tick.bid.clone().unwrap_or_else(|| tick.price.clone()),
+    };
+    let limit_ok = |limit: &Num| match order.side {
+        Side::Buy => &touch <= limit,
+        Side::Sell => &touch >= limit,
+    };
+    let stop_hit = |stop: &Num| match order.side {
+        Side::Buy => &tick.price >= stop,
+        Side::Sell => &tick.price <= stop,
+    };
+
+    let marketable = match order.type_ {
+        OrderType::Market => true,
+        OrderType::Limit => limit_ok(order.limit_price.as_ref()?),
+        OrderType::Stop => stop_hit(order.stop_price.as_ref()?),
+        OrderType::StopLimit => {
+            stop_hit(order.stop_price.as_ref()?) && limit_ok(order.limit_price.as_ref()?)
+        }
+        OrderType::TrailingStop => stop_hit(order.stop_price.as_ref()?),
+    };
+    if marketable {
+        Some(touch)
+    } else {
+        None
+    }
+}
+
+/// Fill as much of the order as the tick allows, recording a trade update.
+fn fill(
+    order: &mut BrokerOrder,
+    price: Num,
+    available: &mut Option<Num>,
+    now: DateTime<Utc>,
+    updates: &mut Vec<TradeUpdate>,
+) {
+    let zero = Num::from(0);
+    let remaining = order.qty.clone().unwrap_or_default() - &order.filled_quantity;
+    let qty = match available.as_ref() {
+        Some(size) if size < &remaining => size.clone(),
+        _ => remaining.clone(),
+    };
+    if qty <= zero {
+        return;
+    }
+    if let Some(size) = available.as_mut() {
+        *size = &*size - &qty;
+    }
+
+    let previous_cost =
+        order.average_fill_price.clone().unwrap_or_default() * &order.filled_quantity;
+    order.filled_quantity = &order.filled_quantity + &qty;
+    order.average_fill_price = Some((previous_cost + &price * &qty) / &order.filled_quantity);
+    order.filled_at = Some(now);
+    let event = if qty == remaining {
+        order.status = OrderStatus::Filled;
+        LotUpdateEvent::Fill
+    } else {
+        order.status = OrderStatus::PartiallyFilled;
+        LotUpdateEvent::PartialFill
+    };
+    updates.push(TradeUpdate {
+        execution_id: Some(Uuid::new_v4().to_string()),
+        timestamp: Some(now),
+        price: Some(price),
+        qty: Some(qty),
+        ..TradeUpdate::new(event, order.clone())
+    });
+}
+
+/// Move a trailing stop along with the price. The stop follows the highest price for a sell and the
+/// lowest for a buy, and never moves back.
+fn trail(order: &mut BrokerOrder, tick: &Tick) {
+    let high_water_mark = match (&order.high_water_mark, order.side) {
+        (Some(mark), Side::Sell) if mark >= &tick.price => mark.clone(),
+        (Some(mark), Side::Buy) if mark <= &tick.price => mark.clone(),
+        _ => tick.price.clone(),
+    };
+    let offset = match (&order.trail_price, &order.trail_percent) {
+        (Some(trail_price), _) => trail_price.clone(),
+        (None, Some(trail_percent)) => &high_water_mark * trail_percent / Num::from(100),
+        (None, None) => return,
+    };
+    order.stop_price = Some(match order.side {
+        Side::Sell => &high_water_mark - offset,
+        Side::Buy => &high_water_mark + offset,
+    });
+    order.high_water_mark = Some(high_water_mark);
+}
+
+/// The take profit and stop of a one-cancels-other order are siblings, whichever fills first
+/// cancels the other.
+fn match_oco(
+    order: &mut BrokerOrder,
+    tick: &Tick,
+    available: &mut Option<Num>,
+    updates: &mut Vec<TradeUpdate>,
+) {
+    let mut filled = false;
+    if is_working(order.status) {
+        if let Some(price) = execution_price(order, tick) {
+            fill(order, price, available, tick.timestamp, updates);
+            filled = order.status == OrderStatus::Filled;
+        }
+    }
+    if !filled {
+        for leg in order.legs.iter_mut() {
+            if !is_working(leg.status) {
+                continue;
+            }
+            if let Some(price) = execution_price(leg, tick) {
+                fill(leg, price, available, tick.timestamp, updates);
+                if leg.status == OrderStatus::Filled {
+                    filled = true;
+                    break;
+                }
+            }
+        }
+    }
+    if !filled {
+        return;
+    }
+    for leg in order.legs.iter_mut() {
+        if !leg.status.is_terminal() {
+            leg.status = OrderStatus::Canceled;
+            updates.push(TradeUpdate::new(LotUpdateEvent::Canceled, leg.clone()));
+        }
+    }
+    if !order.status.is_terminal() {
+        order.status = OrderStatus::Canceled;
+        updates.push(TradeUpdate::new(LotUpdateEvent::Canceled, order.clone()));
+    }
+}
+
+fn match_order(
+    order: &mut BrokerOrder,
+    tick: &Tick,
+    available: &mut Option<Num>,
+    updates: &mut Vec<TradeUpdate>,
+) {
+    if order.class == OrderClass::OneCancelsOther {
+        match_oco(order, tick, available, updates);
+        return;
+    }
+    if is_working(order.status) && order.type_ == OrderType::TrailingStop {
+        trail(order, tick);
+    }
+    if is_working(order.status) {
+        if let Some(price) = execution_price(order, tick) {
+            fill(order, price, available, tick.timestamp, updates);
+        }
+        // bracket legs are held until the entry is completely filled
+        if order.status == OrderStatus::Filled {
+            for leg in order.legs.iter_mut() {
+                if leg.status == OrderStatus::Held {
+                    leg.status = OrderStatus::New;
+                }
+            }
+        }
+    }
+
+    if order.status != OrderStatus::Filled {
+        return;
+    }
+    let mut filled_leg = None;
+    for (i, leg) in order.legs.iter_mut().enumerate() {
+        if !is_working(leg.status) {
+            continue;
+        }
+        if let Some(price) = execution_price(leg, tick) {
+            fill(leg, price, available, tick.timestamp, updates);
+            if leg.status == OrderStatus::Filled {
+                filled_leg = Some(i);
+                break;
+            }
+        }
+    }
+    // the legs are one-cancels-other
+    if let Some(filled) = filled_leg {
+        for (i, leg) in order.legs.iter_mut().enumerate() {
+            if i != filled && !leg.status.is_terminal() {
+                leg.status = OrderStatus::Canceled;
+                updates.push(TradeUpdate::new(LotUpdateEvent::Canceled, leg.clone()));
+            }
+        }
+    }
+}
+
+fn find_order<'a>(orders: &'a [BrokerOrder], id: &OrderId) -> Option<&'a BrokerOrder> {
+    for order in orders.iter() {
+        if &order.id == id {
+            return Some(order);
+        }
+        if let Some(leg) = find_order(&order.legs, id) {
+            return Some(leg);
+        }
+    }
+    None
+}
+
+fn find_order_mut<'a>(orders: &'a mut [BrokerOrder], id: &OrderId) -> Option<&'a mut BrokerOrder> {
+    for order in orders.iter_mut() {
+        if &order.id == id {
+            return Some(order);
+        }
+        if let Some(leg) = find_order_mut(&mut order.legs, id) {
+            return Some(leg);
+        }
+    }
+    None
+}
+
+fn find_by_client_id<'a>(orders: &'a [BrokerOrder], client_id: &str) -> Option<&'a BrokerOrder> {
+    for order in orders.iter() {
+        if order.client_order_id == client_id {
+            return Some(order);
+        }
+        if let Some(leg) = find_by_client_id(&order.legs, client_id) {
+            return Some(leg);
+        }
+    }
+    None
+}
+
+fn new_order(
+    sym: &str,
+    side: Side,
+    type_: OrderType,
+    qty: &Num,
+    now: DateTime<Utc>,
+) -> BrokerOrder {
+    BrokerOrder {
+        id: OrderId(Uuid::new_v4().to_string()),
+        client_order_id: Uuid::new_v4().to_string(),
+        status: OrderStatus::New,
+        sym: sym.to_string(),
+        side,
+        type_,
+        class: OrderClass::Simple,
+        qty: Some(qty.clone()),
+        filled_quantity: Num::from(0),
+        average_fill_price: None,
+        limit_price: None,
+        stop_price: None,
+        trail_price: None,
+        trail_percent: None,
+        high_water_mark: None,
+        created_at: now,
+        filled_at: None,
+        legs: vec![],
+    }
+}
+
+#[async_trait]
+impl Broker for SimulatedBroker {
+    async fn place_order(&self, request: &OrderRequest) -> Result<BrokerOrder, BrokerError> {
+        request.validate()?;
+        let mut book = self.book.lock().unwrap();
+        if let Some(reason) = book.reject_next.take() {
+            return Err(BrokerError::Rejected(reason));
+        }
+        let now = book.now();
+
+        let mut order = new_order(&request.sym, request.side, request.type_, &request.qty, now);
+        if let Some(client_id) = &request.client_order_id {
+            order.client_order_id = client_id.clone();
+        }
+        order.class = request.class;
+        order.limit_price = request.limit_price.clone();
+        order.stop_price = request.stop_price.clone();
+        order.trail_price = request.trail_price.clone();
+        order.trail_percent = request.trail_percent.clone();
+
+        if request.class == OrderClass::OneCancelsOther {
+            // the order itself is the take profit, with the stop as its one leg
+            order.limit_price = request.take_profit.clone();
+            let mut stop = new_order(
+                &request.sym,
+                request.side,
+                OrderType::Stop,
+                &request.qty,
+                now,
+            );
+            stop.stop_price = request.stop_loss.clone();
+            order.legs = vec![stop];
+        }
+        // an oto order is a bracket with only one of the legs
+        if request.class == OrderClass::Bracket || request.class == OrderClass::OneTriggersOther {
+            let exit_side = match request.side {
+                Side::Buy => Side::Sell,
+                Side::Sell => Side::Buy,
+            };
+            if request.take_profit.is_some() {
+                let mut target =
+                    new_order(&request.sym, exit_side, OrderType::Limit, &request.qty, now);
+                target.limit_price = request.take_profit.clone();
+                target.status = OrderStatus::Held;
+                order.legs.push(target);
+            }
+            if request.stop_loss.is_some() {
+                let mut stop =
+                    new_order(&request.sym, exit_side, OrderType::Stop, &request.qty, now);
+                stop.stop_price = request.stop_loss.clone();
+                stop.status = OrderStatus::Held;
+                order.legs.push(stop);
+            }
+        }
+        book.emit(TradeUpdate::new(LotUpdateEvent::New, order.clone()));
+
+        // marketable orders execute against the last print right away
+        let mut updates = vec![];
+        if let Some(tick) = book.last.get(&order.sym) {
+            let mut available = tick.size.clone();
+            match_order(&mut order, tick, &mut available, &mut updates);
+        }
+        for update in updates {
+            book.emit(update);
+        }
+
+        book.orders.push(order.clone());
+        Ok(order)
+    }
+
+    async fn cancel_order(&self, id: &OrderId) -> Result<(), BrokerError> {
+        let mut book = self.book.lock().unwrap();
+        let order = match find_order_mut(&mut book.orders, id) {
+            Some(order) => order,
+            None => return Err(BrokerError::NotFound(format!("order {}", id))),
+        };
+        if order.status.is_terminal() {
+            return Err(BrokerError::Rejected(format!(
+                "order {} is not cancelable",
+                id
+            )));
+        }
+        let mut updates = vec![];
+        order.status = OrderStatus::Canceled;
+        updates.push(TradeUpdate::new(LotUpdateEvent::Canceled, order.clone()));
+        for leg in order.legs.iter_mut() {
+            if !leg.status.is_terminal() {
+                leg.status = OrderStatus::Canceled;
+                updates.push(TradeUpdate::new(LotUpdateEvent::Canceled, leg.clone()));
+            }
+        }
+        for update in updates {
+            book.emit(update);
+        }
+        Ok(())
+    }
+
+    async fn replace_order(
+        &self,
+        id: &OrderId,
+        request: &ReplaceRequest,
+    ) -> Result<BrokerOrder, BrokerError> {
+        let mut book = self.book.lock().unwrap();
+        let order = match find_order_mut(&mut book.orders, id) {
+            Some(order) => order,
+            None => return Err(BrokerError::NotFound(format!("order {}", id))),
+        };
+        if order.status.is_terminal() {
+            return Err(BrokerError::Rejected(format!(
+                "order {} is not replaceable",
+                id
+            )));
+        }
+        order.id = OrderId(Uuid::new_v4().to_string());
+        if let Some(qty) = &request.qty {
+            order.qty = Some(qty.clone());
+        }
+        if let Some(limit_price) = &request.limit_price {
+            order.limit_price = Some(limit_price.clone());
+        }
+        if let Some(stop_price) = &request.stop_price {
+            order.stop_price = Some(stop_price.clone());
+        }
+        if let Some(trail) = &request.trail {
+            if order.trail_percent.is_some() {
+                order.trail_percent = Some(trail.clone());
+            } else {
+                order.trail_price = Some(trail.clone());
+            }
+        }
+        let replaced = order.clone();
+        book.emit(TradeUpdate::new(LotUpdateEvent::Replaced, replaced.clone()));
+        Ok(replaced)
+    }
+
+    async fn get_order(&self, id: &OrderId) -> Result<BrokerOrder, BrokerError> {
+        let book = self.book.lock().unwrap();
+        find_order(&book.orders, id)
+            .cloned()
+            .ok_or_else(|| BrokerError::NotFound(format!("order {}", id)))
+    }
+
+    async fn get_order_by_client_id(&self, client_id: &str) -> Result<BrokerOrder, BrokerError> {
+        let book = self.book.lock().unwrap();
+        find_by_client_id(&book.orders, client_id)
+            .cloned()
+            .ok_or_else(|| BrokerError::NotFound(format!("order not found for {}", client_id)))
+    }
+
+    async fn list_positions(&self) -> Result<Vec<BrokerPosition>, BrokerError> {
+        let book = self.book.lock().unwrap();
+        let zero = Num::from(0);
+        let positions = book
+            .holdings
+            .iter()
+            .filter(|(_, holding)| holding.qty != zero)
+            .map(|(sym, holding)| {
+                let current_price = book.last.get(sym).map(|tick| tick.price.clone());
+                let market_value = current_price.as_ref().map(|price| price * &holding.qty);
+                let unrealized_pl = current_price
+                    .as_ref()
+                    .map(|price| (price - &holding.avg_entry_price) * &holding.qty);
+                BrokerPosition {
+                    sym: sym.clone(),
+                    qty: if holding.qty < zero {
+                        &zero - &holding.qty
+                    } else {
+                        holding.qty.clone()
+                    },
+                    side: if holding.qty < zero {
+                        PositionType::Short
+                    } else {
+                        PositionType::Long
+                    },
+                    avg_entry_price: holding.avg_entry_price.clone(),
+                    current_price,
+                    market_value,
+                    unrealized_pl,
+                }
+            })
+            .collect();
+        Ok(positions)
+    }
+
+    async fn latest_trades(&self, syms: &[String]) -> Result<HashMap<String, Num>, BrokerError> {
+        let book = self.book.lock().unwrap();
+        Ok(syms
+            .iter()
+            .filter_map(|sym| {
+                book.last
+                    .get(sym)
+                    .map(|tick| (sym.clone(), tick.price.clone()))
+            })
+            .collect())
+    }
+
+    async fn latest_quote(&self, sym: &str) -> Result<Quote, BrokerError> {
+        let book = self.book.lock().unwrap();
+        let tick = book
+            .last
+            .get(sym)
+            .ok_or_else(|| BrokerError::NotFound(format!("No quote for {}", sym)))?;
+        Ok(Quote {
+            sym: sym.to_string(),
+            bid: tick.bid.clone(),
+            ask: tick.ask.clone(),
+            timestamp: tick.timestamp,
+        })
+    }
+
+    async fn cash_activities(
+        &self,
+        after: Option<DateTime<Utc>>,
+    ) -> Result<Vec<CashActivity>, BrokerError> {
+        let book = self.book.lock().unwrap();
+        Ok(book
+            .cash
+            .iter()
+            .filter(|activity| after.map_or(true, |after| activity.date > after))
+            .cloned()
+            .collect())
+    }
+
+    async fn stream_updates(&self, sink: TradeUpdateSink) -> Result<(), BrokerError> {
+        self.book.lock().unwrap().sinks.push(sink);
+        Ok(())
+    }
+
+    async fn stream_trades(&self, syms: SymbolDrain, sink: TradeSink) -> Result<(), BrokerError> {
+        self.book.lock().unwrap().feeds.push(TradeFeed {
+            syms,
+            sink,
+            watched: HashSet::new(),
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+use chrono::{DateTime, Utc};
+use num_decimal::Num;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use turbosql::{select, ToSql, ToSqlOutput, Turbosql};
+
+use crate::broker::{Broker, CashActivity};
+use crate::lot::{Lot, PositionType};
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum CashEventKind {
+    /// Cash dividend paid on a long holding.
+    Dividend,
+    /// Dividend paid in shares. It counts toward total return, the shares are not added to the lot.
+    ReinvestedDividend,
+    /// Dividend owed on a short holding, a negative amount.
+    ShortDividend,
+    /// Interest received on cash, or paid.
+    Interest,
+}
+
+impl ToSql for CashEventKind {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, turbosql::rusqlite::Error> {
+        Ok(ToSqlOutput::from(serde_json::json!(self).to_string()))
+    }
+}
+
+/// Cash received or paid outside of trades. Positive amounts were received, negative ones paid.
+/// Events without a lot belong to the bucket, or to the account when neither is set.
+#[derive(Debug, Serialize, Turbosql, Default, Clone)]
+pub struct CashEvent {
+    /// DB row ID
+    pub rowid: Option<i64>,
+    /// Row ID of the lot
+    pub lot_id: Option<i64>,
+    /// ID of the bucket
+    pub bucket_id: Option<i64>,
+    pub sym: Option<String>,
+    pub kind: Option<CashEventKind>,
+    pub amount: Option<Num>,
+    /// Shares the amount was paid on
+    pub qty: Option<Num>,
+    /// ID of the broker account activity the event was imported from
+    pub activity_id: Option<String>,
+    pub note: Option<String>,
+    pub paid_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// Cash events summed by kind.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct CashTotals {
+    /// Dividends received, reinvested or owed
+    pub dividends: Num,
+    pub interest: Num,
+    pub total: Num,
+}
+
+impl CashTotals {
+    pub fn of(events: &[CashEvent]) -> Self {
+        let mut totals = Self::default();
+        for event in events {
+            let amount = match &event.amount {
+                Some(amount) => amount,
+                None => continue,
+            };
+            match event.kind {
+                Some(CashEventKind::Interest) => totals.interest = &totals.interest + amount,
+                _ => totals.dividends = &totals.dividends + amount,
+            }
+            totals.total = &totals.total + amount;
+        }
+        totals
+    }
+}
+
+impl CashEvent {
+    /// Record an event entered by hand, against a lot or else a bucket.
+    pub fn record(
+        lot: Option<&Lot>,
+        bucket_id: Option<i64>,
+        kind: CashEventKind,
+        amount: Num,
+        note: Option<String>,
+        paid_at: Option<DateTime<Utc>>,
+    ) -> Result<i64, turbosql::Error> {
+        Self {
+            lot_id: lot.and_then(|lot| lot.rowid),
+            bucket_id: lot.and_then(|lot| lot.bucket_id).or(bucket_id),
+            sym: lot.and_then(|lot| lot.sym.clone()),
+            kind: Some(kind),
+            amount: Some(amount),
+            qty: lot.and_then(|lot| lot.qty.clone()),
+            note,
+            paid_at: Some(paid_at.unwrap_or_else(Utc::now)),
+            created_at: Some(Utc::now()),
+            ..Default::default()
+        }
+        .insert()
+    }
+
+    pub fn for_lot(lot_id: i64) -> Result<Vec<Self>, turbosql::Error> {
+        select!(Vec<CashEvent> "WHERE lot_id = ? ORDER BY paid_at", lot_id)
+    }
+
+    pub fn for_bucket(bucket_id: i64) -> Result<Vec<Self>, turbosql::Error> {
+        select!(Vec<CashEvent> "WHERE bucket_id = ? ORDER BY paid_at", bucket_id)
+    }
+
+    /// When the most recent imported activity was paid, to import from there on.
+    pub fn last_imported_at() -> Result<Option<DateTime<Utc>>, turbosql::Error> {
+        let events =
+            select!(Vec<CashEvent> "WHERE activity_id IS NOT NULL ORDER BY paid_at DESC LIMIT 1")?;
+        Ok(events.into_iter().next().and_then(|event| event.paid_at))
+    }
+
+    fn imported(activity_id: &str) -> Result<bool, turbosql::Error> {
+        let count =
+            select!(i64 "SELECT COUNT(*) FROM cashevent WHERE activity_id = ?", activity_id)?;
+        Ok(count > 0)
+    }
+
+    /// Record a broker activity. An activity for a symbol is shared out among the lots on that
+    /// side of it by the shares each held when it was paid, longs for a dividend and shorts for a
+    /// dividend owed, so tranches already taken off do not count. Activities no lot can take, like interest on cash, are kept for the account.
+    ///
+    /// Entitlement to a dividend is fixed on the ex-date, but the broker only gives the pay date,
+    /// weeks later. A lot sold in between misses a dividend it earned, which is kept for the
+    /// account or goes to the other lots still held, and a lot opened in between takes a share it
+    /// did not earn.
+    pub fn import(activity: &CashActivity) -> Result<Vec<Self>, Box<dyn Error>> {
+        if Self::imported(&activity.id)? {
+            return Ok(vec![]);
+        }
+        let position_type = if activity.kind == CashEventKind::ShortDividend {
+            PositionType::Short
+        } else {
+            PositionType::Long
+        };
+        let mut lots: Vec<(Lot, Num)> = vec![];
+        if let Some(sym) = &activity.sym {
+            for lot in select!(Vec<Lot> "WHERE sym = ?", sym)? {
+                if lot.position_type.unwrap_or_default() != position_type {
+                    continue;
+                }
+                let qty = lot.qty_held_at(activity.date)?;
+                if qty > Num::from(0) {
+                    lots.push((lot, qty));
+                }
+            }
+        }
+        let held: Num = lots.iter().fold(Num::from(0), |held, (_, qty)| held + qty);
+
+        let template = Self {
+            sym: activity.sym.clone(),
+            kind: Some(activity.kind),
+            activity_id: Some(activity.id.clone()),
+            note: activity.description.clone(),
+            paid_at: Some(activity.date),
+            created_at: Some(Utc::now()),
+            ..Default::default()
+        };
+        let mut events = vec![];
+        if held == Num::from(0) {
+            let mut event = Self {
+                amount: Some(activity.net_amount.clone()),
+                qty: activity.qty.clone(),
+                ..template
+            };
+            event.rowid = Some(event.insert()?);
+            events.push(event);
+            return Ok(events);
+        }
+        for (lot, qty) in lots {
+            let mut event = Self {
+                lot_id: lot.rowid,
+                bucket_id: lot.bucket_id,
+                amount: Some(&(&activity.net_amount * &qty) / &held),
+                qty: Some(qty),
+                ..template.clone()
+            };
+            event.rowid = Some(event.insert()?);
+            events.push(event);
+        }
+        Ok(events)
+    }
+}
+
+/// Pull dividend and interest activities from the broker since the last import, and import them.
+/// The window goes back a day from the last one imported, as activities dated the same day can
+/// show up later; those already imported are skipped.
+pub async fn import_from(broker: &dyn Broker) -> Result<Vec<CashEvent>, Box<dyn Error>> {
+    let after = CashEvent::last_imported_at()?.map(|at| at - chrono::Duration::days(1));
+    let activities = broker.cash_activities(after).await?;
+    import_activities(&activities)
+}
+
+/// Import broker activities, skipping those already imported, and fold the cash into the lots
+/// they were shared out to. Returns the events recorded.
+pub fn import_activities(activities: &[CashActivity]) -> Result<Vec<CashEvent>, Box<dyn Error>> {
+    let mut events = vec![];
+    for activity in activities {
+        events.extend(CashEvent::import(activity)?);
+    }
+    let lot_ids: HashSet<i64> = events.iter().filter_map(|event| event.lot_id).collect();
+    for lot_id in lot_ids {
+        let mut lot = Lot::get(lot_id)?;
+        lot.apply_cash_events()?;
+        lot.update()?;
+    }
+    tracing::info!("cash_event: imported {} events", events.len());
+    Ok(events)
+}
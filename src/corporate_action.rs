@@ -0,0 +1,191 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use num_decimal::Num;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::read_to_string;
+use turbosql::{execute, select, Turbosql};
+
+use crate::lot::{Lot, LotStatus};
+
+/// A stock split or reverse split. Holders get `new_rate` shares for every `old_rate` they had,
+/// so a 4-for-1 split is 4:1 and a 1-for-10 reverse split is 1:10.
+#[derive(Debug, Deserialize, Serialize, Turbosql, Default, Clone)]
+pub struct StockSplit {
+    /// DB row ID
+    #[serde(skip_deserializing)]
+    pub rowid: Option<i64>,
+    pub sym: Option<String>,
+    pub new_rate: Option<i64>,
+    pub old_rate: Option<i64>,
+    /// First trading day on the new share basis
+    pub effective_date: Option<NaiveDate>,
+    #[serde(skip_deserializing)]
+    pub applied_at: Option<DateTime<Utc>>,
+    /// Number of lots restated
+    #[serde(skip_deserializing)]
+    pub lot_count: Option<i64>,
+}
+
+impl StockSplit {
+    /// New shares per old share.
+    pub fn ratio(&self) -> Option<Num> {
+        match (self.new_rate, self.old_rate) {
+            (Some(new_rate), Some(old_rate)) if new_rate > 0 && old_rate > 0 => {
+                Some(Num::new(new_rate, old_rate))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn for_sym(sym: &str) -> Result<Vec<Self>, turbosql::Error> {
+        select!(Vec<StockSplit> "WHERE sym = ? ORDER BY rowid", sym)
+    }
+
+    /// Restate the open and pending lots in the split's symbol that were created before it took
+    /// effect, and record it. It all happens in one transaction, so a failure part way leaves
+    /// every lot on the old basis and the split unrecorded. A split already recorded for the
+    /// symbol and date is refused, so the same file of splits can be loaded more than once.
+    pub fn apply(self) -> Result<Self, Box<dyn Error>> {
+        execute!("BEGIN IMMEDIATE")?;
+        match self.restate_lots() {
+            Ok(split) => {
+                execute!("COMMIT")?;
+                Ok(split)
+            }
+            Err(e) => {
+                execute!("ROLLBACK")?;
+                Err(e)
+            }
+        }
+    }
+
+    fn restate_lots(mut self) -> Result<Self, Box<dyn Error>> {
+        let sym = self.sym.clone().ok_or("sym is required")?;
+        let effective_date = self.effective_date.ok_or("effective_date is required")?;
+        if self.ratio().is_none() {
+            return Err("new_rate and old_rate must be > 0".into());
+        }
+        if Self::for_sym(&sym)?
+            .iter()
+            .any(|split| split.effective_date == Some(effective_date))
+        {
+            return Err(
+                format!("split of {} on {} was already applied", sym, effective_date).into(),
+            );
+        }
+
+        // a pending lot's order was placed on the old basis, so its fills are restated too
+        let lots: Vec<Lot> =
+            select!(Vec<Lot> "WHERE sym = ? AND status IN (?, ?)", sym, LotStatus::Open, LotStatus::Pending)?
+                .into_iter()
+                .filter(|lot| {
+                    lot.opened_at
+                        .or(lot.created_at)
+                        .map_or(false, |at| at.naive_utc().date() < effective_date)
+                })
+                .collect();
+        tracing::info!(
+            "stock_split: {} {:?}:{:?} on {}, {} lots",
+            sym,
+            self.new_rate,
+            self.old_rate,
+            effective_date,
+            lots.len()
+        );
+        for mut lot in lots.iter().cloned() {
+            lot.apply_split(&self)?;
+        }
+        self.applied_at = Some(Utc::now());
+        self.lot_count = Some(lots.len() as i64);
+        self.rowid = Some(self.insert()?);
+        Ok(self)
+    }
+}
+
+/// Read splits from a file with one JSON object per line, the format of `StockSplit` without the
+/// fields set when it is applied.
+pub fn load_splits(path: &str) -> Result<Vec<StockSplit>, Box<dyn Error>> {
+    let mut splits = vec![];
+    for line in read_to_string(path)?.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        splits.push(serde_json::from_str(line)?);
+    }
+    Ok(splits)
+}
+
+#[test]
+fn test_apply_split() {
+    use crate::bucket::Bucket;
+    use crate::lot::{OrderTimeInForce, PositionType};
+    use crate::price_adjustment::{AdjustmentReason, PriceAdjustment};
+
+    let _res = std::panic::catch_unwind(|| {
+        turbosql::execute!("DELETE FROM stocksplit WHERE sym = ?", "SPLT").unwrap()
+    });
+    let rowid = Lot::create(
+        "SPLT".to_string(),
+        Num::from(10),
+        PositionType::Long,
+        Bucket::new("test"),
+        Some(Num::from(400)),
+        Some(Num::from(480)),
+        Some(Num::from(360)),
+        Some(OrderTimeInForce::Day),
+    );
+    let mut lot = Lot::get(rowid).unwrap();
+    lot.status = Some(LotStatus::Open);
+    lot.opened_at = Some(Utc::now() - chrono::Duration::days(30));
+    lot.record_fill(&Num::from(10), &Some(Num::from(400)), lot.opened_at);
+    lot.update().unwrap();
+    let pending_id = Lot::create(
+        "SPLT".to_string(),
+        Num::from(10),
+        PositionType::Long,
+        Bucket::new("test"),
+        Some(Num::from(400)),
+        None,
+        None,
+        Some(OrderTimeInForce::Day),
+    );
+    let mut pending = Lot::get(pending_id).unwrap();
+    pending.created_at = Some(Utc::now() - chrono::Duration::days(1));
+    pending.update().unwrap();
+
+    let split = StockSplit {
+        sym: Some("SPLT".to_string()),
+        new_rate: Some(4),
+        old_rate: Some(1),
+        effective_date: Some(Utc::now().naive_utc().date()),
+        ..Default::default()
+    };
+    let applied = split.clone().apply().unwrap();
+    assert!(applied.lot_count.unwrap() >= 2);
+
+    let pending = Lot::get(pending_id).unwrap();
+    assert_eq!(pending.status, Some(LotStatus::Pending));
+    assert_eq!(pending.qty, Some(Num::from(40)));
+    assert_eq!(pending.limit_price, Some(Num::from(100)));
+
+    let lot = Lot::get(rowid).unwrap();
+    assert_eq!(lot.qty, Some(Num::from(40)));
+    assert_eq!(lot.filled_avg_price, Some(Num::from(100)));
+    assert_eq!(lot.limit_price, Some(Num::from(100)));
+    assert_eq!(lot.target_price, Some(Num::from(120)));
+    assert_eq!(lot.stop_price, Some(Num::from(90)));
+    assert_eq!(lot.cost_basis, Some(Num::from(4000)));
+    let adjustments = PriceAdjustment::history(lot.client_id.as_ref().unwrap()).unwrap();
+    assert_eq!(adjustments.len(), 3);
+    assert!(adjustments
+        .iter()
+        .all(|adjustment| adjustment.reason == Some(AdjustmentReason::Split)));
+
+    // the broker still reports the opening order on the old basis
+    let mut lot = lot;
+    lot.record_fill(&Num::from(10), &Some(Num::from(400)), None);
+    assert_eq!(lot.qty, Some(Num::from(40)));
+    assert_eq!(lot.cost_basis, Some(Num::from(4000)));
+
+    assert!(split.apply().is_err());
+}
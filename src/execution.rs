@@ -0,0 +1,108 @@
+use chrono::{DateTime, Utc};
+use num_decimal::Num;
+use serde::{Deserialize, Serialize};
+use turbosql::{select, ToSql, ToSqlOutput, Turbosql};
+
+use crate::broker::OrderId;
+use crate::lot::Lot;
+use crate::sync_lots::TradeUpdate;
+
+/// Whether an execution opened the lot or closed it out.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ExecutionSide {
+    Entry,
+    Exit,
+}
+
+impl ToSql for ExecutionSide {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, turbosql::rusqlite::Error> {
+        Ok(ToSqlOutput::from(serde_json::json!(self).to_string()))
+    }
+}
+
+/// A single execution reported by the broker against one of a lot's orders.
+#[derive(Debug, Serialize, Turbosql, Default, Clone)]
+pub struct Execution {
+    /// DB row ID
+    pub rowid: Option<i64>,
+    /// Row ID of the lot
+    pub lot_id: Option<i64>,
+    /// The broker's ID for the execution, unique per lot
+    pub execution_id: Option<String>,
+    /// ID of the order that was executed
+    pub order_id: Option<OrderId>,
+    pub side: Option<ExecutionSide>,
+    pub price: Option<Num>,
+    pub qty: Option<Num>,
+    pub executed_at: Option<DateTime<Utc>>,
+}
+
+impl Execution {
+    /// Whether the execution carried by `update` was already recorded for the lot.
+    pub fn exists(lot: &Lot, update: &TradeUpdate) -> Result<bool, turbosql::Error> {
+        match &update.execution_id {
+            Some(execution_id) => {
+                let count = select!(i64 "SELECT COUNT(*) FROM execution WHERE lot_id = ? AND execution_id = ?", lot.rowid, execution_id)?;
+                Ok(count > 0)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Whether the exit execution carried by `update` was already recorded, for any lot. One
+    /// exit can close several lots, and the lot split off by a partial exit carries it on.
+    pub fn exit_exists(update: &TradeUpdate) -> Result<bool, turbosql::Error> {
+        match &update.execution_id {
+            Some(execution_id) => {
+                let count = select!(i64 "SELECT COUNT(*) FROM execution WHERE order_id = ? AND execution_id = ? AND side = ?", update.order.id, execution_id, ExecutionSide::Exit)?;
+                Ok(count > 0)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Store the execution carried by a fill update. Updates without execution details are skipped.
+    pub fn record(
+        lot: &Lot,
+        side: ExecutionSide,
+        update: &TradeUpdate,
+    ) -> Result<Option<i64>, turbosql::Error> {
+        if update.execution_id.is_none() || update.price.is_none() || update.qty.is_none() {
+            return Ok(None);
+        }
+        let rowid = Self {
+            lot_id: lot.rowid,
+            execution_id: update.execution_id.clone(),
+            order_id: Some(update.order.id.clone()),
+            side: Some(side),
+            price: update.price.clone(),
+            qty: update.qty.clone(),
+            executed_at: update.timestamp,
+            ..Default::default()
+        }
+        .insert()?;
+        Ok(Some(rowid))
+    }
+
+    pub fn for_lot(lot_id: i64, side: ExecutionSide) -> Result<Vec<Self>, turbosql::Error> {
+        select!(Vec<Execution> "WHERE lot_id = ? AND side = ? ORDER BY rowid", lot_id, side)
+    }
+
+    /// Total quantity and volume weighted average price of the executions.
+    pub fn vwap(executions: &[Self]) -> Option<(Num, Num)> {
+        let mut qty = Num::from(0);
+        let mut notional = Num::from(0);
+        for execution in executions {
+            if let (Some(price), Some(execution_qty)) = (&execution.price, &execution.qty) {
+                notional = notional + price * execution_qty;
+                qty = qty + execution_qty;
+            }
+        }
+        if qty == Num::from(0) {
+            None
+        } else {
+            let avg = &notional / &qty;
+            Some((qty, avg))
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use num_decimal::Num;
+use std::error::Error;
+use std::time::Duration;
+
+use crate::broker::{
+    Broker, BrokerError, BrokerOrder, OrderClass, OrderId, OrderRequest, OrderType, ReplaceRequest,
+};
+use crate::lot::{Lot, OrderTimeInForce};
+use crate::price_adjustment::AdjustmentReason;
+use crate::tranche::Tranche;
+
+/// Put in the exits requested at entry that wait for the entry to fill, once the lot holds all it
+/// is going to: a trailing stop, or the exit of each tranche of a lot that scales out. Lots that
+/// have theirs already are left alone, so this runs after every fill, whether it came off the
+/// stream, back from placing the order or from a sync. Returns whether an exit was placed.
+pub async fn place_entry_exits(broker: &dyn Broker, lot: &mut Lot) -> Result<bool, Box<dyn Error>> {
+    if !lot.entry_complete() {
+        return Ok(false);
+    }
+    if lot.is_trailing() && lot.stop_order_id.is_none() {
+        let stop = place_trailing_stop(broker, lot).await?;
+        lot.track_trailing_stop(&stop)?;
+        return Ok(true);
+    }
+    if lot.scales_out() {
+        let unplaced = Tranche::for_lot(lot.rowid.unwrap_or_default())?
+            .iter()
+            .any(|tranche| tranche.order_id.is_none() && !tranche.is_disposed());
+        if unplaced {
+            place_tranche_exits(broker, lot).await?;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// How long canceled orders get to be confirmed done before a replacement is given up on.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(10);
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A database error met on the way to or back from the broker, surfaced like the broker's own so
+/// callers see one error type.
+fn db_error(e: impl std::fmt::Display) -> BrokerError {
+    BrokerError::Other(e.to_string())
+}
+
+/// Cancel `ids` and wait until the broker reports every one of them done. A cancel is only a
+/// request: until it goes through, the order still holds its shares and the broker refuses
+/// another exit for them. Returns the orders as they ended, as one may have filled instead.
+pub async fn cancel_and_wait(
+    broker: &dyn Broker,
+    ids: &[OrderId],
+) -> Result<Vec<BrokerOrder>, BrokerError> {
+    let canceled = futures::future::join_all(ids.iter().map(|id| broker.cancel_order(id))).await;
+    for (id, result) in ids.iter().zip(canceled) {
+        // an order that is already done cannot be canceled, its status below says how it ended
+        if let Err(e) = result {
+            tracing::debug!("cancel_and_wait: {}: {}", id, e);
+        }
+    }
+    let deadline = tokio::time::Instant::now() + CANCEL_TIMEOUT;
+    let mut done = vec![];
+    for id in ids {
+        loop {
+            let order = broker.get_order(id).await?;
+            if order.status.is_terminal() {
+                done.push(order);
+                break;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(BrokerError::Other(format!(
+                    "order {} still {:?} after it was canceled",
+                    id, order.status
+                )));
+            }
+            tokio::time::sleep(CANCEL_POLL_INTERVAL).await;
+        }
+    }
+    Ok(done)
+}
+
+/// Place a stop or limit order closing out the lot's holdings.
+pub async fn place_exit(
+    broker: &dyn Broker,
+    lot: &Lot,
+    type_: OrderType,
+    price: Num,
+) -> Result<BrokerOrder, BrokerError> {
+    let request = OrderRequest {
+        sym: lot.sym.clone().unwrap_or_default(),
+        side: lot.exit_side(),
+        qty: lot
+            .filled_qty
+            .clone()
+            .or_else(|| lot.qty.clone())
+            .unwrap_or_default(),
+        type_,
+        time_in_force: Some(OrderTimeInForce::UntilCanceled),
+        limit_price: if type_ == OrderType::Limit {
+            Some(price.clone())
+        } else {
+            None
+        },
+        stop_price: if type_ == OrderType::Stop {
+            Some(price)
+        } else {
+            None
+        },
+        ..Default::default()
+    };
+    broker.place_order(&request).await
+}
+
+/// Place a one-cancels-other stop and target pair closing out `qty` of the lot's holdings.
+pub async fn place_oco_exit(
+    broker: &dyn Broker,
+    lot: &Lot,
+    qty: Num,
+    stop_price: Num,
+    limit_price: Num,
+) -> Result<BrokerOrder, BrokerError> {
+    let request = OrderRequest {
+        sym: lot.sym.clone().unwrap_or_default(),
+        side: lot.exit_side(),
+        qty,
+        class: OrderClass::OneCancelsOther,
+        type_: OrderType::Limit,
+        time_in_force: Some(OrderTimeInForce::UntilCanceled),
+        take_profit: Some(limit_price),
+        stop_loss: Some(stop_price),
+        ..Default::default()
+    };
+    broker.place_order(&request).await
+}
+
+/// Place the one-cancels-other exit of each tranche of a lot that scales out, each with the
+/// lot's stop for the tranche's quantity.
+pub async fn place_tranche_exits(broker: &dyn Broker, lot: &Lot) -> Result<(), BrokerError> {
+    let stop_price = lot.stop_price.clone().unwrap_or_default();
+    let tranches = Tranche::for_lot(lot.rowid.unwrap_or_default()).map_err(db_error)?;
+    for mut tranche in tranches {
+        if tranche.order_id.is_some() || tranche.is_disposed() {
+            continue;
+        }
+        let order = place_oco_exit(
+            broker,
+            lot,
+            tranche.qty.clone().unwrap_or_default(),
+            stop_price.clone(),
+            tranche.target_price.clone().unwrap_or_default(),
+        )
+        .await?;
+        tranche.stop_order_id = order.legs.first().map(|leg| leg.id.clone());
+        tranche.order_id = Some(order.id);
+        tranche.update().map_err(db_error)?;
+    }
+    Ok(())
+}
+
+/// Replace the stop leg of every tranche still working.
+pub async fn move_tranche_stops(
+    broker: &dyn Broker,
+    lot: &Lot,
+    stop_price: &Num,
+) -> Result<(), BrokerError> {
+    let tranches = Tranche::for_lot(lot.rowid.unwrap_or_default()).map_err(db_error)?;
+    for mut tranche in tranches
+        .into_iter()
+        .filter(|tranche| !tranche.is_disposed())
+    {
+        let order_id = match &tranche.order_id {
+            Some(order_id) => order_id,
+            None => continue,
+        };
+        let order = broker.get_order(order_id).await?;
+        for leg in order.legs.iter().filter(|leg| !leg.status.is_terminal()) {
+            let request = ReplaceRequest {
+                stop_price: Some(stop_price.clone()),
+                ..Default::default()
+            };
+            let replaced = broker.replace_order(&leg.id, &request).await?;
+            // the replacement is a new order, updates for it come under the new ID
+            tranche.stop_order_id = Some(replaced.id);
+            tranche.update().map_err(db_error)?;
+        }
+    }
+    Ok(())
+}
+
+/// Put a trailing stop for the lot's holdings in place of its bracket legs, or move the trail of
+/// the one already working. The broker cannot pair a trailing stop with a take profit, and will not
+/// hold the shares for two exits, so the target leg is canceled along with the stop.
+pub async fn place_trailing_stop(
+    broker: &dyn Broker,
+    lot: &Lot,
+) -> Result<BrokerOrder, BrokerError> {
+    if let Some(stop_order_id) = &lot.stop_order_id {
+        let stop = broker.get_order(stop_order_id).await?;
+        if stop.type_ == OrderType::TrailingStop && !stop.status.is_terminal() {
+            let request = ReplaceRequest {
+                trail: lot
+                    .trail_price
+                    .clone()
+                    .or_else(|| lot.trail_percent.clone()),
+                ..Default::default()
+            };
+            return broker.replace_order(stop_order_id, &request).await;
+        }
+    }
+
+    let mut exits: Vec<OrderId> = lot.exit_order_id.iter().cloned().collect();
+    if let Some(open_order_id) = &lot.open_order_id {
+        let order = broker.get_order(open_order_id).await?;
+        exits.extend(
+            order
+                .legs
+                .iter()
+                .filter(|leg| !leg.status.is_terminal())
+                .map(|leg| leg.id.clone()),
+        );
+    }
+    cancel_and_wait(broker, &exits).await?;
+
+    let request = OrderRequest {
+        sym: lot.sym.clone().unwrap_or_default(),
+        side: lot.exit_side(),
+        qty: lot
+            .filled_qty
+            .clone()
+            .or_else(|| lot.qty.clone())
+            .unwrap_or_default(),
+        type_: OrderType::TrailingStop,
+        time_in_force: Some(OrderTimeInForce::UntilCanceled),
+        trail_price: lot.trail_price.clone(),
+        trail_percent: lot.trail_percent.clone(),
+        ..Default::default()
+    };
+    broker.place_order(&request).await
+}
+
+/// Cancel the exits of a lot still working on the broker, and wait for them to be done, so it
+/// releases the shares for another sale: the legs of the opening order, and the exits attached
+/// after the entry. Canceling an oco parent takes its leg along. Fails if one of them filled
+/// instead, as the shares are gone.
+pub async fn cancel_exits(
+    broker: &dyn Broker,
+    lot: &Lot,
+    open_order: &BrokerOrder,
+) -> Result<(), BrokerError> {
+    let open_legs: Vec<OrderId> = open_order
+        .legs
+        .iter()
+        .filter(|leg| !leg.status.is_terminal())
+        .map(|leg| leg.id.clone())
+        .collect();
+    let standalone_exits = lot.standalone_exits(open_order);
+    let tranche_exits: Vec<OrderId> = if lot.scales_out() {
+        Tranche::for_lot(lot.rowid.unwrap_or_default())
+            .map_err(db_error)?
+            .into_iter()
+            .filter(|tranche| !tranche.is_disposed())
+            .filter_map(|tranche| tranche.order_id)
+            .collect()
+    } else {
+        vec![]
+    };
+    let exits: Vec<OrderId> = open_legs
+        .into_iter()
+        .chain(lot.exit_order_id.clone())
+        .chain(standalone_exits)
+        .chain(tranche_exits)
+        .collect();
+    let canceled = cancel_and_wait(broker, &exits).await?;
+    if canceled
+        .iter()
+        .flat_map(BrokerOrder::flatten)
+        .any(|exit| exit.filled_quantity > Num::from(0))
+    {
+        return Err(BrokerError::Rejected(format!(
+            "an exit of {} filled before it could be canceled",
+            lot.client_id.as_deref().unwrap_or_default()
+        )));
+    }
+    Ok(())
+}
+
+/// Put back the exits of the lot left open by a sale of part of it, which canceled them. Soft
+/// levels are watched by zoocarp and need nothing on the broker.
+pub async fn reattach_exits(broker: &dyn Broker, lot: &mut Lot) -> Result<(), BrokerError> {
+    if lot.is_trailing() {
+        let stop = place_trailing_stop(broker, lot).await?;
+        lot.track_trailing_stop(&stop).map_err(db_error)?;
+        return Ok(());
+    }
+    let stop_price = lot
+        .stop_price
+        .clone()
+        .filter(|_| lot.soft_stop != Some(true));
+    let limit_price = lot
+        .target_price
+        .clone()
+        .filter(|_| lot.soft_target != Some(true));
+    let (stop, target) = match (stop_price, limit_price) {
+        (Some(stop_price), Some(limit_price)) => {
+            let qty = lot.open_qty().unwrap_or_default();
+            let oco = place_oco_exit(broker, lot, qty, stop_price, limit_price).await?;
+            (oco.legs.first().cloned(), Some(oco))
+        }
+        (Some(stop_price), None) => (
+            Some(place_exit(broker, lot, OrderType::Stop, stop_price).await?),
+            None,
+        ),
+        (None, Some(limit_price)) => (
+            None,
+            Some(place_exit(broker, lot, OrderType::Limit, limit_price).await?),
+        ),
+        (None, None) => return Ok(()),
+    };
+    lot.modify_bracket(stop.as_ref(), target.as_ref(), AdjustmentReason::Broker)
+        .map_err(db_error)?;
+    if let Some(oco) = target.as_ref().filter(|order| !order.legs.is_empty()) {
+        lot.track_oco(oco).map_err(db_error)?;
+    }
+    Ok(())
+}
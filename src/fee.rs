@@ -0,0 +1,168 @@
+use chrono::{DateTime, Utc};
+use num_decimal::Num;
+use serde::{Deserialize, Serialize};
+use turbosql::{select, ToSql, ToSqlOutput, Turbosql};
+
+use crate::broker::Side;
+use crate::execution::ExecutionSide;
+use crate::lot::Lot;
+use crate::sync_lots::TradeUpdate;
+
+/// SEC Section 31 fee, in cents per million dollars of sale proceeds.
+pub const SEC_FEE_CENTS_PER_MILLION: i64 = 2780;
+/// FINRA Trading Activity Fee per share sold, in millionths of a dollar.
+pub const TAF_PER_SHARE_MICROS: i64 = 166;
+/// Cap on the Trading Activity Fee per trade, in cents.
+pub const TAF_MAX_CENTS: i64 = 830;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum FeeKind {
+    /// Per trade commission charged by the broker.
+    Commission,
+    /// SEC Section 31 fee on sales.
+    Sec,
+    /// FINRA Trading Activity Fee on sales.
+    Taf,
+    /// Manual correction, negative for rebates.
+    Adjustment,
+}
+
+impl ToSql for FeeKind {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, turbosql::rusqlite::Error> {
+        Ok(ToSqlOutput::from(serde_json::json!(self).to_string()))
+    }
+}
+
+/// A charge against one side of a lot.
+#[derive(Debug, Serialize, Turbosql, Default, Clone)]
+pub struct Fee {
+    /// DB row ID
+    pub rowid: Option<i64>,
+    /// Row ID of the lot
+    pub lot_id: Option<i64>,
+    /// ID of the bucket the lot belongs to
+    pub bucket_id: Option<i64>,
+    pub kind: Option<FeeKind>,
+    /// Whether the fee was charged when opening or closing the lot
+    pub side: Option<ExecutionSide>,
+    pub amount: Option<Num>,
+    /// Execution the fee was charged on, if any
+    pub execution_id: Option<String>,
+    pub note: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// Fees summed by kind.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct FeeTotals {
+    pub commission: Num,
+    pub regulatory: Num,
+    pub adjustment: Num,
+    pub total: Num,
+}
+
+impl FeeTotals {
+    pub fn of(fees: &[Fee]) -> Self {
+        let mut totals = Self::default();
+        for fee in fees {
+            let amount = match &fee.amount {
+                Some(amount) => amount,
+                None => continue,
+            };
+            match fee.kind {
+                Some(FeeKind::Commission) => totals.commission = &totals.commission + amount,
+                Some(FeeKind::Sec) | Some(FeeKind::Taf) => {
+                    totals.regulatory = &totals.regulatory + amount
+                }
+                Some(FeeKind::Adjustment) | None => totals.adjustment = &totals.adjustment + amount,
+            }
+            totals.total = &totals.total + amount;
+        }
+        totals
+    }
+}
+
+impl Fee {
+    pub fn record(
+        lot: &Lot,
+        kind: FeeKind,
+        side: ExecutionSide,
+        amount: Num,
+        execution_id: Option<String>,
+        note: Option<String>,
+    ) -> Result<i64, turbosql::Error> {
+        Self {
+            lot_id: lot.rowid,
+            bucket_id: lot.bucket_id,
+            kind: Some(kind),
+            side: Some(side),
+            amount: Some(amount),
+            execution_id,
+            note,
+            created_at: Some(Utc::now()),
+            ..Default::default()
+        }
+        .insert()
+    }
+
+    /// Record the SEC and TAF fees for the execution in `update`, if it was a sale.
+    pub fn record_regulatory(
+        lot: &Lot,
+        side: ExecutionSide,
+        update: &TradeUpdate,
+    ) -> Result<(), turbosql::Error> {
+        if update.order.side != Side::Sell {
+            return Ok(());
+        }
+        let (price, qty) = match (&update.price, &update.qty) {
+            (Some(price), Some(qty)) => (price, qty),
+            _ => return Ok(()),
+        };
+        let (sec, taf) = regulatory_fees(price, qty);
+        for (kind, amount) in [(FeeKind::Sec, sec), (FeeKind::Taf, taf)] {
+            if amount > Num::from(0) {
+                Self::record(lot, kind, side, amount, update.execution_id.clone(), None)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn for_lot(lot_id: i64) -> Result<Vec<Self>, turbosql::Error> {
+        select!(Vec<Fee> "WHERE lot_id = ? ORDER BY rowid", lot_id)
+    }
+
+    pub fn for_bucket(bucket_id: i64) -> Result<Vec<Self>, turbosql::Error> {
+        select!(Vec<Fee> "WHERE bucket_id = ? ORDER BY rowid", bucket_id)
+    }
+}
+
+/// SEC and TAF fees on a sale of `qty` at `price`. The SEC fee is rounded up to the next cent,
+/// as the exchanges pass it on, and the TAF to the nearest cent.
+pub fn regulatory_fees(price: &Num, qty: &Num) -> (Num, Num) {
+    let sec_cents = price * qty * Num::new(SEC_FEE_CENTS_PER_MILLION, 1_000_000);
+    let whole_cents = sec_cents.trunc();
+    let sec_cents = if whole_cents < sec_cents {
+        whole_cents + Num::from(1)
+    } else {
+        whole_cents
+    };
+    let sec = sec_cents / Num::from(100);
+    let taf = qty * Num::new(TAF_PER_SHARE_MICROS, 1_000_000);
+    let taf_max = Num::new(TAF_MAX_CENTS, 100);
+    let taf = if taf > taf_max { taf_max } else { taf };
+    (sec, taf.round_with(2))
+}
+
+#[test]
+fn test_regulatory_fees() {
+    let (sec, taf) = regulatory_fees(&Num::from(100), &Num::from(1000));
+    assert_eq!(sec, Num::new(278, 100));
+    assert_eq!(taf, Num::new(17, 100));
+
+    // a fraction of a cent is still a cent
+    let (sec, _) = regulatory_fees(&Num::new(1001, 100), &Num::from(7));
+    assert_eq!(sec, Num::new(1, 100));
+
+    let (_, taf) = regulatory_fees(&Num::from(1), &Num::from(1_000_000));
+    assert_eq!(taf, Num::new(TAF_MAX_CENTS, 100));
+}
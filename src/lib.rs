@@ -1,4 +1,17 @@
+pub mod broker;
 pub mod bucket;
+pub mod cash_event;
+pub mod corporate_action;
+pub mod execution;
+pub mod exit_order;
+pub mod fee;
 pub mod lot;
+pub mod lot_event;
+pub mod market_data_client;
+pub mod price_adjustment;
+pub mod soft_exit;
 pub mod sync_lots;
+pub mod tax_lot;
 pub mod trade_update_client;
+pub mod tranche;
+pub mod wash_sale;
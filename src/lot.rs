@@ -1,11 +1,23 @@
+use crate::broker::{BrokerOrder, OrderId, OrderStatus, OrderType, Side};
 use crate::bucket::Bucket;
+use crate::cash_event::{CashEvent, CashTotals};
+use crate::corporate_action::StockSplit;
+use crate::execution::{Execution, ExecutionSide};
+use crate::fee::{Fee, FeeTotals};
+use crate::lot_event::{LotEvent, LotTransition};
+use crate::price_adjustment::{AdjustmentReason, PriceAdjustment, PriceField};
+use crate::sync_lots::LotUpdateEvent;
+use crate::tax_lot::LotSelection;
+use crate::tranche::Tranche;
+use crate::wash_sale::WashSale;
 
-use apca::api::v2::order as apcaOrder;
 use chrono::DateTime;
 use chrono::Utc;
+use chrono::{Datelike, Duration, NaiveDate};
 use num_decimal::Num;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::fmt;
 #[cfg(test)]
 use turbosql::execute;
 use turbosql::{select, ToSql, ToSqlOutput, Turbosql};
@@ -22,10 +34,51 @@ pub enum LotStatus {
     Disposed,
     /// The order expired or was canceled before it was filled.
     Canceled,
+    /// Part of the lot was sold or covered, and it was split into a disposed and an open lot
+    /// that carry on in its place.
+    Split,
     /// One of the other statuses, needs manual followup.
     Other,
 }
 
+impl LotStatus {
+    /// The lot lifecycle: Pending → Open → Disposed, or Pending → Canceled, or Open → Split after
+    /// a partial exit. Either live status can be flagged Other, and Other can be resolved to
+    /// anything. Everything else is illegal, including leaving Disposed, Canceled or Split.
+    pub fn can_transition_to(&self, next: LotStatus) -> bool {
+        matches!(
+            (self, next),
+            (LotStatus::Pending, LotStatus::Open)
+                | (LotStatus::Pending, LotStatus::Canceled)
+                | (LotStatus::Pending, LotStatus::Other)
+                | (LotStatus::Open, LotStatus::Disposed)
+                | (LotStatus::Open, LotStatus::Split)
+                | (LotStatus::Open, LotStatus::Other)
+                | (LotStatus::Other, _)
+        ) || *self == next
+    }
+}
+
+/// A status change that the lot lifecycle does not allow.
+#[derive(Debug)]
+pub struct IllegalTransition {
+    pub client_id: Option<String>,
+    pub from: Option<LotStatus>,
+    pub to: LotStatus,
+}
+
+impl fmt::Display for IllegalTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "lot {:?} cannot go from {:?} to {:?}",
+            self.client_id, self.from, self.to
+        )
+    }
+}
+
+impl Error for IllegalTransition {}
+
 /// needs to be implemented for any enum that is used in `select!` macro params.
 // Need to make this a derive macro, but I've already spent way too much time on this, and sqlite
 // is temporary anyway.
@@ -52,6 +105,24 @@ pub enum PositionType {
     Short,
 }
 
+impl PositionType {
+    /// Side of the order that opens a position of this type.
+    pub fn entry_side(&self) -> Side {
+        match self {
+            PositionType::Long => Side::Buy,
+            PositionType::Short => Side::Sell,
+        }
+    }
+
+    /// Side of the order that closes a position of this type.
+    pub fn exit_side(&self) -> Side {
+        match self {
+            PositionType::Long => Side::Sell,
+            PositionType::Short => Side::Buy,
+        }
+    }
+}
+
 /// A description of the time for which an order is valid.
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub enum OrderTimeInForce {
@@ -71,10 +142,10 @@ pub struct Lot {
     /// Our local ID for the lot.
     pub client_id: Option<String>,
     /// Time original order was submitted.
-    pub created_at: Option<DateTime<Utc>>, // TODO: need to track filled_at too
+    pub created_at: Option<DateTime<Utc>>,
     /// Symbol of the position
     pub sym: Option<String>,
-    /// Number of shares or contracts or coins
+    /// Number of shares or contracts or coins held, or ordered while the lot is pending
     pub qty: Option<Num>,
     /// Long or Short
     pub position_type: Option<PositionType>,
@@ -90,7 +161,7 @@ pub struct Lot {
     pub target_price: Option<Num>,
     /// Original stop loss price as entered by the user
     pub stop_price: Option<Num>,
-    /// Total cost basis for the lot
+    /// Total cost basis for the lot, net of entry fees
     pub cost_basis: Option<Num>,
     /// Time order was sold or covered.
     pub disposed_at: Option<DateTime<Utc>>,
@@ -99,17 +170,296 @@ pub struct Lot {
     /// Reason for disposal
     pub dispose_reason: Option<DisposeReason>,
     /// The current status on the broker system, as of the last update
-    pub broker_status: Option<apcaOrder::Status>,
+    pub broker_status: Option<OrderStatus>,
     /// ID of the opening order in the broker system
-    pub open_order_id: Option<apcaOrder::Id>,
+    pub open_order_id: Option<OrderId>,
     /// ID of the closing order in the broker system
-    pub disposing_order_id: Option<apcaOrder::Id>,
+    pub disposing_order_id: Option<OrderId>,
     /// ID of the stop order in the broker system
-    pub stop_order_id: Option<apcaOrder::Id>,
+    pub stop_order_id: Option<OrderId>,
     /// ID of the target order in the broker system
-    pub target_order_id: Option<apcaOrder::Id>,
+    pub target_order_id: Option<OrderId>,
     /// ID of the bucket
     pub bucket_id: Option<i64>,
+    /// Quantity originally requested in the opening order
+    pub ordered_qty: Option<Num>,
+    /// Cumulative quantity filled on the opening order
+    pub filled_qty: Option<Num>,
+    /// Time of the first fill on the opening order, when the holding period starts.
+    pub opened_at: Option<DateTime<Utc>>,
+    /// Fees charged on the opening side, included in the cost basis
+    pub entry_fees: Option<Num>,
+    /// Fees charged on the closing side, taken out of the proceeds
+    pub exit_fees: Option<Num>,
+    /// Dollar offset of a trailing stop exit
+    pub trail_price: Option<Num>,
+    /// Percent offset of a trailing stop exit
+    pub trail_percent: Option<Num>,
+    /// Best price seen by the trailing stop, the highest for a long and the lowest for a short
+    pub high_water_mark: Option<Num>,
+    /// ID of the one-cancels-other exit order in the broker system, for lots opened without a
+    /// bracket
+    pub exit_order_id: Option<OrderId>,
+    /// The stop is watched by zoocarp instead of resting on the broker
+    pub soft_stop: Option<bool>,
+    /// The target is watched by zoocarp instead of resting on the broker
+    pub soft_target: Option<bool>,
+    /// Order sent when a soft stop or target is crossed, market by default or a limit at the level
+    pub soft_exit_type: Option<OrderType>,
+    /// Which soft level was crossed
+    pub soft_trigger: Option<DisposeReason>,
+    /// Price that crossed the soft level
+    pub soft_trigger_price: Option<Num>,
+    pub soft_triggered_at: Option<DateTime<Utc>>,
+    /// Number of take-profit tranches for a lot that scales out, None for a single target
+    pub tranche_count: Option<i64>,
+    /// Quantity sold or covered so far, which grows with each tranche of a lot that scales out
+    pub disposed_qty: Option<Num>,
+    /// Row ID of the lot this one was split from
+    pub parent_id: Option<i64>,
+    /// Quantity of the partially filled exit already disposed of by the lots split off before
+    /// this one, and its value
+    pub split_exit_qty: Option<Num>,
+    pub split_exit_value: Option<Num>,
+    /// Shares per original share from the stock splits applied to the lot. The broker keeps
+    /// reporting the opening order on the original basis.
+    pub split_ratio: Option<Num>,
+    /// Dividends and interest received on the lot, less dividends owed on a short, along with its
+    /// share of those paid to the lots it was split from
+    pub cash_income: Option<Num>,
+    /// How the lot was chosen for the sale that disposed of it, when several lots were sold
+    /// together
+    pub lot_selection: Option<LotSelection>,
+    /// Loss on the sale of the lot disallowed as a wash sale
+    pub wash_disallowed_loss: Option<Num>,
+    /// Losses disallowed on wash sales the lot was the replacement for, added to its tax basis
+    pub wash_basis_adjustment: Option<Num>,
+    /// Why the opening order was canceled, rejected or expired
+    pub status_reason: Option<String>,
+}
+
+/// Tax treatment of a holding period.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum HoldingTerm {
+    /// Held one year or less.
+    ShortTerm,
+    /// Held more than one year.
+    LongTerm,
+}
+
+/// A lot along with values computed for display.
+#[derive(Debug, Serialize, Clone)]
+pub struct LotWithStats {
+    #[serde(flatten)]
+    pub lot: Lot,
+    /// Whole days held, through disposal or today
+    pub holding_days: Option<i64>,
+    pub holding_term: Option<HoldingTerm>,
+    /// Days until an open short-term lot turns long-term
+    pub days_to_long_term: Option<i64>,
+    /// Result of a disposed lot
+    pub realized: Option<RealizedGain>,
+    /// Price at which the lot stops out, following the high-water mark for a trailing stop
+    pub effective_stop: Option<Num>,
+}
+
+/// The outcome of a disposed lot. Slippage is per unit against the price the user entered,
+/// positive when the fill was worse than asked for.
+#[derive(Clone, Debug, Serialize, Eq, PartialEq)]
+pub struct RealizedGain {
+    /// Value of the disposal net of exit fees
+    pub proceeds: Num,
+    pub gain: Num,
+    pub gain_pct: Option<Num>,
+    /// Entry fill against `limit_price`
+    pub entry_slippage: Option<Num>,
+    pub entry_slippage_pct: Option<Num>,
+    /// Exit fill against `stop_price` or `target_price`, depending on how the lot was disposed
+    pub exit_slippage: Option<Num>,
+    pub exit_slippage_pct: Option<Num>,
+    /// Gain plus cash income
+    pub total_return: Num,
+    pub total_return_pct: Option<Num>,
+    /// Gain as reported for taxes, with the loss disallowed by a wash sale added back and the
+    /// losses moved onto the lot's basis taken out
+    pub tax_gain: Num,
+}
+
+fn percent_of(part: &Num, whole: &Num) -> Option<Num> {
+    if *whole == Num::from(0) {
+        None
+    } else {
+        Some(part / whole * Num::from(100))
+    }
+}
+
+/// Why an opening order in `status` went unfilled, when the broker gave no reason.
+fn closed_reason(status: OrderStatus) -> String {
+    match status {
+        OrderStatus::Rejected => "rejected by the broker".to_string(),
+        OrderStatus::Expired => "expired unfilled".to_string(),
+        _ => "canceled".to_string(),
+    }
+}
+
+/// Per unit slippage of a fill on the given side, positive when it was worse than `expected`.
+fn slippage(side: Side, expected: &Num, fill: &Num) -> Num {
+    match side {
+        Side::Buy => fill - expected,
+        Side::Sell => expected - fill,
+    }
+}
+
+impl LotWithStats {
+    pub fn new(lot: Lot, now: DateTime<Utc>) -> Self {
+        let holding_term = lot.holding_term(now);
+        let days_to_long_term = match (lot.status, holding_term, lot.long_term_date()) {
+            (Some(LotStatus::Open), Some(HoldingTerm::ShortTerm), Some(date)) => {
+                Some((date - now.naive_utc().date()).num_days())
+            }
+            _ => None,
+        };
+        Self {
+            holding_days: lot.holding_period(now).map(|held| held.num_days()),
+            holding_term,
+            days_to_long_term,
+            realized: lot.realized(),
+            effective_stop: lot.trailing_stop_level().or_else(|| lot.stop_price.clone()),
+            lot,
+        }
+    }
+}
+
+/// A single lot with its stats and the history of its prices.
+#[derive(Debug, Serialize, Clone)]
+pub struct LotDetail {
+    #[serde(flatten)]
+    pub stats: LotWithStats,
+    pub adjustments: Vec<PriceAdjustment>,
+    /// Take-profit levels of a lot that scales out
+    pub tranches: Vec<Tranche>,
+    /// The disposed and the open lot a split lot was divided into
+    pub children: Vec<Lot>,
+    /// Wash sales the lot was sold in or bought as the replacement for
+    pub wash_sales: Vec<WashSale>,
+}
+
+impl LotDetail {
+    pub fn get(client_id: &str, now: DateTime<Utc>) -> Result<Self, Box<dyn Error>> {
+        let lot = Lot::get_by_client_id(client_id)?;
+        let children = match lot.status {
+            Some(LotStatus::Split) => lot.children()?,
+            _ => vec![],
+        };
+        let tranches = match (lot.scales_out(), lot.rowid) {
+            (true, Some(rowid)) => Tranche::for_lot(rowid)?,
+            _ => vec![],
+        };
+        let wash_sales = match lot.rowid {
+            Some(rowid) => WashSale::for_lot(rowid)?,
+            None => vec![],
+        };
+        Ok(Self {
+            stats: LotWithStats::new(lot, now),
+            adjustments: PriceAdjustment::history(client_id)?,
+            tranches,
+            children,
+            wash_sales,
+        })
+    }
+}
+
+/// An open lot valued at the latest trade price.
+#[derive(Debug, Serialize, Clone)]
+pub struct LotMark {
+    #[serde(flatten)]
+    pub lot: Lot,
+    /// Latest trade price, None if the broker had no trade for the symbol
+    pub price: Option<Num>,
+    pub market_value: Option<Num>,
+    pub unrealized_gain: Option<Num>,
+    pub unrealized_gain_pct: Option<Num>,
+    /// Unrealized gain plus cash income
+    pub total_return: Option<Num>,
+    /// How far the price can move against the lot before the stop is hit
+    pub stop_distance: Option<Num>,
+    pub stop_distance_pct: Option<Num>,
+    /// How far the price has to move in favor of the lot to reach the target
+    pub target_distance: Option<Num>,
+    pub target_distance_pct: Option<Num>,
+}
+
+impl LotMark {
+    pub fn new(lot: Lot, price: Option<Num>) -> Self {
+        let position_type = lot.position_type.unwrap_or_default();
+        // shares a tranche or a sale of part of the lot disposed of are no longer marked, and take
+        // their share of the cost basis with them
+        let qty = lot.open_qty().or_else(|| lot.qty.clone());
+        let market_value = match (&price, &qty) {
+            (Some(price), Some(qty)) => Some(price * qty),
+            _ => None,
+        };
+        let cost_basis = match (&lot.cost_basis, &lot.filled_qty, &qty) {
+            (Some(cost_basis), Some(filled_qty), Some(qty)) if *filled_qty > Num::from(0) => {
+                Some(&(cost_basis * qty) / filled_qty)
+            }
+            (cost_basis, _, _) => cost_basis.clone(),
+        };
+        let unrealized_gain = match (&market_value, &cost_basis) {
+            (Some(value), Some(cost_basis)) => Some(match position_type {
+                PositionType::Long => value - cost_basis,
+                PositionType::Short => cost_basis - value,
+            }),
+            _ => None,
+        };
+        // both distances are positive while the price sits between the stop and the target
+        let stop_distance = match (&price, &lot.stop_price) {
+            (Some(price), Some(stop)) => Some(match position_type {
+                PositionType::Long => price - stop,
+                PositionType::Short => stop - price,
+            }),
+            _ => None,
+        };
+        let target_distance = match (&price, &lot.target_price) {
+            (Some(price), Some(target)) => Some(match position_type {
+                PositionType::Long => target - price,
+                PositionType::Short => price - target,
+            }),
+            _ => None,
+        };
+        let total_return = unrealized_gain.as_ref().map(|gain| match &lot.cash_income {
+            Some(income) => gain + income,
+            None => gain.clone(),
+        });
+        let pct_of_price = |distance: &Option<Num>| match (distance, &price) {
+            (Some(distance), Some(price)) => percent_of(distance, price),
+            _ => None,
+        };
+
+        Self {
+            unrealized_gain_pct: match (&unrealized_gain, &cost_basis) {
+                (Some(gain), Some(cost_basis)) => percent_of(gain, cost_basis),
+                _ => None,
+            },
+            stop_distance_pct: pct_of_price(&stop_distance),
+            target_distance_pct: pct_of_price(&target_distance),
+            lot,
+            price,
+            market_value,
+            unrealized_gain,
+            total_return,
+            stop_distance,
+            target_distance,
+        }
+    }
+}
+
+/// How far along the opening order is.
+#[derive(Clone, Debug, Serialize, Eq, PartialEq)]
+pub struct FillProgress {
+    pub filled_qty: Num,
+    pub ordered_qty: Num,
+    pub remaining_qty: Num,
 }
 
 impl Lot {
@@ -127,7 +477,8 @@ impl Lot {
             created_at: Some(Utc::now()),
             client_id: Uuid::new_v4().to_string().into(),
             sym: Some(sym),
-            qty: Some(qty),
+            qty: Some(qty.clone()),
+            ordered_qty: Some(qty),
             position_type: Some(position_type),
             status: Some(LotStatus::Pending),
             bucket_id: bucket.rowid,
@@ -137,7 +488,13 @@ impl Lot {
             stop_price,
             ..Default::default()
         };
-        lot.insert().unwrap()
+        let rowid = lot.insert().unwrap();
+        let lot = Self {
+            rowid: Some(rowid),
+            ..lot
+        };
+        LotEvent::record(&lot, LotTransition::Created, None, None, None).unwrap();
+        rowid
     }
 
     pub fn get(rowid: i64) -> Result<Self, Box<dyn Error>> {
@@ -150,65 +507,283 @@ impl Lot {
         Ok(lot)
     }
 
+    /// The lots an exit order belongs to, by the IDs of their stop, target, one-cancels-other
+    /// exit or disposing order. Lots that were split are left out, the lots split from them
+    /// carry the IDs on. A sale of several lots disposes of all of them with one order.
+    pub fn for_exit_order(id: &OrderId) -> Result<Vec<Self>, turbosql::Error> {
+        select!(
+            Vec<Lot>
+            "WHERE (stop_order_id = ? OR target_order_id = ? OR exit_order_id = ? OR disposing_order_id = ?) AND status != ?",
+            id,
+            id,
+            id,
+            id,
+            LotStatus::Split
+        )
+    }
+
+    /// Sync the lot with a fill on one of its exits. A liquidation or sale disposed of the lot
+    /// when it was sent, so its fill only brings the price; any other exit is tracked as usual.
+    pub fn apply_exit_fill(&mut self, order: &BrokerOrder) -> Result<&mut Self, Box<dyn Error>> {
+        match self.status {
+            Some(LotStatus::Open) => self.track_exit(order),
+            Some(LotStatus::Disposed)
+                if self.dispose_reason == Some(DisposeReason::Liquidation)
+                    && self.disposing_order_id.as_ref() == Some(&order.id)
+                    && order.status == OrderStatus::Filled =>
+            {
+                if self.disposed_fill_price != order.average_fill_price {
+                    self.disposed_fill_price = order.average_fill_price.clone();
+                    self.disposed_at = order.filled_at.or(self.disposed_at);
+                    self.update()?;
+                }
+                Ok(self)
+            }
+            _ => Ok(self),
+        }
+    }
+
+    /// Side of the order that opens the lot.
+    pub fn entry_side(&self) -> Side {
+        self.position_type.unwrap_or_default().entry_side()
+    }
+
+    /// Side of any order that closes the lot, sell for a long and buy to cover for a short.
+    pub fn exit_side(&self) -> Side {
+        self.position_type.unwrap_or_default().exit_side()
+    }
+
+    /// Look for a filled exit of `order_type` among `exits`, the legs of a bracket or the pair of
+    /// a one-cancels-other order, and dispose of the lot if there is one.
     pub fn detect_disposal<F>(
         &mut self,
-        order: &apcaOrder::Order,
-        order_type: apcaOrder::Type,
+        exits: &[BrokerOrder],
+        order_type: OrderType,
         reason: DisposeReason,
         field_fill: F,
     ) -> Result<(), Box<dyn Error>>
     where
-        F: FnOnce(&mut Lot, apcaOrder::Order),
+        F: FnOnce(&mut Lot, BrokerOrder),
     {
-        let disposing_order = order
-            .legs
-            .clone()
-            .into_iter()
-            .filter(|leg| leg.type_ == order_type)
-            .next();
+        // the closing legs of a short are buys
+        let exit_side = self.exit_side();
+        let disposing_order = exits
+            .iter()
+            .find(|leg| leg.type_ == order_type && leg.side == exit_side);
 
         if let Some(disposing_order) = disposing_order {
-            match disposing_order.status {
-                apcaOrder::Status::Filled | apcaOrder::Status::PartiallyFilled => {
-                    tracing::debug!(
-                        "detect_disposal: {:?} lot {:?} order {:?}",
-                        reason,
-                        self.client_id,
-                        disposing_order.id
-                    );
-                    self.status = Some(LotStatus::Disposed);
-                    self.disposed_at = disposing_order.filled_at;
-                    self.disposed_fill_price = disposing_order.average_fill_price.clone();
-                    self.dispose_reason = Some(reason);
-                    self.disposing_order_id = Some(disposing_order.id);
-                }
-                _ => {}
-            }
-            field_fill(self, disposing_order);
+            // the order IDs go along to the open part if the lot is split
+            field_fill(self, disposing_order.clone());
+            self.dispose_with_exit(disposing_order, reason)?;
         }
         Ok(())
     }
 
-    pub fn fill_with(&mut self, order: &apcaOrder::Order) -> Result<&mut Self, turbosql::Error> {
+    /// Dispose of the lot with what `exit` filled. A partial fill splits the lot instead: the part
+    /// sold or covered goes to a disposed child, and the rest to an open child which follows the
+    /// exit from there. Returns the open child if there was a split.
+    pub fn dispose_with_exit(
+        &mut self,
+        exit: &BrokerOrder,
+        reason: DisposeReason,
+    ) -> Result<Option<Lot>, Box<dyn Error>> {
+        if !matches!(
+            exit.status,
+            OrderStatus::Filled | OrderStatus::PartiallyFilled
+        ) {
+            return Ok(None);
+        }
+        tracing::debug!(
+            "dispose_with_exit: {:?} lot {:?} order {:?}",
+            reason,
+            self.client_id,
+            exit.id
+        );
+        // only the part of the exit not taken by the lots split off before this one
+        let prior_qty = self.split_exit_qty.clone().unwrap_or_default();
+        let qty = &exit.filled_quantity - &prior_qty;
+        let price = match (&exit.average_fill_price, &self.split_exit_value) {
+            (Some(price), Some(prior_value)) if qty > Num::from(0) => {
+                Some(&(price * &exit.filled_quantity - prior_value) / &qty)
+            }
+            (price, _) => price.clone(),
+        };
+
+        let held = self.qty.clone().unwrap_or_default();
+        if exit.status == OrderStatus::PartiallyFilled {
+            if self.status != Some(LotStatus::Open) || qty <= Num::from(0) || qty >= held {
+                return Ok(None);
+            }
+            let (_, open) = self.split(&qty, price, reason, exit)?;
+            return Ok(Some(open));
+        }
+        if self.transition_to(LotStatus::Disposed).is_ok() {
+            self.disposed_at = exit.filled_at;
+            self.disposed_fill_price = price;
+            self.dispose_reason = Some(reason);
+            self.disposing_order_id = Some(exit.id.clone());
+        }
+        Ok(None)
+    }
+
+    /// Split the lot into a disposed child for `qty` sold or covered by `exit` at `price`, and an
+    /// open child with the rest. Cost basis and fees are shared out by quantity, and the lot
+    /// itself becomes Split.
+    pub fn split(
+        &mut self,
+        qty: &Num,
+        price: Option<Num>,
+        reason: DisposeReason,
+        exit: &BrokerOrder,
+    ) -> Result<(Lot, Lot), Box<dyn Error>> {
+        let previous_status = self.status;
+        let held = self.qty.clone().unwrap_or_default();
+        self.transition_to(LotStatus::Split)?;
+
+        let mut disposed = self.split_child(qty, &held);
+        disposed.status = Some(LotStatus::Disposed);
+        disposed.disposed_at = exit.filled_at;
+        disposed.disposed_fill_price = price;
+        disposed.dispose_reason = Some(reason);
+        disposed.disposing_order_id = Some(exit.id.clone());
+
+        let mut open = self.split_child(&(&held - qty), &held);
+        open.split_exit_qty = Some(exit.filled_quantity.clone());
+        open.split_exit_value = exit
+            .average_fill_price
+            .as_ref()
+            .map(|price| price * &exit.filled_quantity);
+
+        let payload = serde_json::to_string(exit).ok();
+        self.update()?;
+        LotEvent::record(
+            self,
+            LotTransition::Split,
+            previous_status,
+            None,
+            payload.clone(),
+        )?;
+        for child in [&mut disposed, &mut open] {
+            child.rowid = Some(child.insert()?);
+            LotEvent::record(
+                child,
+                LotTransition::Split,
+                previous_status,
+                None,
+                payload.clone(),
+            )?;
+        }
+        Ok((disposed, open))
+    }
+
+    pub fn children(&self) -> Result<Vec<Lot>, turbosql::Error> {
+        select!(Vec<Lot> "WHERE parent_id = ? ORDER BY rowid", self.rowid)
+    }
+
+    /// A copy of the lot holding `qty` of the `held` quantity, with its share of the cost basis
+    /// and fees.
+    fn split_child(&self, qty: &Num, held: &Num) -> Lot {
+        let share = |value: &Option<Num>| value.as_ref().map(|value| &(value * qty) / held);
+        Lot {
+            rowid: None,
+            client_id: Some(Uuid::new_v4().to_string()),
+            parent_id: self.rowid,
+            status: Some(LotStatus::Open),
+            qty: Some(qty.clone()),
+            ordered_qty: Some(qty.clone()),
+            filled_qty: Some(qty.clone()),
+            cost_basis: share(&self.cost_basis),
+            entry_fees: share(&self.entry_fees),
+            exit_fees: share(&self.exit_fees),
+            cash_income: share(&self.cash_income),
+            wash_basis_adjustment: share(&self.wash_basis_adjustment),
+            created_at: Some(Utc::now()),
+            ..self.clone()
+        }
+    }
+
+    /// The lots this one was split from, each with the lot's share of its quantity.
+    pub(crate) fn ancestor_shares(&self) -> Result<Vec<(i64, Num)>, turbosql::Error> {
+        let qty = self.qty.clone().unwrap_or_default();
+        let mut shares = vec![];
+        let mut parent_id = self.parent_id;
+        while let Some(id) = parent_id {
+            let parent = select!(Lot "WHERE rowid = ?", id)?;
+            if let Some(parent_qty) = parent.qty.as_ref().filter(|q| **q > Num::from(0)) {
+                shares.push((id, &qty / parent_qty));
+            }
+            parent_id = parent.parent_id;
+        }
+        Ok(shares)
+    }
+
+    /// Fees charged to the lot, along with its share of those charged to the lots it was split
+    /// from.
+    pub fn allocated_fees(&self) -> Result<Vec<Fee>, turbosql::Error> {
+        let rowid = match self.rowid {
+            Some(rowid) => rowid,
+            None => return Ok(vec![]),
+        };
+        let mut fees = Fee::for_lot(rowid)?;
+        for (id, share) in self.ancestor_shares()? {
+            fees.extend(Fee::for_lot(id)?.into_iter().map(|mut fee| {
+                fee.amount = fee.amount.map(|amount| &amount * &share);
+                fee
+            }));
+        }
+        Ok(fees)
+    }
+
+    /// Cash events paid on the lot, along with its share of those paid on the lots it was split
+    /// from.
+    pub fn allocated_cash_events(&self) -> Result<Vec<CashEvent>, turbosql::Error> {
+        let rowid = match self.rowid {
+            Some(rowid) => rowid,
+            None => return Ok(vec![]),
+        };
+        let mut events = CashEvent::for_lot(rowid)?;
+        for (id, share) in self.ancestor_shares()? {
+            events.extend(CashEvent::for_lot(id)?.into_iter().map(|mut event| {
+                event.amount = event.amount.map(|amount| &amount * &share);
+                event
+            }));
+        }
+        Ok(events)
+    }
+
+    pub fn fill_with(&mut self, order: &BrokerOrder) -> Result<&mut Self, turbosql::Error> {
         let orig_lot = self.clone();
-        let qty = order.filled_quantity.clone();
 
-        self.set_status_from(&order.status);
-        self.open_order_id = Some(order.id);
-        self.limit_price = order.limit_price.clone();
+        if self.set_status_from(&order.status).is_err() {
+            return Ok(self);
+        }
+        self.open_order_id = Some(order.id.clone());
+        self.adjust_price(
+            PriceField::Limit,
+            self.restate_price(&order.limit_price),
+            AdjustmentReason::Broker,
+        )?;
 
         match order.status {
-            apcaOrder::Status::Filled | apcaOrder::Status::PartiallyFilled => {
+            OrderStatus::Filled | OrderStatus::PartiallyFilled => {
                 tracing::debug!(
                     "fill_with: {:?} {:?} {:?}",
                     order.status,
                     self.sym,
                     order.id
                 );
-                self.filled_avg_price = order.average_fill_price.clone();
-                self.set_cost_basis(&qty, &order.average_fill_price);
+                self.record_fill(
+                    &order.filled_quantity,
+                    &order.average_fill_price,
+                    order.filled_at,
+                );
+                self.apply_executions()?;
+            }
+            OrderStatus::Canceled | OrderStatus::Expired | OrderStatus::Rejected => {
+                if self.status_reason.is_none() {
+                    self.status_reason = Some(closed_reason(order.status));
+                }
             }
-            // TODO: Expired, Rejected
             _ => {
                 tracing::debug!(
                     "lot::fill_with: order {} status {:?}",
@@ -220,67 +795,855 @@ impl Lot {
 
         // a bracket order has a stop leg and a limit leg. The original order is already
         // filled, so need to check each to see if either target was hit or stop was hit.
-        if &order.legs.len() > &0 {
+        if !order.legs.is_empty() {
             self.detect_disposal(
-                order,
-                apcaOrder::Type::Stop,
+                &order.legs,
+                OrderType::Stop,
                 DisposeReason::StopOut,
-                &mut |lot: &mut Lot, order: apcaOrder::Order| {
+                &mut |lot: &mut Lot, order: BrokerOrder| {
                     lot.stop_order_id = Some(order.id);
                 },
             )
             .unwrap();
 
             self.detect_disposal(
-                order,
-                apcaOrder::Type::Limit,
+                &order.legs,
+                OrderType::Limit,
                 DisposeReason::Profit,
-                &mut |lot: &mut Lot, order: apcaOrder::Order| {
+                &mut |lot: &mut Lot, order: BrokerOrder| {
                     lot.target_order_id = Some(order.id);
                 },
             )
             .unwrap();
         };
+        // a partial exit that split the lot already saved it and recorded the fill as the split
+        if orig_lot != *self && self.status != Some(LotStatus::Split) {
+            self.update()?;
+            LotEvent::record(
+                self,
+                LotTransition::Filled,
+                orig_lot.status,
+                None,
+                serde_json::to_string(order).ok(),
+            )?;
+        }
+        Ok(self)
+    }
+
+    /// Apply a trade update on the opening order other than a fill. An order canceled, expired
+    /// or rejected before any of it filled cancels the lot, with `reason` or a generic one, while
+    /// one partly filled leaves the lot holding what was filled. A replacement moves the lot onto
+    /// the new order, and done for day only marks the broker status. Returns whether the lot
+    /// changed; it is not saved.
+    pub fn apply_order_update(
+        &mut self,
+        event: LotUpdateEvent,
+        order: &BrokerOrder,
+        reason: Option<String>,
+    ) -> Result<bool, turbosql::Error> {
+        let orig_lot = self.clone();
+        match event {
+            LotUpdateEvent::Fill | LotUpdateEvent::PartialFill => return Ok(false),
+            LotUpdateEvent::New => {
+                if self.set_status_from(&order.status).is_err() {
+                    return Ok(false);
+                }
+                self.open_order_id = Some(order.id.clone());
+            }
+            LotUpdateEvent::Canceled | LotUpdateEvent::Expired | LotUpdateEvent::Rejected => {
+                if order.filled_quantity > Num::from(0) {
+                    if self.transition_to(LotStatus::Open).is_err() {
+                        return Ok(false);
+                    }
+                    self.record_fill(
+                        &order.filled_quantity,
+                        &order.average_fill_price,
+                        order.filled_at,
+                    );
+                    self.broker_status = Some(order.status);
+                } else {
+                    if self.set_status_from(&order.status).is_err() {
+                        return Ok(false);
+                    }
+                    self.status_reason =
+                        Some(reason.unwrap_or_else(|| closed_reason(order.status)));
+                }
+            }
+            LotUpdateEvent::Replaced => {
+                self.open_order_id = Some(order.id.clone());
+                self.broker_status = Some(order.status);
+                if let Some(qty) = &order.qty {
+                    self.ordered_qty = Some(self.restate_qty(qty));
+                }
+                self.adjust_price(
+                    PriceField::Limit,
+                    self.restate_price(&order.limit_price),
+                    AdjustmentReason::Broker,
+                )?;
+            }
+            LotUpdateEvent::DoneForDay => self.broker_status = Some(OrderStatus::DoneForDay),
+            // not an event of the opening order
+            LotUpdateEvent::SoftExitTriggered => return Ok(false),
+        }
+        Ok(orig_lot != *self)
+    }
+
+    /// Cancel a lot whose opening order the broker refused outright, keeping its reason.
+    pub fn reject_with(&mut self, reason: &str) -> Result<&mut Self, Box<dyn Error>> {
+        let previous_status = self.status;
+        self.transition_to(LotStatus::Canceled)?;
+        self.broker_status = Some(OrderStatus::Rejected);
+        self.status_reason = Some(reason.to_string());
+        self.update()?;
+        LotEvent::record(
+            self,
+            LotTransition::Rejected,
+            previous_status,
+            None,
+            Some(reason.to_string()),
+        )?;
+        Ok(self)
+    }
+
+    /// Set one of the lot's prices, recording the change if the price moved. Does not save the lot.
+    pub fn adjust_price(
+        &mut self,
+        field: PriceField,
+        price: Option<Num>,
+        reason: AdjustmentReason,
+    ) -> Result<bool, turbosql::Error> {
+        let current = match field {
+            PriceField::Limit => &mut self.limit_price,
+            PriceField::Stop => &mut self.stop_price,
+            PriceField::Target => &mut self.target_price,
+        };
+        if *current == price {
+            return Ok(false);
+        }
+        let old_price = std::mem::replace(current, price.clone());
+        PriceAdjustment::record(self, field, old_price, price, reason)?;
+        Ok(true)
+    }
+
+    /// Take the new stop and/or target after their legs were replaced on the broker. The
+    /// replacements carry new order IDs.
+    pub fn modify_bracket(
+        &mut self,
+        stop: Option<&BrokerOrder>,
+        target: Option<&BrokerOrder>,
+        reason: AdjustmentReason,
+    ) -> Result<&mut Self, Box<dyn Error>> {
+        if let Some(stop) = stop {
+            self.adjust_price(PriceField::Stop, stop.stop_price.clone(), reason)?;
+            self.stop_order_id = Some(stop.id.clone());
+        }
+        if let Some(target) = target {
+            self.adjust_price(PriceField::Target, target.limit_price.clone(), reason)?;
+            self.target_order_id = Some(target.id.clone());
+        }
+        self.update()?;
+        LotEvent::record(
+            self,
+            LotTransition::BracketModified,
+            self.status,
+            Some(LotUpdateEvent::Replaced),
+            serde_json::to_string(&[stop, target]).ok(),
+        )?;
+        Ok(self)
+    }
+
+    pub fn is_trailing(&self) -> bool {
+        self.trail_price.is_some() || self.trail_percent.is_some()
+    }
+
+    /// Whether the lot holds all its opening order will fill, the order having filled in full or
+    /// closed after filling in part.
+    pub fn entry_complete(&self) -> bool {
+        self.status == Some(LotStatus::Open)
+            && self
+                .broker_status
+                .map_or(false, |status| status.is_terminal())
+    }
+
+    /// Stop level implied by the high-water mark and the trail offset.
+    pub fn trailing_stop_level(&self) -> Option<Num> {
+        let mark = self.high_water_mark.as_ref()?;
+        let offset = match (&self.trail_price, &self.trail_percent) {
+            (Some(trail_price), _) => trail_price.clone(),
+            (None, Some(trail_percent)) => mark * trail_percent / Num::from(100),
+            (None, None) => return None,
+        };
+        Some(match self.position_type.unwrap_or_default() {
+            PositionType::Long => mark - offset,
+            PositionType::Short => mark + offset,
+        })
+    }
+
+    /// Sync the lot with its trailing stop order: the trail, the high-water mark and the stop
+    /// level it implies, and the disposal once the stop fills.
+    pub fn track_trailing_stop(
+        &mut self,
+        order: &BrokerOrder,
+    ) -> Result<&mut Self, Box<dyn Error>> {
+        let orig_lot = self.clone();
+
+        self.stop_order_id = Some(order.id.clone());
+        self.trail_price = order.trail_price.clone();
+        self.trail_percent = order.trail_percent.clone();
+        if order.high_water_mark.is_some() {
+            self.high_water_mark = order.high_water_mark.clone();
+        }
+        if order.stop_price.is_some() {
+            self.adjust_price(
+                PriceField::Stop,
+                order.stop_price.clone(),
+                AdjustmentReason::Trailing,
+            )?;
+        }
+        self.dispose_with_exit(order, DisposeReason::StopOut)?;
+
         if orig_lot != *self {
             self.update()?;
+            LotEvent::record(
+                self,
+                LotTransition::TrailingStop,
+                orig_lot.status,
+                None,
+                serde_json::to_string(order).ok(),
+            )?;
         }
         Ok(self)
     }
 
-    pub fn liquidate_with(
+    /// Sync the lot with its one-cancels-other exit, the same way `fill_with` follows bracket legs.
+    pub fn track_oco(&mut self, order: &BrokerOrder) -> Result<&mut Self, Box<dyn Error>> {
+        let orig_lot = self.clone();
+
+        self.exit_order_id = Some(order.id.clone());
+        let exits = order.flatten();
+        self.detect_disposal(
+            &exits,
+            OrderType::Stop,
+            DisposeReason::StopOut,
+            |lot: &mut Lot, order: BrokerOrder| {
+                lot.stop_order_id = Some(order.id);
+            },
+        )?;
+        self.detect_disposal(
+            &exits,
+            OrderType::Limit,
+            DisposeReason::Profit,
+            |lot: &mut Lot, order: BrokerOrder| {
+                lot.target_order_id = Some(order.id);
+            },
+        )?;
+
+        if orig_lot != *self {
+            self.update()?;
+            if self.status != orig_lot.status {
+                LotEvent::record(
+                    self,
+                    LotTransition::ExitFilled,
+                    orig_lot.status,
+                    None,
+                    serde_json::to_string(order).ok(),
+                )?;
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn has_soft_exits(&self) -> bool {
+        self.soft_stop.unwrap_or(false) || self.soft_target.unwrap_or(false)
+    }
+
+    /// The soft level crossed by a trade at `price`, if any. The stop wins if both are crossed.
+    pub fn soft_exit_crossed(&self, price: &Num) -> Option<DisposeReason> {
+        if self.status != Some(LotStatus::Open) || self.soft_triggered_at.is_some() {
+            return None;
+        }
+        let long = self.position_type.unwrap_or_default() == PositionType::Long;
+        if let (Some(true), Some(stop)) = (self.soft_stop, &self.stop_price) {
+            if (long && price <= stop) || (!long && price >= stop) {
+                return Some(DisposeReason::StopOut);
+            }
+        }
+        if let (Some(true), Some(target)) = (self.soft_target, &self.target_price) {
+            if (long && price >= target) || (!long && price <= target) {
+                return Some(DisposeReason::Profit);
+            }
+        }
+        None
+    }
+
+    /// Record that a soft level was crossed and `order` was sent to close the lot. The order then
+    /// stands in for the stop or target leg, and disposes of the lot once it fills.
+    pub fn record_soft_trigger(
+        &mut self,
+        reason: DisposeReason,
+        price: Num,
+        order: &BrokerOrder,
+    ) -> Result<&mut Self, Box<dyn Error>> {
+        self.soft_trigger = Some(reason);
+        self.soft_trigger_price = Some(price);
+        self.soft_triggered_at = Some(Utc::now());
+        match reason {
+            DisposeReason::Profit => self.target_order_id = Some(order.id.clone()),
+            _ => self.stop_order_id = Some(order.id.clone()),
+        }
+        self.update()?;
+        LotEvent::record(
+            self,
+            LotTransition::SoftExitTriggered,
+            self.status,
+            None,
+            serde_json::to_string(order).ok(),
+        )?;
+        Ok(self)
+    }
+
+    /// Exit orders placed on their own rather than as legs of `order`, the opening order.
+    pub fn standalone_exits(&self, order: &BrokerOrder) -> Vec<OrderId> {
+        [&self.stop_order_id, &self.target_order_id]
+            .into_iter()
+            .flatten()
+            .filter(|id| !order.legs.iter().any(|leg| &leg.id == *id))
+            .cloned()
+            .collect()
+    }
+
+    /// Sync the lot with one of its standalone exit orders, disposing of it once the exit fills.
+    pub fn track_exit(&mut self, order: &BrokerOrder) -> Result<&mut Self, Box<dyn Error>> {
+        if order.type_ == OrderType::TrailingStop {
+            return self.track_trailing_stop(order);
+        }
+        let reason = if self.stop_order_id.as_ref() == Some(&order.id) {
+            DisposeReason::StopOut
+        } else if self.target_order_id.as_ref() == Some(&order.id) {
+            DisposeReason::Profit
+        } else {
+            return Ok(self);
+        };
+        let previous_status = self.status;
+        self.dispose_with_exit(order, reason)?;
+        if self.status == Some(LotStatus::Disposed) && previous_status != self.status {
+            self.update()?;
+            LotEvent::record(
+                self,
+                LotTransition::ExitFilled,
+                previous_status,
+                None,
+                serde_json::to_string(order).ok(),
+            )?;
+        }
+        Ok(self)
+    }
+
+    pub fn scales_out(&self) -> bool {
+        self.tranche_count.unwrap_or(0) > 0
+    }
+
+    /// Quantity still held, the filled quantity less what was disposed of so far.
+    pub fn open_qty(&self) -> Option<Num> {
+        let filled_qty = self.filled_qty.as_ref()?;
+        Some(match &self.disposed_qty {
+            Some(disposed_qty) => filled_qty - disposed_qty,
+            None => filled_qty.clone(),
+        })
+    }
+
+    /// Sync one tranche of a lot that scales out with its exit, taking up fills in part as well as
+    /// in full. The lot is disposed of once the last tranche is, at the average price of all of
+    /// them.
+    pub fn track_tranche(
         &mut self,
-        order: &apcaOrder::Order,
-    ) -> Result<&mut Self, turbosql::Error> {
+        tranche: &mut Tranche,
+        order: &BrokerOrder,
+    ) -> Result<&mut Self, Box<dyn Error>> {
+        if !tranche.track(order)? {
+            return Ok(self);
+        }
+        let previous_status = self.status;
+        let tranches = Tranche::for_lot(self.rowid.unwrap_or_default())?;
+        self.sum_tranches(&tranches);
+        if tranches.iter().all(Tranche::is_disposed)
+            && self.transition_to(LotStatus::Disposed).is_ok()
+        {
+            // the reason and time of the lot are those of the tranche that closed it
+            if let Some(last) = tranches.iter().max_by_key(|tranche| tranche.disposed_at) {
+                self.disposed_at = last.disposed_at;
+                self.dispose_reason = last.dispose_reason;
+                self.disposing_order_id = last.disposing_order_id.clone();
+            }
+        }
+        self.update()?;
+        LotEvent::record(
+            self,
+            LotTransition::TrancheDisposed,
+            previous_status,
+            None,
+            serde_json::to_string(order).ok(),
+        )?;
+        Ok(self)
+    }
+
+    /// Take the disposed quantity from the tranches, those disposed of in part included, and their
+    /// average fill price once all of them are out.
+    fn sum_tranches(&mut self, tranches: &[Tranche]) {
+        let mut disposed_qty = Num::from(0);
+        let mut value = Some(Num::from(0));
+        for tranche in tranches
+            .iter()
+            .filter(|tranche| tranche.disposed_qty.is_some())
+        {
+            let qty = tranche.disposed_qty.clone().unwrap_or_default();
+            value = match (value, &tranche.disposed_fill_price) {
+                (Some(value), Some(price)) => Some(value + price * &qty),
+                _ => None,
+            };
+            disposed_qty = &disposed_qty + &qty;
+        }
+        self.disposed_fill_price = match value {
+            Some(value)
+                if tranches.iter().all(Tranche::is_disposed) && disposed_qty > Num::from(0) =>
+            {
+                Some(&value / &disposed_qty)
+            }
+            _ => None,
+        };
+        self.disposed_qty = Some(disposed_qty);
+    }
+
+    pub fn liquidate_with(&mut self, order: &BrokerOrder) -> Result<&mut Self, Box<dyn Error>> {
+        let previous_status = self.status;
+        self.transition_to(LotStatus::Disposed)?;
         self.disposed_at = Some(Utc::now());
-        self.disposing_order_id = Some(order.id);
+        self.disposing_order_id = Some(order.id.clone());
         self.dispose_reason = Some(DisposeReason::Liquidation);
         self.disposed_fill_price = order.average_fill_price.clone();
-        self.status = LotStatus::Disposed.into();
+        // what the tranches have not taken off goes with the liquidation
+        if self.scales_out() {
+            let mut tranches = Tranche::for_lot(self.rowid.unwrap_or_default())?;
+            for tranche in tranches.iter_mut().filter(|tranche| !tranche.is_disposed()) {
+                tranche.dispose_with(DisposeReason::Liquidation, order)?;
+            }
+            self.sum_tranches(&tranches);
+        }
+        self.update()?;
+        LotEvent::record(
+            self,
+            LotTransition::Liquidated,
+            previous_status,
+            None,
+            serde_json::to_string(order).ok(),
+        )?;
+        Ok(self)
+    }
+
+    /// Dispose of `qty` of the lot with a sale of several lots chosen by `selection`. Its exits
+    /// must already be canceled. Selling less than the lot holds splits it, and the open child,
+    /// left without exits, is returned.
+    pub fn sell_with(
+        &mut self,
+        qty: &Num,
+        order: &BrokerOrder,
+        selection: LotSelection,
+    ) -> Result<Option<Lot>, Box<dyn Error>> {
+        self.lot_selection = Some(selection);
+        if self.open_qty().map_or(true, |held| *qty >= held) {
+            self.liquidate_with(order)?;
+            return Ok(None);
+        }
+        let (_, mut open) = self.split(
+            qty,
+            order.average_fill_price.clone(),
+            DisposeReason::Liquidation,
+            order,
+        )?;
+        // the sale is not an exit of the open child, and the exits it inherited are gone
+        open.lot_selection = None;
+        open.split_exit_qty = None;
+        open.split_exit_value = None;
+        open.exit_order_id = None;
+        open.stop_order_id = None;
+        open.target_order_id = None;
+        open.update()?;
+        Ok(Some(open))
+    }
+
+    /// Take the cumulative filled quantity and average price of the opening order. Updates that
+    /// arrive out of order and report less than what was already filled are ignored.
+    pub fn record_fill(
+        &mut self,
+        filled_qty: &Num,
+        avg_price: &Option<Num>,
+        filled_at: Option<DateTime<Utc>>,
+    ) {
+        // the entry of a lot split from another was settled at the split
+        if self.parent_id.is_some() {
+            return;
+        }
+        let filled_qty = &self.restate_qty(filled_qty);
+        let avg_price = &self.restate_price(avg_price);
+        if let Some(current) = &self.filled_qty {
+            if filled_qty < current {
+                tracing::warn!(
+                    "record_fill: lot {:?} ignoring stale fill of {} after {}",
+                    self.client_id,
+                    filled_qty,
+                    current
+                );
+                return;
+            }
+        }
+        if self.opened_at.is_none() {
+            self.opened_at = filled_at;
+        }
+        self.filled_qty = Some(filled_qty.clone());
+        self.qty = Some(filled_qty.clone());
+        self.filled_avg_price = avg_price.clone();
+        self.set_cost_basis(filled_qty, avg_price);
+    }
+
+    /// Derive prices from the executions recorded for the lot. Entry executions only take over from
+    /// the order level averages once they account for the whole filled quantity, so a missed
+    /// stream message does not understate the lot.
+    pub fn apply_executions(&mut self) -> Result<(), turbosql::Error> {
+        let rowid = match self.rowid {
+            Some(rowid) => rowid,
+            None => return Ok(()),
+        };
+
+        let entries = Execution::for_lot(rowid, ExecutionSide::Entry)?;
+        if let Some((qty, avg_price)) = Execution::vwap(&entries) {
+            let qty = self.restate_qty(&qty);
+            let avg_price = self.restate_price(&Some(avg_price)).unwrap_or_default();
+            let covers_fill = match &self.filled_qty {
+                Some(filled_qty) => &qty >= filled_qty,
+                None => true,
+            };
+            if covers_fill {
+                self.filled_qty = Some(qty.clone());
+                self.qty = Some(qty.clone());
+                self.filled_avg_price = Some(avg_price.clone());
+                self.set_cost_basis(&qty, &Some(avg_price));
+            }
+        }
+        let first_execution = entries.iter().filter_map(|e| e.executed_at).min();
+        if first_execution.is_some()
+            && (self.opened_at.is_none() || first_execution < self.opened_at)
+        {
+            self.opened_at = first_execution;
+        }
+
+        let exits = Execution::for_lot(rowid, ExecutionSide::Exit)?;
+        if let Some((_, avg_price)) = Execution::vwap(&exits) {
+            self.disposed_fill_price = Some(avg_price);
+        }
+        Ok(())
+    }
+
+    /// Restate the lot for a stock split. Quantities are multiplied by the ratio and prices
+    /// divided by it, so the cost basis stays the same. The limit, stop and target changes are
+    /// recorded as price adjustments, and the split as a lot event.
+    pub fn apply_split(&mut self, split: &StockSplit) -> Result<&mut Self, Box<dyn Error>> {
+        let ratio = split.ratio().ok_or("split ratio must be > 0")?;
+        let times = |qty: &Option<Num>| qty.as_ref().map(|qty| qty * &ratio);
+        let per = |price: &Option<Num>| price.as_ref().map(|price| price / &ratio);
+
+        self.qty = times(&self.qty);
+        self.ordered_qty = times(&self.ordered_qty);
+        self.filled_qty = times(&self.filled_qty);
+        self.disposed_qty = times(&self.disposed_qty);
+        self.split_exit_qty = times(&self.split_exit_qty);
+        self.filled_avg_price = per(&self.filled_avg_price);
+        self.high_water_mark = per(&self.high_water_mark);
+        self.trail_price = per(&self.trail_price);
+        self.soft_trigger_price = per(&self.soft_trigger_price);
+        let limit_price = per(&self.limit_price);
+        self.adjust_price(PriceField::Limit, limit_price, AdjustmentReason::Split)?;
+        let stop_price = per(&self.stop_price);
+        self.adjust_price(PriceField::Stop, stop_price, AdjustmentReason::Split)?;
+        let target_price = per(&self.target_price);
+        self.adjust_price(PriceField::Target, target_price, AdjustmentReason::Split)?;
+        self.split_ratio = Some(match &self.split_ratio {
+            Some(split_ratio) => split_ratio * &ratio,
+            None => ratio.clone(),
+        });
+
+        if self.scales_out() {
+            for mut tranche in Tranche::for_lot(self.rowid.unwrap_or_default())? {
+                tranche.qty = times(&tranche.qty);
+                tranche.disposed_qty = times(&tranche.disposed_qty);
+                tranche.target_price = per(&tranche.target_price);
+                tranche.disposed_fill_price = per(&tranche.disposed_fill_price);
+                tranche.update()?;
+            }
+        }
+
         self.update()?;
+        LotEvent::record(
+            self,
+            LotTransition::StockSplit,
+            self.status,
+            None,
+            serde_json::to_string(split).ok(),
+        )?;
         Ok(self)
     }
 
+    /// A quantity of the opening order on the lot's current share basis.
+    fn restate_qty(&self, qty: &Num) -> Num {
+        match &self.split_ratio {
+            Some(ratio) => qty * ratio,
+            None => qty.clone(),
+        }
+    }
+
+    /// A price of the opening order on the lot's current share basis.
+    fn restate_price(&self, price: &Option<Num>) -> Option<Num> {
+        match &self.split_ratio {
+            Some(ratio) => price.as_ref().map(|price| price / ratio),
+            None => price.clone(),
+        }
+    }
+
+    /// Whether the lot held its shares at `at`, from the first fill until it was disposed of. A
+    /// lot that was split holds nothing itself, its children do.
+    pub fn held_at(&self, at: DateTime<Utc>) -> bool {
+        if !matches!(
+            self.status,
+            Some(LotStatus::Open) | Some(LotStatus::Disposed)
+        ) {
+            return false;
+        }
+        let opened = self.opened_at.map_or(false, |opened_at| opened_at <= at);
+        opened
+            && self
+                .disposed_at
+                .map_or(true, |disposed_at| disposed_at > at)
+    }
+
+    /// Shares the lot held at `at`, the filled quantity less the tranches disposed of by then.
+    /// Part of a tranche filled while the rest is still working carries no time, so it counts as
+    /// gone.
+    pub fn qty_held_at(&self, at: DateTime<Utc>) -> Result<Num, turbosql::Error> {
+        if !self.held_at(at) {
+            return Ok(Num::from(0));
+        }
+        let mut qty = self
+            .filled_qty
+            .clone()
+            .or_else(|| self.qty.clone())
+            .unwrap_or_default();
+        if self.scales_out() {
+            for tranche in Tranche::for_lot(self.rowid.unwrap_or_default())? {
+                let gone = tranche
+                    .disposed_at
+                    .map_or(true, |disposed_at| disposed_at <= at);
+                if let (true, Some(disposed_qty)) = (gone, &tranche.disposed_qty) {
+                    qty = &qty - disposed_qty;
+                }
+            }
+        }
+        Ok(qty)
+    }
+
+    /// Time held, from the first entry fill through disposal, or `now` for open lots.
+    pub fn holding_period(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let opened_at = self.opened_at?;
+        Some(self.disposed_at.unwrap_or(now) - opened_at)
+    }
+
+    /// First day on which the lot counts as held for more than one year, the day after the
+    /// anniversary of the first fill.
+    pub fn long_term_date(&self) -> Option<NaiveDate> {
+        let opened = self.opened_at?.naive_utc().date();
+        let anniversary = opened
+            .with_year(opened.year() + 1)
+            .or_else(|| NaiveDate::from_ymd_opt(opened.year() + 1, 2, 28))?;
+        anniversary.succ_opt()
+    }
+
+    pub fn holding_term(&self, now: DateTime<Utc>) -> Option<HoldingTerm> {
+        let long_term_date = self.long_term_date()?;
+        let held_through = self.disposed_at.unwrap_or(now).naive_utc().date();
+        if held_through >= long_term_date {
+            Some(HoldingTerm::LongTerm)
+        } else {
+            Some(HoldingTerm::ShortTerm)
+        }
+    }
+
+    /// Proceeds, gain and slippage for a disposed lot, None while the lot is still live.
+    pub fn realized(&self) -> Option<RealizedGain> {
+        if self.status != Some(LotStatus::Disposed) {
+            return None;
+        }
+        let position_type = self.position_type.unwrap_or_default();
+        let exit_price = self.disposed_fill_price.as_ref()?;
+        let cost_basis = self.cost_basis.as_ref()?;
+        let proceeds = self.proceeds()?;
+        // for a short the cost basis is what the sale brought in, and the proceeds are what it
+        // took to cover
+        let gain = match position_type {
+            PositionType::Long => &proceeds - cost_basis,
+            PositionType::Short => cost_basis - &proceeds,
+        };
+
+        let entry_slippage = match (&self.limit_price, &self.filled_avg_price) {
+            (Some(limit), Some(fill)) => Some(slippage(position_type.entry_side(), limit, fill)),
+            _ => None,
+        };
+        let expected_exit = match self.dispose_reason {
+            Some(DisposeReason::StopOut) => self.stop_price.as_ref(),
+            // each tranche of a lot that scales out has a target of its own
+            Some(DisposeReason::Profit) if !self.scales_out() => self.target_price.as_ref(),
+            _ => None,
+        };
+        let exit_slippage =
+            expected_exit.map(|expected| slippage(position_type.exit_side(), expected, exit_price));
+        let total_return = match &self.cash_income {
+            Some(income) => &gain + income,
+            None => gain.clone(),
+        };
+        let mut tax_gain = gain.clone();
+        if let Some(disallowed) = &self.wash_disallowed_loss {
+            tax_gain = &tax_gain + disallowed;
+        }
+        if let Some(adjustment) = &self.wash_basis_adjustment {
+            tax_gain = &tax_gain - adjustment;
+        }
+
+        Some(RealizedGain {
+            gain_pct: percent_of(&gain, cost_basis),
+            entry_slippage_pct: entry_slippage
+                .as_ref()
+                .and_then(|slip| percent_of(slip, self.limit_price.as_ref()?)),
+            exit_slippage_pct: exit_slippage
+                .as_ref()
+                .and_then(|slip| percent_of(slip, expected_exit?)),
+            total_return_pct: percent_of(&total_return, cost_basis),
+            proceeds,
+            gain,
+            entry_slippage,
+            exit_slippage,
+            total_return,
+            tax_gain,
+        })
+    }
+
+    pub fn fill_progress(&self) -> Option<FillProgress> {
+        let ordered_qty = self.ordered_qty.clone()?;
+        let filled_qty = self.filled_qty.clone().unwrap_or_default();
+        Some(FillProgress {
+            remaining_qty: &ordered_qty - &filled_qty,
+            filled_qty,
+            ordered_qty,
+        })
+    }
+
+    /// Fill price × quantity, plus entry fees for a long and less them for a short, where the
+    /// cost basis is what the sale brought in.
     pub fn set_cost_basis(&mut self, qty: &Num, fill_price: &Option<Num>) {
         self.cost_basis = if let Some(price) = fill_price {
-            Some(price * qty)
+            let gross = price * qty;
+            Some(
+                match (&self.entry_fees, self.position_type.unwrap_or_default()) {
+                    (None, _) => gross,
+                    (Some(fees), PositionType::Long) => gross + fees,
+                    (Some(fees), PositionType::Short) => gross - fees,
+                },
+            )
         } else {
             None
         };
     }
 
-    pub fn set_status_from(&mut self, status: &apcaOrder::Status) {
-        self.broker_status = Some(status.clone());
-        self.status = match status {
-            apcaOrder::Status::New
-            | apcaOrder::Status::PendingNew
-            | apcaOrder::Status::Accepted => Some(LotStatus::Pending),
-            apcaOrder::Status::PartiallyFilled | apcaOrder::Status::Filled => Some(LotStatus::Open),
-            apcaOrder::Status::Canceled
-            | apcaOrder::Status::Rejected
-            | apcaOrder::Status::Expired => Some(LotStatus::Canceled),
-            _ => Some(LotStatus::Other), // this should never happen so going to flag these for
-                                         // manual followup
+    /// Value of the disposal net of exit fees: what a sale brought in, or what it took to cover.
+    pub fn proceeds(&self) -> Option<Num> {
+        let gross = self.disposed_fill_price.as_ref()? * self.qty.as_ref()?;
+        Some(
+            match (&self.exit_fees, self.position_type.unwrap_or_default()) {
+                (None, _) => gross,
+                (Some(fees), PositionType::Long) => gross - fees,
+                (Some(fees), PositionType::Short) => gross + fees,
+            },
+        )
+    }
+
+    /// Total up the fees recorded for the lot and fold them into the cost basis.
+    pub fn apply_fees(&mut self) -> Result<(), turbosql::Error> {
+        if self.rowid.is_none() {
+            return Ok(());
+        }
+        let fees = self.allocated_fees()?;
+        let total = |side| {
+            let side_fees: Vec<Fee> = fees
+                .iter()
+                .filter(|fee| fee.side == Some(side))
+                .cloned()
+                .collect();
+            if side_fees.is_empty() {
+                None
+            } else {
+                Some(FeeTotals::of(&side_fees).total)
+            }
+        };
+        self.entry_fees = total(ExecutionSide::Entry);
+        self.exit_fees = total(ExecutionSide::Exit);
+        if let Some(qty) = self.filled_qty.clone() {
+            let price = self.filled_avg_price.clone();
+            self.set_cost_basis(&qty, &price);
+        }
+        Ok(())
+    }
+
+    /// Total up the cash events recorded for the lot into its cash income.
+    pub fn apply_cash_events(&mut self) -> Result<(), turbosql::Error> {
+        let events = self.allocated_cash_events()?;
+        self.cash_income = if events.is_empty() {
+            None
+        } else {
+            Some(CashTotals::of(&events).total)
+        };
+        Ok(())
+    }
+
+    /// Move the lot to `next` if the lifecycle allows it. Illegal transitions leave the lot as is.
+    pub fn transition_to(&mut self, next: LotStatus) -> Result<(), IllegalTransition> {
+        let allowed = match self.status {
+            Some(status) => status.can_transition_to(next),
+            None => true,
+        };
+        if !allowed {
+            let err = IllegalTransition {
+                client_id: self.client_id.clone(),
+                from: self.status,
+                to: next,
+            };
+            tracing::warn!("transition_to: rejected, {}", err);
+            return Err(err);
         }
+        self.status = Some(next);
+        Ok(())
+    }
+
+    pub fn set_status_from(&mut self, status: &OrderStatus) -> Result<(), IllegalTransition> {
+        let next = match status {
+            OrderStatus::New | OrderStatus::PendingNew | OrderStatus::Accepted => {
+                LotStatus::Pending
+            }
+            OrderStatus::PartiallyFilled | OrderStatus::Filled => LotStatus::Open,
+            OrderStatus::Canceled | OrderStatus::Rejected | OrderStatus::Expired => {
+                LotStatus::Canceled
+            }
+            _ => LotStatus::Other, // this should never happen so going to flag these for
+                                   // manual followup
+        };
+        self.transition_to(next)?;
+        self.broker_status = Some(*status);
+        Ok(())
     }
 
     pub fn get_lots(
@@ -305,6 +1668,20 @@ impl Lot {
         };
         Ok(lots)
     }
+
+    /// All open lots, optionally limited to one bucket.
+    pub fn get_open_lots(bucket_id: Option<i64>) -> Result<Vec<Lot>, Box<dyn Error>> {
+        let lots = match bucket_id {
+            Some(bucket_id) => select!(
+                Vec<Lot>
+                "WHERE bucket_id = ? AND status = ? ORDER BY created_at DESC",
+                bucket_id,
+                LotStatus::Open
+            )?,
+            None => select!(Vec<Lot> "WHERE status = ? ORDER BY created_at DESC", LotStatus::Open)?,
+        };
+        Ok(lots)
+    }
 }
 
 #[cfg(test)]
@@ -329,65 +1706,71 @@ fn create_lot() -> Lot {
 }
 
 #[cfg(test)]
-fn apca_order() -> apcaOrder::Order {
-    apcaOrder::Order {
-        id: apcaOrder::Id(Uuid::new_v4()),
+fn broker_order() -> BrokerOrder {
+    BrokerOrder {
+        id: OrderId(Uuid::new_v4().to_string()),
         client_order_id: "c4390a00-cc88-4979-840c-7feeb08278c5".to_string(),
-        status: apcaOrder::Status::Filled,
-        created_at: chrono::Utc::now(),
-        updated_at: Some(chrono::Utc::now()),
-        submitted_at: Some(chrono::Utc::now()),
-        filled_at: Some(chrono::Utc::now()),
-        expired_at: None,
-        canceled_at: None,
-        asset_class: apca::api::v2::asset::Class::UsEquity,
-        asset_id: apca::api::v2::asset::Id(Uuid::new_v4()),
-        symbol: "TEST".to_string(),
-        amount: apcaOrder::Amount::quantity(100),
+        status: OrderStatus::Filled,
+        sym: "TEST".to_string(),
+        side: crate::broker::Side::Buy,
+        type_: OrderType::Limit,
+        class: crate::broker::OrderClass::Bracket,
+        qty: Some(Num::from(100)),
         filled_quantity: Num::from(100),
-        type_: apcaOrder::Type::Limit,
-        class: apcaOrder::Class::Bracket,
-        side: apcaOrder::Side::Buy,
-        time_in_force: apcaOrder::TimeInForce::UntilCanceled,
-        stop_price: Some(Num::from(0)),
+        average_fill_price: Some(Num::from(101)),
         limit_price: Some(Num::from(0)),
+        stop_price: Some(Num::from(0)),
         trail_price: None,
         trail_percent: None,
-        average_fill_price: Some(Num::from(101)),
-        extended_hours: false,
+        high_water_mark: None,
+        created_at: chrono::Utc::now(),
+        filled_at: Some(chrono::Utc::now()),
         legs: vec![],
     }
 }
 
 #[cfg(test)]
-fn apca_bracket_order() -> apcaOrder::Order {
-    let mut order = apca_order();
+fn broker_bracket_order() -> BrokerOrder {
+    let mut order = broker_order();
 
-    let mut stop = apca_order();
-    stop.side = apcaOrder::Side::Sell;
-    stop.type_ = apcaOrder::Type::Stop;
+    let mut stop = broker_order();
+    stop.side = crate::broker::Side::Sell;
+    stop.type_ = OrderType::Stop;
     stop.filled_quantity = Num::from(0);
     stop.filled_at = None;
     stop.stop_price = Some(Num::from(99));
-    stop.status = apcaOrder::Status::Held;
+    stop.status = OrderStatus::Held;
 
-    let mut limit = apca_order();
-    limit.side = apcaOrder::Side::Sell;
-    limit.type_ = apcaOrder::Type::Limit;
+    let mut limit = broker_order();
+    limit.side = crate::broker::Side::Sell;
+    limit.type_ = OrderType::Limit;
     limit.filled_quantity = Num::from(0);
     limit.filled_at = None;
     limit.limit_price = Some(Num::from(103));
-    limit.status = apcaOrder::Status::New;
+    limit.status = OrderStatus::New;
 
     order.legs = vec![limit, stop];
     order
 }
 
+#[cfg(test)]
+fn short_bracket_order() -> BrokerOrder {
+    let mut order = broker_bracket_order();
+    order.side = crate::broker::Side::Sell;
+    order.limit_price = Some(Num::from(101));
+    for leg in order.legs.iter_mut() {
+        leg.side = crate::broker::Side::Buy;
+    }
+    order.legs[0].limit_price = Some(Num::from(99));
+    order.legs[1].stop_price = Some(Num::from(103));
+    order
+}
+
 #[test]
 fn test_fill_with() {
     setup();
 
-    let order = apca_bracket_order();
+    let order = broker_bracket_order();
 
     let mut lot = create_lot();
 
@@ -404,9 +1787,9 @@ fn test_fill_with() {
 fn test_fill_with_when_bracket_order_stopped_out() {
     let stopped_out_at = chrono::Utc::now();
 
-    let mut order = apca_bracket_order();
-    order.legs[0].status = apcaOrder::Status::Replaced;
-    order.legs[1].status = apcaOrder::Status::Filled;
+    let mut order = broker_bracket_order();
+    order.legs[0].status = OrderStatus::Replaced;
+    order.legs[1].status = OrderStatus::Filled;
     order.legs[1].filled_quantity = Num::from(100);
     order.legs[1].filled_at = Some(stopped_out_at);
 
@@ -422,16 +1805,16 @@ fn test_fill_with_when_bracket_order_stopped_out() {
         stop_leg.average_fill_price.as_ref()
     );
     assert_eq!(lot.dispose_reason, Some(DisposeReason::StopOut));
-    assert_eq!(lot.disposing_order_id, Some(stop_leg.id));
+    assert_eq!(lot.disposing_order_id.as_ref(), Some(&stop_leg.id));
 }
 
 #[test]
 fn test_fill_with_when_bracket_order_target_hit() {
     let closed_at = chrono::Utc::now();
 
-    let mut order = apca_bracket_order();
-    order.legs[1].status = apcaOrder::Status::Replaced;
-    order.legs[0].status = apcaOrder::Status::Filled;
+    let mut order = broker_bracket_order();
+    order.legs[1].status = OrderStatus::Replaced;
+    order.legs[0].status = OrderStatus::Filled;
     order.legs[0].filled_quantity = Num::from(100);
     order.legs[0].filled_at = Some(closed_at);
 
@@ -447,5 +1830,303 @@ fn test_fill_with_when_bracket_order_target_hit() {
         target_leg.average_fill_price.as_ref()
     );
     assert_eq!(lot.dispose_reason, Some(DisposeReason::Profit));
-    assert_eq!(lot.disposing_order_id, Some(target_leg.id));
+    assert_eq!(lot.disposing_order_id.as_ref(), Some(&target_leg.id));
+}
+
+#[test]
+fn test_lot_status_transitions() {
+    assert!(LotStatus::Pending.can_transition_to(LotStatus::Open));
+    assert!(LotStatus::Pending.can_transition_to(LotStatus::Canceled));
+    assert!(LotStatus::Open.can_transition_to(LotStatus::Disposed));
+    assert!(LotStatus::Other.can_transition_to(LotStatus::Open));
+    assert!(LotStatus::Open.can_transition_to(LotStatus::Open));
+    assert!(!LotStatus::Disposed.can_transition_to(LotStatus::Open));
+    assert!(!LotStatus::Canceled.can_transition_to(LotStatus::Open));
+    assert!(!LotStatus::Open.can_transition_to(LotStatus::Pending));
+    assert!(!LotStatus::Open.can_transition_to(LotStatus::Canceled));
+}
+
+#[test]
+fn test_fill_with_does_not_reopen_disposed_lot() {
+    let mut lot = create_lot();
+    lot.status = Some(LotStatus::Disposed);
+    lot.update().unwrap();
+
+    let mut order = broker_order();
+    order.status = OrderStatus::PartiallyFilled;
+    lot.fill_with(&order).unwrap();
+
+    let lot = Lot::get(lot.rowid.unwrap()).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Disposed));
+    assert_eq!(lot.open_order_id, None);
+    assert_eq!(lot.filled_avg_price, None);
+}
+
+#[test]
+fn test_holding_term() {
+    let opened_at = "2022-01-15T15:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let mut lot = Lot {
+        status: Some(LotStatus::Open),
+        opened_at: Some(opened_at),
+        ..Default::default()
+    };
+
+    let now = "2022-12-16T15:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let stats = LotWithStats::new(lot.clone(), now);
+    assert_eq!(stats.holding_days, Some(335));
+    assert_eq!(stats.holding_term, Some(HoldingTerm::ShortTerm));
+    assert_eq!(stats.days_to_long_term, Some(31));
+
+    // exactly one year is still short-term
+    lot.status = Some(LotStatus::Disposed);
+    lot.disposed_at = Some("2023-01-15T20:00:00Z".parse::<DateTime<Utc>>().unwrap());
+    assert_eq!(lot.holding_term(now), Some(HoldingTerm::ShortTerm));
+
+    lot.disposed_at = Some("2023-01-16T15:00:00Z".parse::<DateTime<Utc>>().unwrap());
+    let stats = LotWithStats::new(lot, now);
+    assert_eq!(stats.holding_term, Some(HoldingTerm::LongTerm));
+    assert_eq!(stats.days_to_long_term, None);
+}
+
+#[test]
+fn test_realized_gain_long_stop_out() {
+    let lot = Lot {
+        status: Some(LotStatus::Disposed),
+        position_type: Some(PositionType::Long),
+        qty: Some(Num::from(100)),
+        limit_price: Some(Num::from(10)),
+        filled_avg_price: Some(Num::new(1010, 100)),
+        cost_basis: Some(Num::from(1010)),
+        stop_price: Some(Num::from(9)),
+        disposed_fill_price: Some(Num::new(890, 100)),
+        dispose_reason: Some(DisposeReason::StopOut),
+        ..Default::default()
+    };
+    let realized = lot.realized().unwrap();
+    assert_eq!(realized.proceeds, Num::from(890));
+    assert_eq!(realized.gain, Num::from(-120));
+    assert_eq!(realized.entry_slippage, Some(Num::new(10, 100)));
+    assert_eq!(realized.entry_slippage_pct, Some(Num::from(1)));
+    assert_eq!(realized.exit_slippage, Some(Num::new(10, 100)));
+}
+
+#[test]
+fn test_realized_gain_short_target_hit() {
+    let lot = Lot {
+        status: Some(LotStatus::Disposed),
+        position_type: Some(PositionType::Short),
+        qty: Some(Num::from(100)),
+        filled_avg_price: Some(Num::from(50)),
+        cost_basis: Some(Num::from(5000)),
+        target_price: Some(Num::from(40)),
+        disposed_fill_price: Some(Num::new(3990, 100)),
+        dispose_reason: Some(DisposeReason::Profit),
+        ..Default::default()
+    };
+    let realized = lot.realized().unwrap();
+    assert_eq!(realized.proceeds, Num::from(3990));
+    assert_eq!(realized.gain, Num::from(1010));
+    assert_eq!(realized.gain_pct, Some(Num::new(202, 10)));
+    assert_eq!(realized.entry_slippage, None);
+    // covered below the target, which is better than asked for
+    assert_eq!(realized.exit_slippage, Some(Num::new(-10, 100)));
+}
+
+#[test]
+fn test_lot_mark() {
+    let long = Lot {
+        status: Some(LotStatus::Open),
+        position_type: Some(PositionType::Long),
+        qty: Some(Num::from(100)),
+        filled_qty: Some(Num::from(100)),
+        cost_basis: Some(Num::from(1000)),
+        stop_price: Some(Num::from(9)),
+        target_price: Some(Num::from(12)),
+        ..Default::default()
+    };
+    let mark = LotMark::new(long, Some(Num::from(11)));
+    assert_eq!(mark.market_value, Some(Num::from(1100)));
+    assert_eq!(mark.unrealized_gain, Some(Num::from(100)));
+    assert_eq!(mark.unrealized_gain_pct, Some(Num::from(10)));
+    assert_eq!(mark.stop_distance, Some(Num::from(2)));
+    assert_eq!(mark.target_distance, Some(Num::from(1)));
+
+    // 40 of the 100 were sold, the 60 left are marked against their share of the cost
+    let partly_sold = Lot {
+        disposed_qty: Some(Num::from(40)),
+        ..mark.lot
+    };
+    let mark = LotMark::new(partly_sold, Some(Num::from(11)));
+    assert_eq!(mark.market_value, Some(Num::from(660)));
+    assert_eq!(mark.unrealized_gain, Some(Num::from(60)));
+    assert_eq!(mark.unrealized_gain_pct, Some(Num::from(10)));
+
+    let short = Lot {
+        status: Some(LotStatus::Open),
+        position_type: Some(PositionType::Short),
+        qty: Some(Num::from(100)),
+        filled_qty: Some(Num::from(100)),
+        cost_basis: Some(Num::from(1000)),
+        stop_price: Some(Num::from(12)),
+        target_price: Some(Num::from(8)),
+        ..Default::default()
+    };
+    let mark = LotMark::new(short, Some(Num::from(11)));
+    assert_eq!(mark.unrealized_gain, Some(Num::from(-100)));
+    assert_eq!(mark.stop_distance, Some(Num::from(1)));
+    assert_eq!(mark.target_distance, Some(Num::from(3)));
+
+    let unpriced = LotMark::new(Lot::default(), None);
+    assert_eq!(unpriced.market_value, None);
+    assert_eq!(unpriced.unrealized_gain, None);
+}
+
+#[test]
+fn test_fill_with_short_stop_out() {
+    setup();
+
+    let mut lot = create_lot();
+    lot.position_type = Some(PositionType::Short);
+    lot.update().unwrap();
+
+    let mut order = short_bracket_order();
+    lot.fill_with(&order).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Open));
+    assert_eq!(lot.stop_order_id, Some(order.legs[1].id.clone()));
+
+    order.legs[1].status = OrderStatus::Filled;
+    order.legs[1].filled_quantity = Num::from(100);
+    order.legs[1].average_fill_price = Some(Num::from(103));
+    order.legs[1].filled_at = Some(chrono::Utc::now());
+    order.legs[0].status = OrderStatus::Canceled;
+    lot.fill_with(&order).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Disposed));
+    assert_eq!(lot.dispose_reason, Some(DisposeReason::StopOut));
+    assert_eq!(lot.disposed_fill_price, Some(Num::from(103)));
+    assert_eq!(lot.disposing_order_id, Some(order.legs[1].id.clone()));
+}
+
+#[test]
+fn test_detect_disposal_ignores_entry_side_legs() {
+    setup();
+
+    // sell side legs belong to a long, they cannot close a short
+    let mut lot = create_lot();
+    lot.position_type = Some(PositionType::Short);
+    lot.status = Some(LotStatus::Open);
+    let mut order = broker_bracket_order();
+    order.legs[0].status = OrderStatus::Filled;
+    lot.detect_disposal(
+        &order.legs,
+        OrderType::Limit,
+        DisposeReason::Profit,
+        |_, _| {},
+    )
+    .unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Open));
+}
+
+#[test]
+fn test_trailing_stop_level() {
+    let mut lot = Lot {
+        position_type: Some(PositionType::Long),
+        stop_price: Some(Num::from(90)),
+        high_water_mark: Some(Num::from(110)),
+        trail_price: Some(Num::from(5)),
+        ..Default::default()
+    };
+    assert_eq!(lot.trailing_stop_level(), Some(Num::from(105)));
+
+    lot.trail_price = None;
+    lot.trail_percent = Some(Num::from(10));
+    assert_eq!(lot.trailing_stop_level(), Some(Num::from(99)));
+
+    lot.position_type = Some(PositionType::Short);
+    lot.high_water_mark = Some(Num::from(80));
+    assert_eq!(lot.trailing_stop_level(), Some(Num::from(88)));
+
+    lot.trail_percent = None;
+    assert_eq!(lot.trailing_stop_level(), None);
+}
+
+#[test]
+fn test_soft_exit_crossed() {
+    let mut lot = Lot {
+        status: Some(LotStatus::Open),
+        position_type: Some(PositionType::Long),
+        stop_price: Some(Num::from(9)),
+        target_price: Some(Num::from(12)),
+        soft_stop: Some(true),
+        ..Default::default()
+    };
+    assert_eq!(lot.soft_exit_crossed(&Num::from(10)), None);
+    assert_eq!(
+        lot.soft_exit_crossed(&Num::from(9)),
+        Some(DisposeReason::StopOut)
+    );
+    // the target rests on the broker
+    assert_eq!(lot.soft_exit_crossed(&Num::from(13)), None);
+
+    lot.soft_target = Some(true);
+    assert_eq!(
+        lot.soft_exit_crossed(&Num::from(13)),
+        Some(DisposeReason::Profit)
+    );
+
+    lot.position_type = Some(PositionType::Short);
+    lot.stop_price = Some(Num::from(12));
+    lot.target_price = Some(Num::from(9));
+    assert_eq!(
+        lot.soft_exit_crossed(&Num::from(12)),
+        Some(DisposeReason::StopOut)
+    );
+    assert_eq!(
+        lot.soft_exit_crossed(&Num::from(8)),
+        Some(DisposeReason::Profit)
+    );
+
+    lot.soft_triggered_at = Some(Utc::now());
+    assert_eq!(lot.soft_exit_crossed(&Num::from(12)), None);
+}
+
+#[test]
+fn test_partial_exit_splits_lot() {
+    setup();
+
+    let mut lot = create_lot();
+    let mut order = broker_bracket_order();
+    lot.fill_with(&order).unwrap();
+    assert_eq!(lot.cost_basis, Some(Num::from(10100)));
+
+    order.legs[0].status = OrderStatus::PartiallyFilled;
+    order.legs[0].filled_quantity = Num::from(40);
+    order.legs[0].average_fill_price = Some(Num::from(103));
+    lot.fill_with(&order).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Split));
+
+    let children = lot.children().unwrap();
+    assert_eq!(children.len(), 2);
+    let (disposed, mut open) = (children[0].clone(), children[1].clone());
+    assert_eq!(disposed.parent_id, lot.rowid);
+    assert_eq!(disposed.status, Some(LotStatus::Disposed));
+    assert_eq!(disposed.qty, Some(Num::from(40)));
+    assert_eq!(disposed.cost_basis, Some(Num::from(4040)));
+    assert_eq!(disposed.disposed_fill_price, Some(Num::from(103)));
+    assert_eq!(disposed.dispose_reason, Some(DisposeReason::Profit));
+    assert_eq!(disposed.realized().unwrap().gain, Num::from(80));
+    assert_eq!(open.parent_id, lot.rowid);
+    assert_eq!(open.status, Some(LotStatus::Open));
+    assert_eq!(open.qty, Some(Num::from(60)));
+    assert_eq!(open.cost_basis, Some(Num::from(6060)));
+    assert_eq!(open.target_order_id.as_ref(), Some(&order.legs[0].id));
+
+    // the rest fills, 60 more at 104 for an average of 103.6
+    order.legs[0].status = OrderStatus::Filled;
+    order.legs[0].filled_quantity = Num::from(100);
+    order.legs[0].average_fill_price = Some(Num::new(1036, 10));
+    open.fill_with(&order).unwrap();
+    assert_eq!(open.status, Some(LotStatus::Disposed));
+    assert_eq!(open.qty, Some(Num::from(60)));
+    assert_eq!(open.disposed_fill_price, Some(Num::from(104)));
+    assert_eq!(open.realized().unwrap().gain, Num::from(180));
 }
@@ -0,0 +1,95 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use turbosql::{select, Turbosql};
+
+use crate::lot::{Lot, LotStatus};
+use crate::sync_lots::LotUpdateEvent;
+
+/// What caused a lot to change.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum LotTransition {
+    /// The lot was created and is about to be sent to the broker.
+    Created,
+    /// The lot was synced with its opening order from the broker.
+    Filled,
+    /// A message on the trade updates stream changed the lot.
+    TradeUpdate,
+    /// The lot was manually closed out.
+    Liquidated,
+    /// The stop or target leg was replaced with a new price.
+    BracketModified,
+    /// The trailing stop was placed, moved or hit.
+    TrailingStop,
+    /// An exit order placed on its own, outside a bracket, was filled.
+    ExitFilled,
+    /// The price crossed a soft stop or target, and zoocarp sent the exit.
+    SoftExitTriggered,
+    /// One tranche of a lot that scales out was sold or covered.
+    TrancheDisposed,
+    /// A partial exit split the lot into a disposed and an open lot.
+    Split,
+    /// The lot was restated for a stock split or reverse split.
+    StockSplit,
+    /// A loss on the lot was disallowed as a wash sale, or the lot took on a disallowed loss as
+    /// the replacement.
+    WashSale,
+    /// The broker refused the opening order when it was placed.
+    Rejected,
+    /// startup_sync could not find the order on the broker and canceled the lot.
+    SyncCanceled,
+}
+
+/// One entry in the append-only history of a lot. Rows are never updated.
+#[derive(Debug, Serialize, Turbosql, Default, Clone)]
+pub struct LotEvent {
+    /// DB row ID
+    pub rowid: Option<i64>,
+    /// Row ID of the lot
+    pub lot_id: Option<i64>,
+    /// Local ID of the lot
+    pub client_id: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub transition: Option<LotTransition>,
+    /// Lot status before the change
+    pub previous_status: Option<LotStatus>,
+    /// Lot status after the change
+    pub status: Option<LotStatus>,
+    /// The trade update event which triggered the change, if any
+    pub event: Option<LotUpdateEvent>,
+    /// The broker message or order JSON as received
+    pub payload: Option<String>,
+}
+
+impl LotEvent {
+    pub fn record(
+        lot: &Lot,
+        transition: LotTransition,
+        previous_status: Option<LotStatus>,
+        event: Option<LotUpdateEvent>,
+        payload: Option<String>,
+    ) -> Result<i64, turbosql::Error> {
+        tracing::debug!(
+            "lot_event: {:?} {:?} {:?} => {:?}",
+            lot.client_id,
+            transition,
+            previous_status,
+            lot.status
+        );
+        Self {
+            lot_id: lot.rowid,
+            client_id: lot.client_id.clone(),
+            created_at: Some(Utc::now()),
+            transition: Some(transition),
+            previous_status,
+            status: lot.status,
+            event,
+            payload,
+            ..Default::default()
+        }
+        .insert()
+    }
+
+    pub fn history(client_id: &str) -> Result<Vec<Self>, turbosql::Error> {
+        select!(Vec<LotEvent> "WHERE client_id = ? ORDER BY rowid", client_id)
+    }
+}
@@ -10,25 +10,41 @@ use serde::Deserialize;
 use serde_json::json;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tower_http::cors::CorsLayer;
 
-use apca::api::v2::{order, positions};
-use apca::data::v2::{last_quote, last_trade};
-// use apca::data::v2::Feed::IEX;
-use apca::ApiInfo;
-use apca::Client;
 use num_decimal::Num;
 // use apca::Error;
 
 use dotenvy::dotenv;
 
+use zoocarp::broker::alpaca::AlpacaBroker;
+use zoocarp::broker::simulator::{load_ticks, SimulatedBroker};
+use zoocarp::broker::{
+    Broker, BrokerError, OrderClass, OrderId, OrderRequest, OrderType, ReplaceRequest,
+};
 use zoocarp::bucket::Bucket;
-use zoocarp::lot::{self, Lot, LotStatus};
-use zoocarp::sync_lots::{startup_sync, LotUpdateEvent, LotUpdateNotice};
-use zoocarp::trade_update_client::{listen_for_trade_updates, ChannelDrain, ChannelSink};
+use zoocarp::cash_event::{self, CashEvent, CashEventKind, CashTotals};
+use zoocarp::corporate_action::{load_splits, StockSplit};
+use zoocarp::execution::ExecutionSide;
+use zoocarp::exit_order::{
+    cancel_exits, move_tranche_stops, place_entry_exits, place_exit, place_oco_exit,
+    place_trailing_stop,
+};
+use zoocarp::fee::{Fee, FeeKind, FeeTotals};
+use zoocarp::lot::{self, Lot, LotDetail, LotMark, LotStatus, LotWithStats};
+use zoocarp::lot_event::LotEvent;
+use zoocarp::price_adjustment::{AdjustmentReason, PriceField};
+use zoocarp::soft_exit;
+use zoocarp::sync_lots::{apply_trade_update, startup_sync, LotUpdateEvent, LotUpdateNotice};
+use zoocarp::tax_lot::{self, select_lots, LotSelection};
+use zoocarp::trade_update_client::{ChannelDrain, ChannelSink};
+use zoocarp::tranche::Tranche;
+use zoocarp::wash_sale::{self, WashSale};
 
 #[derive(Clone)]
 struct State {
+    broker: Arc<dyn Broker>,
     lot_update_sink: ChannelSink,
     lot_update_drain: ChannelDrain,
 }
@@ -39,19 +55,95 @@ async fn main() {
     // initialize tracing, RUST_LOG=debug
     tracing_subscriber::fmt::init();
 
+    // ZOOCARP_BROKER=simulator trades against a local matching engine, replaying the ticks in
+    // ZOOCARP_SIM_FEED if given
+    let broker: Arc<dyn Broker> = match std::env::var("ZOOCARP_BROKER").as_deref() {
+        Ok("simulator") => {
+            let simulator = Arc::new(SimulatedBroker::new());
+            if let Ok(path) = std::env::var("ZOOCARP_SIM_FEED") {
+                let ticks = load_ticks(&path).unwrap();
+                let replayer = simulator.clone();
+                tokio::spawn(async move { replayer.replay(ticks, 1.0).await });
+            }
+            simulator as Arc<dyn Broker>
+        }
+        _ => Arc::new(AlpacaBroker::from_env()),
+    };
+
+    // stock splits listed in ZOOCARP_SPLITS, one per line, restate the lots they affect. Those
+    // already applied are skipped
+    if let Ok(path) = std::env::var("ZOOCARP_SPLITS") {
+        for split in load_splits(&path).unwrap() {
+            if let Err(e) = split.apply() {
+                tracing::warn!("stock split not applied: {}", e);
+            }
+        }
+    }
+
     // create mpsc unbounded channel for trade updates with LotUpdateNotice
     let (update_tx, update_rx) = async_channel::unbounded();
 
     // spawn thread to poll startup_sync every 15 minutes
+    let sync_broker = broker.clone();
     tokio::spawn(async move {
         loop {
-            startup_sync().await.unwrap();
+            if let Err(e) = startup_sync(sync_broker.as_ref()).await {
+                tracing::error!("error syncing lots: {}", e);
+            }
+            if let Err(e) = cash_event::import_from(sync_broker.as_ref()).await {
+                tracing::error!("error importing cash activities: {}", e);
+            }
+            if let Err(e) = wash_sale::scan() {
+                tracing::error!("error checking for wash sales: {}", e);
+            }
             tokio::time::sleep(std::time::Duration::from_secs(900)).await;
         }
     });
 
-    // Subscribe to trade_updates
-    listen_for_trade_updates(update_tx.clone()).await.unwrap();
+    // watch soft stops and targets, which are not on the broker
+    tokio::spawn(soft_exit::watch(broker.clone(), update_tx.clone()));
+
+    // Subscribe to trade_updates, and apply each one to its lot
+    let (trade_update_tx, trade_update_rx) = async_channel::unbounded();
+    broker.stream_updates(trade_update_tx).await.unwrap();
+    let notice_tx = update_tx.clone();
+    let exit_broker = broker.clone();
+    tokio::spawn(async move {
+        while let Ok(update) = trade_update_rx.recv().await {
+            match apply_trade_update(update) {
+                Ok(notices) => {
+                    for notice in notices {
+                        // a trailing stop or scale-out targets requested at entry go in once the
+                        // entry is filled
+                        let exit = notice.exit;
+                        let mut lot = notice.lot.clone();
+                        notice_tx.send(notice).await.unwrap();
+                        if exit {
+                            continue;
+                        }
+                        let placed = match place_entry_exits(exit_broker.as_ref(), &mut lot).await {
+                            Ok(placed) => placed,
+                            Err(e) => {
+                                tracing::error!(
+                                    "error placing exits for {:?}: {}",
+                                    lot.client_id,
+                                    e
+                                );
+                                false
+                            }
+                        };
+                        if placed {
+                            notice_tx
+                                .send(LotUpdateNotice::new(lot, LotUpdateEvent::New))
+                                .await
+                                .unwrap();
+                        }
+                    }
+                }
+                Err(e) => tracing::error!("error applying trade update: {:?}", e),
+            }
+        }
+    });
 
     // build our application with a route
     let app = Router::new()
@@ -60,15 +152,31 @@ async fn main() {
         .route("/quote/:symbol", get(get_quote))
         .route("/positions", get(get_positions))
         .route("/orders", get(get_lots))
-        .route("/order", post(place_order))
+        .route("/lot/:client_id", get(get_lot))
+        .route("/lot/:client_id/history", get(get_lot_history))
+        .route("/lot/:client_id/fee", post(add_lot_fee))
+        .route("/lot/:client_id/cash", post(add_lot_cash))
+        .route("/marks", get(get_marks))
+        .route("/order", post(place_order).patch(modify_order))
         .route("/order/:id", delete(cancel_order))
         .route("/liquidate", patch(liquidate_order))
+        .route("/sell", post(sell_shares))
+        .route("/split", post(add_split))
+        .route("/wash_sales", get(get_wash_sales).post(scan_wash_sales))
         .route("/buckets", get(list_buckets))
         .route("/bucket", post(create_bucket))
         .route("/bucket/:name", patch(update_bucket))
+        .route("/bucket/:name/marks", get(get_bucket_marks))
+        .route("/bucket/:name/fees", get(get_bucket_fees))
+        .route(
+            "/bucket/:name/cash",
+            get(get_bucket_cash).post(add_bucket_cash),
+        )
+        .route("/cash/import", post(import_cash))
         .route("/bucket", delete(delete_bucket))
         .route("/ws", get(ws_handler))
         .layer(Extension(State {
+            broker,
             lot_update_sink: update_tx,
             lot_update_drain: update_rx,
         }))
@@ -99,78 +207,287 @@ async fn ws_handler(ws: WebSocketUpgrade, Extension(state): Extension<State>) ->
     })
 }
 
-fn alpaca_client() -> Client {
-    let api_info = ApiInfo::from_env().unwrap();
-    Client::new(api_info)
-}
-
 fn json_error(code: StatusCode, msg: &str) -> (StatusCode, Json<serde_json::Value>) {
     let body = json!({ "error": msg });
     (code, Json(body))
 }
 
-// extract helpful API error responses from the apca RequestError
-fn api_post_error(
-    e: apca::RequestError<order::PostError>,
-) -> (StatusCode, Json<serde_json::Value>) {
+fn broker_error(e: BrokerError) -> (StatusCode, Json<serde_json::Value>) {
     match e {
-        apca::RequestError::Endpoint(order::PostError::InvalidInput(api_error)) => {
-            json_error(StatusCode::BAD_REQUEST, &api_error.unwrap().message)
-        }
-        apca::RequestError::Endpoint(order::PostError::NotPermitted(api_error)) => {
-            json_error(StatusCode::BAD_REQUEST, &api_error.unwrap().message)
-        }
+        BrokerError::NotFound(_) => json_error(StatusCode::NOT_FOUND, &e.to_string()),
         _ => json_error(StatusCode::BAD_REQUEST, &e.to_string()),
     }
 }
 
+fn server_error(e: impl std::fmt::Display) -> (StatusCode, Json<serde_json::Value>) {
+    json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+}
+
+/// Tell websocket clients about a change to the lot made by a handler.
+async fn notify(state: &State, lot: &Lot, event: LotUpdateEvent) {
+    let notice = LotUpdateNotice::new(lot.clone(), event);
+    if state.lot_update_sink.send(notice).await.is_err() {
+        tracing::error!("notify: lot update channel closed");
+    }
+}
+
 // -- Handlers --
 async fn root() -> impl IntoResponse {
     Json(json!({ "message": "Hello, World!" }))
 }
 
-async fn get_last_trade(Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
-    let req = last_trade::LastTradeRequest::new(
-        params
-            .get("sym")
-            .unwrap()
-            .split(",")
-            .map(|s| s.to_string()) // ug
-            .collect(),
-    );
-    let trade = alpaca_client()
-        .issue::<last_trade::Get>(&req)
-        .await
-        .unwrap();
-
-    (StatusCode::OK, Json(trade))
+async fn get_last_trade(
+    Extension(state): Extension<State>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let syms: Vec<String> = match params.get("sym") {
+        Some(syms) => syms.split(',').map(|s| s.to_string()).collect(),
+        None => return json_error(StatusCode::BAD_REQUEST, "sym is required"),
+    };
+    match state.broker.latest_trades(&syms).await {
+        Ok(trades) => (StatusCode::OK, Json(json!(trades))),
+        Err(e) => broker_error(e),
+    }
 }
 
-async fn get_quote(Path(symbol): Path<String>) -> impl IntoResponse {
-    let req = last_quote::LastQuoteReq::new(vec![symbol]);
-    let quotes = alpaca_client()
-        .issue::<last_quote::Get>(&req)
-        .await
-        .unwrap();
-
-    (StatusCode::OK, Json(quotes))
+async fn get_quote(
+    Extension(state): Extension<State>,
+    Path(symbol): Path<String>,
+) -> impl IntoResponse {
+    match state.broker.latest_quote(&symbol).await {
+        Ok(quote) => (StatusCode::OK, Json(json!(quote))),
+        Err(e) => broker_error(e),
+    }
 }
 
-async fn get_positions() -> impl IntoResponse {
-    let positions = alpaca_client().issue::<positions::Get>(&()).await.unwrap();
-
-    (StatusCode::OK, Json(positions))
+async fn get_positions(Extension(state): Extension<State>) -> impl IntoResponse {
+    match state.broker.list_positions().await {
+        Ok(positions) => (StatusCode::OK, Json(json!(positions))),
+        Err(e) => broker_error(e),
+    }
 }
 
 async fn get_lots(Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
     let page = 0; // TODO: pagination
     let limit = 50;
     let show_canceled = params.contains_key("show_canceled");
-    let bucket_id = params.get("bucket_id").unwrap().parse::<i64>().unwrap();
+    let bucket_id = match params.get("bucket_id").map(|id| id.parse::<i64>()) {
+        Some(Ok(id)) => id,
+        _ => return json_error(StatusCode::BAD_REQUEST, "bucket_id is required"),
+    };
     // TODO - filter by status (open, closed, all)
-    let lots = Lot::get_lots(bucket_id, page, limit, show_canceled).unwrap();
+    let now = chrono::Utc::now();
+    let lots: Vec<LotWithStats> = match Lot::get_lots(bucket_id, page, limit, show_canceled) {
+        Ok(lots) => lots
+            .into_iter()
+            .map(|lot| LotWithStats::new(lot, now))
+            .collect(),
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+
+    (StatusCode::OK, Json(json!(lots)))
+}
+
+async fn get_lot(Path(client_id): Path<String>) -> impl IntoResponse {
+    match LotDetail::get(&client_id, chrono::Utc::now()) {
+        Ok(detail) => (StatusCode::OK, Json(json!(detail))),
+        Err(e) => json_error(StatusCode::NOT_FOUND, &e.to_string()),
+    }
+}
+
+async fn get_lot_history(Path(client_id): Path<String>) -> impl IntoResponse {
+    if let Err(e) = Lot::get_by_client_id(&client_id) {
+        return json_error(StatusCode::NOT_FOUND, &e.to_string());
+    }
+    match LotEvent::history(&client_id) {
+        Ok(events) => (StatusCode::OK, Json(json!(events))),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FeeInput {
+    kind: FeeKind,
+    side: ExecutionSide,
+    amount: Num,
+    note: Option<String>,
+}
+
+async fn add_lot_fee(
+    Path(client_id): Path<String>,
+    Json(input): Json<FeeInput>,
+) -> impl IntoResponse {
+    let mut lot = match Lot::get_by_client_id(&client_id) {
+        Ok(lot) => lot,
+        Err(e) => return json_error(StatusCode::NOT_FOUND, &e.to_string()),
+    };
+    if let Err(e) = Fee::record(&lot, input.kind, input.side, input.amount, None, input.note) {
+        return json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+    }
+    if let Err(e) = lot.apply_fees() {
+        return server_error(e);
+    }
+    if let Err(e) = lot.update() {
+        return server_error(e);
+    }
+    (StatusCode::OK, Json(json!(lot)))
+}
+
+/// Record a stock split or reverse split and restate the lots it affects.
+async fn add_split(Json(split): Json<StockSplit>) -> impl IntoResponse {
+    match split.apply() {
+        Ok(split) => (StatusCode::OK, Json(json!(split))),
+        Err(e) => json_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+}
+
+async fn get_wash_sales() -> impl IntoResponse {
+    match WashSale::all() {
+        Ok(sales) => (StatusCode::OK, Json(json!(sales))),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+/// Check for wash sales now, instead of waiting for the next sync. Returns those found.
+async fn scan_wash_sales() -> impl IntoResponse {
+    match wash_sale::scan() {
+        Ok(sales) => (StatusCode::OK, Json(json!(sales))),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+async fn get_bucket_fees(Path(name): Path<String>) -> impl IntoResponse {
+    let bucket = match Bucket::get_by_name(&name) {
+        Ok(bucket) => bucket,
+        Err(e) => return json_error(StatusCode::NOT_FOUND, &e.to_string()),
+    };
+    match Fee::for_bucket(bucket.rowid.unwrap()) {
+        Ok(fees) => (StatusCode::OK, Json(json!(FeeTotals::of(&fees)))),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CashInput {
+    kind: CashEventKind,
+    /// Positive when received, negative when paid
+    amount: Num,
+    note: Option<String>,
+    paid_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+async fn add_lot_cash(
+    Path(client_id): Path<String>,
+    Json(input): Json<CashInput>,
+) -> impl IntoResponse {
+    let mut lot = match Lot::get_by_client_id(&client_id) {
+        Ok(lot) => lot,
+        Err(e) => return json_error(StatusCode::NOT_FOUND, &e.to_string()),
+    };
+    if let Err(e) = CashEvent::record(
+        Some(&lot),
+        None,
+        input.kind,
+        input.amount,
+        input.note,
+        input.paid_at,
+    ) {
+        return json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+    }
+    if let Err(e) = lot.apply_cash_events() {
+        return server_error(e);
+    }
+    if let Err(e) = lot.update() {
+        return server_error(e);
+    }
+    (StatusCode::OK, Json(json!(lot)))
+}
+
+/// Record cash paid to the bucket as a whole, like interest, rather than to one of its lots.
+async fn add_bucket_cash(
+    Path(name): Path<String>,
+    Json(input): Json<CashInput>,
+) -> impl IntoResponse {
+    let bucket = match Bucket::get_by_name(&name) {
+        Ok(bucket) => bucket,
+        Err(e) => return json_error(StatusCode::NOT_FOUND, &e.to_string()),
+    };
+    match CashEvent::record(
+        None,
+        bucket.rowid,
+        input.kind,
+        input.amount,
+        input.note,
+        input.paid_at,
+    ) {
+        Ok(rowid) => (StatusCode::OK, Json(json!({ "rowid": rowid }))),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+async fn get_bucket_cash(Path(name): Path<String>) -> impl IntoResponse {
+    let bucket = match Bucket::get_by_name(&name) {
+        Ok(bucket) => bucket,
+        Err(e) => return json_error(StatusCode::NOT_FOUND, &e.to_string()),
+    };
+    match CashEvent::for_bucket(bucket.rowid.unwrap()) {
+        Ok(events) => (
+            StatusCode::OK,
+            Json(json!({ "totals": CashTotals::of(&events), "events": events })),
+        ),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+/// Import dividends and interest from the broker's account activities now, instead of waiting
+/// for the next sync.
+async fn import_cash(Extension(state): Extension<State>) -> impl IntoResponse {
+    match cash_event::import_from(state.broker.as_ref()).await {
+        Ok(events) => (StatusCode::OK, Json(json!(events))),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+/// Value the open lots at the latest trade for their symbols, fetched in one batch.
+async fn mark_lots(
+    broker: &dyn Broker,
+    bucket_id: Option<i64>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let lots = match Lot::get_open_lots(bucket_id) {
+        Ok(lots) => lots,
+        Err(e) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    let mut syms: Vec<String> = lots.iter().filter_map(|lot| lot.sym.clone()).collect();
+    syms.sort();
+    syms.dedup();
+
+    match broker.latest_trades(&syms).await {
+        Ok(prices) => {
+            let marks: Vec<LotMark> = lots
+                .into_iter()
+                .map(|lot| {
+                    let price = lot.sym.as_ref().and_then(|sym| prices.get(sym)).cloned();
+                    LotMark::new(lot, price)
+                })
+                .collect();
+            (StatusCode::OK, Json(json!(marks)))
+        }
+        Err(e) => broker_error(e),
+    }
+}
 
-    (StatusCode::OK, Json(lots))
+async fn get_marks(Extension(state): Extension<State>) -> impl IntoResponse {
+    mark_lots(state.broker.as_ref(), None).await
+}
+
+async fn get_bucket_marks(
+    Extension(state): Extension<State>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match Bucket::get_by_name(&name) {
+        Ok(bucket) => mark_lots(state.broker.as_ref(), bucket.rowid).await,
+        Err(e) => json_error(StatusCode::NOT_FOUND, &e.to_string()),
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -183,7 +500,29 @@ struct OrderPlacementInput {
     target: Option<Num>,
     time_in_force: Option<lot::OrderTimeInForce>,
     market: Option<bool>,
+    #[serde(rename = "orderType")]
+    type_: Option<OrderType>,
+    /// Stop price of a stop or stop-limit entry
+    trigger: Option<Num>,
     side: Option<lot::PositionType>,
+    /// Exit with a trailing stop instead of a bracket, trailing by dollars or percent
+    trail_price: Option<Num>,
+    trail_percent: Option<Num>,
+    /// Keep the stop off the broker, zoocarp sends the exit once it is crossed
+    soft_stop: Option<bool>,
+    /// Keep the target off the broker, zoocarp sends the exit once it is crossed
+    soft_target: Option<bool>,
+    /// Exit sent when a soft level is crossed, market or limit at the level
+    soft_exit_type: Option<OrderType>,
+    /// Scale out at several targets instead of one, nearest first
+    targets: Option<Vec<TargetInput>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TargetInput {
+    price: Num,
+    /// Quantity to take off at the target, the last one takes what is left if not given
+    qty: Option<Num>,
 }
 
 async fn place_order(
@@ -191,93 +530,463 @@ async fn place_order(
     state: Extension<State>,
 ) -> impl IntoResponse {
     let side = input.side.unwrap_or(lot::PositionType::Long);
+    let trailing = input.trail_price.is_some() || input.trail_percent.is_some();
+    if input.trail_price.is_some() && input.trail_percent.is_some() {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "Give either trail_price or trail_percent",
+        );
+    }
+    if trailing && input.target.is_some() {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "A trailing stop cannot be combined with a target",
+        );
+    }
 
-    let qty = input.qty;
-    let bucket = Bucket::get_by_id(&input.bucket_id.into()).unwrap();
+    let soft_stop = input.soft_stop.unwrap_or(false);
+    let soft_target = input.soft_target.unwrap_or(false);
+    if (soft_stop && input.stop.is_none()) || (soft_target && input.target.is_none()) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "A soft stop or target needs its price",
+        );
+    }
+    if soft_stop && trailing {
+        return json_error(StatusCode::BAD_REQUEST, "A trailing stop cannot be soft");
+    }
+    if !matches!(
+        input.soft_exit_type,
+        None | Some(OrderType::Market) | Some(OrderType::Limit)
+    ) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "A soft exit is either a market or a limit order",
+        );
+    }
 
-    let lot_id = Lot::create(
-        input.sym.clone(),
-        Num::from(qty),
-        side,
-        bucket,
-        input.limit.clone(),
-        input.target.clone(),
-        input.stop.clone(),
-        input.time_in_force,
-    );
-    let mut lot = Lot::get(lot_id).unwrap();
+    // each target of a lot that scales out goes in with the stop as a one-cancels-other pair
+    // once the entry fills
+    let targets: Vec<(Num, Option<Num>)> = input
+        .targets
+        .iter()
+        .flatten()
+        .map(|target| (target.price.clone(), target.qty.clone()))
+        .collect();
+    let scale_out = !targets.is_empty();
+    if scale_out && (input.target.is_some() || trailing || soft_stop || soft_target) {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "Targets cannot be combined with a target, a trailing stop or soft exits",
+        );
+    }
+    if scale_out && input.stop.is_none() {
+        return json_error(StatusCode::BAD_REQUEST, "Scaling out needs a stop");
+    }
+    let plan = if scale_out {
+        match Tranche::plan(&Num::from(input.qty), &targets) {
+            Ok(plan) => plan,
+            Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+        }
+    } else {
+        vec![]
+    };
 
-    let market = input.market.unwrap_or(false);
+    // a bracket needs both exits. Without either the entry goes in on its own, and exits can be
+    // attached once it fills. Soft exits stay with zoocarp, so the other one goes in alone
+    let stop_loss = input
+        .stop
+        .clone()
+        .filter(|_| !soft_stop && !trailing && !scale_out);
+    let take_profit = input.target.clone().filter(|_| !soft_target);
+    let class = match (&stop_loss, &take_profit) {
+        (Some(_), Some(_)) => OrderClass::Bracket,
+        (None, None) => OrderClass::Simple,
+        _ if soft_stop || soft_target => OrderClass::OneTriggersOther,
+        _ => {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "A bracket order needs both a stop and a target",
+            )
+        }
+    };
+    let type_ = input.type_.unwrap_or(if input.market.unwrap_or(false) {
+        OrderType::Market
+    } else {
+        OrderType::Limit
+    });
 
-    let request = order::OrderReqInit {
-        client_order_id: lot.client_id.clone(),
-        class: order::Class::Bracket,
-        type_: if market {
-            order::Type::Market
-        } else {
-            order::Type::Limit
+    let mut request = OrderRequest {
+        sym: input.sym.clone(),
+        side: side.entry_side(),
+        qty: Num::from(input.qty),
+        class,
+        type_,
+        limit_price: match type_ {
+            OrderType::Limit | OrderType::StopLimit => input.limit.clone(),
+            _ => None,
         },
-        limit_price: if market { None } else { input.limit },
-        // extended_hours: true, // TODO make it an input, but cannot use market, or bracket orders per docs
-        stop_loss: Some(order::StopLoss::Stop(input.stop.unwrap_or_default())),
-        take_profit: Some(order::TakeProfit::Limit(input.target.unwrap_or_default())),
-        time_in_force: match input.time_in_force {
-            Some(lot::OrderTimeInForce::Day) => order::TimeInForce::Day,
-            _ => order::TimeInForce::UntilCanceled,
+        stop_price: match type_ {
+            OrderType::Stop | OrderType::StopLimit => input.trigger.clone(),
+            _ => None,
         },
+        // extended_hours: true, // TODO make it an input, but cannot use market, or bracket orders per docs
+        stop_loss,
+        take_profit,
+        time_in_force: input.time_in_force,
         ..Default::default()
+    };
+    if let Err(e) = request.validate() {
+        return broker_error(e);
     }
-    .init(
+
+    let bucket = match Bucket::get_by_id(&input.bucket_id.into()) {
+        Ok(bucket) => bucket,
+        Err(e) => return json_error(StatusCode::NOT_FOUND, &e.to_string()),
+    };
+    let lot_id = Lot::create(
         input.sym,
-        if side == lot::PositionType::Long {
-            order::Side::Buy
-        } else {
-            order::Side::Sell
-        },
-        order::Amount::quantity(qty),
+        Num::from(input.qty),
+        side,
+        bucket,
+        request.limit_price.clone(),
+        // the furthest target for a lot that scales out
+        input
+            .target
+            .or_else(|| plan.last().map(|(price, _)| price.clone())),
+        input.stop,
+        input.time_in_force,
     );
+    let mut lot = match Lot::get(lot_id) {
+        Ok(lot) => lot,
+        Err(e) => return server_error(e),
+    };
+    if scale_out {
+        if let Err(e) = Tranche::create_for(&lot, &plan) {
+            return server_error(e);
+        }
+        lot.tranche_count = Some(plan.len() as i64);
+    }
+    if trailing || soft_stop || soft_target || scale_out {
+        lot.trail_price = input.trail_price;
+        lot.trail_percent = input.trail_percent;
+        lot.soft_stop = Some(soft_stop);
+        lot.soft_target = Some(soft_target);
+        lot.soft_exit_type = input.soft_exit_type;
+        if let Err(e) = lot.update() {
+            return server_error(e);
+        }
+    }
+    request.client_order_id = lot.client_id.clone();
 
-    match alpaca_client().issue::<order::Post>(&request).await {
+    match state.broker.place_order(&request).await {
         Ok(order) => {
-            tracing::debug!("Created order {}", order.id.as_hyphenated());
-
-            lot.fill_with(&order).unwrap();
-            state
-                .lot_update_sink
-                .send(LotUpdateNotice {
-                    event: LotUpdateEvent::New,
-                    lot: lot.clone(),
-                })
-                .await
-                .unwrap();
+            tracing::debug!("Created order {}", order.id);
 
-            tracing::debug!(
-                ">>> New order: {:?} => {:?}",
-                order.id.as_hyphenated().to_string(),
-                lot.rowid
-            );
+            if let Err(e) = lot.fill_with(&order) {
+                // the order is working, its updates bring the lot up to date
+                tracing::error!(
+                    "error updating {:?} with {}: {}",
+                    lot.client_id,
+                    order.id,
+                    e
+                );
+            }
+            // an entry that filled right away gets the exits waiting for the fill now, as its
+            // update may already have gone by
+            if let Err(e) = place_entry_exits(state.broker.as_ref(), &mut lot).await {
+                tracing::error!("error placing exits for {:?}: {}", lot.client_id, e);
+            }
+            notify(&state, &lot, LotUpdateEvent::New).await;
+
+            tracing::debug!(">>> New order: {:?} => {:?}", order.id, lot.rowid);
             (StatusCode::OK, Json(json!(lot)))
         }
         Err(e) => {
             tracing::error!("error placing order: {:?}", e);
-            api_post_error(e)
+            if let Err(reject_error) = lot.reject_with(&e.to_string()) {
+                return server_error(reject_error);
+            }
+            broker_error(e)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OrderModificationInput {
+    id: String,
+    stop: Option<Num>,
+    target: Option<Num>,
+    /// Convert the stop to a trailing stop, or change the trail of one
+    trail_price: Option<Num>,
+    trail_percent: Option<Num>,
+}
+
+/// Whether the order is still working on the broker. Orders that cannot be found count as done.
+async fn is_live(broker: &dyn Broker, id: &Option<OrderId>) -> bool {
+    match id {
+        Some(id) => match broker.get_order(id).await {
+            Ok(order) => !order.status.is_terminal(),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+/// Convert the lot's stop to a trailing stop, or change its trail.
+async fn trail_order(
+    broker: &dyn Broker,
+    mut lot: Lot,
+    trail_price: Option<Num>,
+    trail_percent: Option<Num>,
+) -> Result<Lot, (StatusCode, Json<serde_json::Value>)> {
+    if trail_price.is_some() && trail_percent.is_some() {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "Give either trail_price or trail_percent",
+        ));
+    }
+    if (trail_price.is_some() && lot.trail_percent.is_some())
+        || (trail_percent.is_some() && lot.trail_price.is_some())
+    {
+        return Err(json_error(
+            StatusCode::BAD_REQUEST,
+            "Cannot switch a trailing stop between dollars and percent",
+        ));
+    }
+    let was_trailing = lot.is_trailing();
+    lot.trail_price = trail_price;
+    lot.trail_percent = trail_percent;
+
+    let stop = place_trailing_stop(broker, &lot)
+        .await
+        .map_err(broker_error)?;
+    if !was_trailing {
+        lot.target_order_id = None;
+        lot.exit_order_id = None;
+        lot.adjust_price(PriceField::Target, None, AdjustmentReason::Trailing)
+            .map_err(server_error)?;
+    }
+    lot.track_trailing_stop(&stop).map_err(server_error)?;
+    Ok(lot)
+}
+
+async fn modify_order(
+    Extension(state): Extension<State>,
+    Json(input): Json<OrderModificationInput>,
+) -> impl IntoResponse {
+    let mut lot = match Lot::get_by_client_id(&input.id) {
+        Ok(lot) => lot,
+        Err(e) => return json_error(StatusCode::NOT_FOUND, &e.to_string()),
+    };
+    if lot.status != Some(LotStatus::Open) {
+        return json_error(StatusCode::BAD_REQUEST, "Lot is not open, cannot modify");
+    }
+    if input.trail_price.is_some() || input.trail_percent.is_some() {
+        if input.stop.is_some() || input.target.is_some() {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "Give either a trail or a stop and target",
+            );
+        }
+        return match trail_order(
+            state.broker.as_ref(),
+            lot,
+            input.trail_price,
+            input.trail_percent,
+        )
+        .await
+        {
+            Ok(lot) => {
+                notify(&state, &lot, LotUpdateEvent::Replaced).await;
+                (StatusCode::OK, Json(json!(lot)))
+            }
+            Err(response) => response,
+        };
+    }
+    if lot.is_trailing() && input.stop.is_some() {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "Lot has a trailing stop, change its trail instead",
+        );
+    }
+    if input.stop.is_none() && input.target.is_none() {
+        return json_error(StatusCode::BAD_REQUEST, "Nothing to modify");
+    }
+    if lot.scales_out() {
+        if input.target.is_some() {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "Lot scales out, its targets cannot be changed",
+            );
+        }
+        if let Some(stop_price) = input.stop {
+            if let Err(e) = move_tranche_stops(state.broker.as_ref(), &lot, &stop_price).await {
+                return broker_error(e);
+            }
+            if let Err(e) =
+                lot.adjust_price(PriceField::Stop, Some(stop_price), AdjustmentReason::Manual)
+            {
+                return server_error(e);
+            }
+            if let Err(e) = lot.update() {
+                return server_error(e);
+            }
+            notify(&state, &lot, LotUpdateEvent::Replaced).await;
+            return (StatusCode::OK, Json(json!(lot)));
         }
     }
+    // soft levels only live on the lot, so moving them does not involve the broker
+    let mut input = input;
+    let soft_stop = lot.soft_stop == Some(true) && input.stop.is_some();
+    let soft_target = lot.soft_target == Some(true) && input.target.is_some();
+    if soft_stop || soft_target {
+        if lot.soft_triggered_at.is_some() {
+            return json_error(StatusCode::BAD_REQUEST, "Soft exit was already sent");
+        }
+        if soft_stop {
+            if let Err(e) = lot.adjust_price(
+                PriceField::Stop,
+                input.stop.take(),
+                AdjustmentReason::Manual,
+            ) {
+                return server_error(e);
+            }
+        }
+        if soft_target {
+            if let Err(e) = lot.adjust_price(
+                PriceField::Target,
+                input.target.take(),
+                AdjustmentReason::Manual,
+            ) {
+                return server_error(e);
+            }
+        }
+        if let Err(e) = lot.update() {
+            return server_error(e);
+        }
+        if input.stop.is_none() && input.target.is_none() {
+            notify(&state, &lot, LotUpdateEvent::Replaced).await;
+            return (StatusCode::OK, Json(json!(lot)));
+        }
+    }
+    let broker = state.broker.as_ref();
+    // the lot was entered without a bracket, or its legs are gone, so attach the exits as orders
+    // of their own
+    let stop_live = is_live(broker, &lot.stop_order_id).await;
+    let target_live = is_live(broker, &lot.target_order_id).await;
+    if (input.stop.is_some() && !stop_live) || (input.target.is_some() && !target_live) {
+        if stop_live || target_live {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "Lot has a live exit, modify it or liquidate first",
+            );
+        }
+        let (stop, target) = match (input.stop, input.target) {
+            (Some(stop_price), None) => {
+                match place_exit(broker, &lot, OrderType::Stop, stop_price).await {
+                    Ok(order) => (Some(order), None),
+                    Err(e) => return broker_error(e),
+                }
+            }
+            (None, Some(limit_price)) => {
+                match place_exit(broker, &lot, OrderType::Limit, limit_price).await {
+                    Ok(order) => (None, Some(order)),
+                    Err(e) => return broker_error(e),
+                }
+            }
+            (Some(stop_price), Some(limit_price)) => {
+                let qty = lot
+                    .open_qty()
+                    .or_else(|| lot.qty.clone())
+                    .unwrap_or_default();
+                match place_oco_exit(broker, &lot, qty, stop_price, limit_price).await {
+                    Ok(order) => (order.legs.first().cloned(), Some(order)),
+                    Err(e) => return broker_error(e),
+                }
+            }
+            (None, None) => unreachable!(),
+        };
+        lot.exit_order_id = None;
+        if let Err(e) = lot.modify_bracket(stop.as_ref(), target.as_ref(), AdjustmentReason::Manual)
+        {
+            return server_error(e);
+        }
+        // the take profit of an oco pair carries the stop as its leg
+        if let Some(oco) = target.as_ref().filter(|order| !order.legs.is_empty()) {
+            if let Err(e) = lot.track_oco(oco) {
+                return server_error(e);
+            }
+        }
+        notify(&state, &lot, LotUpdateEvent::New).await;
+        return (StatusCode::OK, Json(json!(lot)));
+    }
+
+    // both legs are live here when asked for, so they have IDs
+    let stop = match (input.stop, &lot.stop_order_id) {
+        (Some(stop_price), Some(stop_order_id)) => {
+            let request = ReplaceRequest {
+                stop_price: Some(stop_price),
+                ..Default::default()
+            };
+            match broker.replace_order(stop_order_id, &request).await {
+                Ok(order) => Some(order),
+                Err(e) => return broker_error(e),
+            }
+        }
+        _ => None,
+    };
+    let target = match (input.target, &lot.target_order_id) {
+        (Some(limit_price), Some(target_order_id)) => {
+            let request = ReplaceRequest {
+                limit_price: Some(limit_price),
+                ..Default::default()
+            };
+            match broker.replace_order(target_order_id, &request).await {
+                Ok(order) => Some(order),
+                Err(e) => {
+                    // keep the stop that already went through
+                    if stop.is_some() {
+                        if let Err(save_error) =
+                            lot.modify_bracket(stop.as_ref(), None, AdjustmentReason::Manual)
+                        {
+                            tracing::error!("modify_order: {:?}: {}", lot.client_id, save_error);
+                        }
+                        notify(&state, &lot, LotUpdateEvent::Replaced).await;
+                    }
+                    return broker_error(e);
+                }
+            }
+        }
+        _ => None,
+    };
+
+    if let Err(e) = lot.modify_bracket(stop.as_ref(), target.as_ref(), AdjustmentReason::Manual) {
+        return server_error(e);
+    }
+    notify(&state, &lot, LotUpdateEvent::Replaced).await;
+    (StatusCode::OK, Json(json!(lot)))
 }
 
 #[derive(Debug, Deserialize)]
 struct OrderLiquidationInput {
-    time_in_force: Option<order::TimeInForce>,
+    time_in_force: Option<lot::OrderTimeInForce>,
     stop: Option<Num>,
     #[serde(rename = "orderType")]
-    type_: Option<order::Type>,
+    type_: Option<OrderType>,
     id: String,
 }
 
-async fn liquidate_order(Json(input): Json<OrderLiquidationInput>) -> impl IntoResponse {
+async fn liquidate_order(
+    Extension(state): Extension<State>,
+    Json(input): Json<OrderLiquidationInput>,
+) -> impl IntoResponse {
     let client_id = &input.id;
 
-    let mut lot = Lot::get_by_client_id(&client_id).unwrap();
+    let mut lot = match Lot::get_by_client_id(client_id) {
+        Ok(lot) => lot,
+        Err(e) => return json_error(StatusCode::NOT_FOUND, &e.to_string()),
+    };
     if lot.status != Some(LotStatus::Open) {
         return json_error(
             StatusCode::BAD_REQUEST,
@@ -285,11 +994,14 @@ async fn liquidate_order(Json(input): Json<OrderLiquidationInput>) -> impl IntoR
         );
     }
 
-    let id = lot.open_order_id.unwrap();
-    tracing::debug!("Fetching order with id {}", id.as_hyphenated());
-    let client = alpaca_client();
+    let id = match lot.open_order_id.clone() {
+        Some(id) => id,
+        None => return json_error(StatusCode::BAD_REQUEST, "Lot has no open order"),
+    };
+    tracing::debug!("Fetching order with id {}", id);
+    let broker = state.broker.as_ref();
 
-    let get_order = client.issue::<order::Get>(&id).await;
+    let get_order = broker.get_order(&id).await;
 
     match get_order {
         Err(e) => {
@@ -300,71 +1012,145 @@ async fn liquidate_order(Json(input): Json<OrderLiquidationInput>) -> impl IntoR
         }
         Ok(retrieved) => {
             tracing::debug!("order found! {:?}", retrieved);
-            let type_ = input.type_.unwrap_or(order::Type::Market);
+            let type_ = input.type_.unwrap_or(OrderType::Market);
             let stop_price = match type_ {
-                order::Type::Market => None,
+                OrderType::Market => None,
                 _ => input.stop,
             };
 
-            let reqt = order::OrderReqInit {
-                time_in_force: input.time_in_force.unwrap_or(order::TimeInForce::Day),
+            // close out what the lot holds: sell a long, buy back a short
+            let reqt = OrderRequest {
+                sym: retrieved.sym.clone(),
+                side: lot.exit_side(),
+                qty: lot
+                    .open_qty()
+                    .unwrap_or_else(|| retrieved.filled_quantity.clone()),
+                time_in_force: Some(input.time_in_force.unwrap_or(lot::OrderTimeInForce::Day)),
                 stop_price,
                 type_,
                 ..Default::default()
-            }
-            .init(
-                retrieved.symbol,
-                order::Side::Sell,
-                order::Amount::quantity(retrieved.filled_quantity),
-            );
+            };
             tracing::debug!("req! {:?}", reqt);
             // might want to use OCO but not clear on how to work it with a bracket order
-            if retrieved.legs.len() > 1 {
-                let open_legs = retrieved
-                    .legs
-                    .into_iter()
-                    .filter(|leg| !leg.status.is_terminal())
-                    .map(|leg| client.issue::<order::Delete>(&leg.id));
-                futures::future::join_all(open_legs).await;
+            if let Err(e) = cancel_exits(broker, &lot, &retrieved).await {
+                return broker_error(e);
             }
 
             if retrieved.status.is_terminal() {
                 tracing::debug!("Base order already terminal");
             } else {
-                client.issue::<order::Delete>(&retrieved.id).await.unwrap();
-                tracing::debug!("Deleted order {}", retrieved.id.as_hyphenated());
+                if let Err(e) = broker.cancel_order(&retrieved.id).await {
+                    return broker_error(e);
+                }
+                tracing::debug!("Deleted order {}", retrieved.id);
             }
 
-            let result = client.issue::<order::Post>(&reqt).await;
-            if result.is_err() {
-                tracing::debug!("bad! {:?}", result);
-                let e = result.err().unwrap();
-                let body = Json(json!({
-                    "error": e.to_string()
-                }));
-                return (StatusCode::BAD_REQUEST, body);
-            }
-            let replaced = result.unwrap();
-            tracing::debug!("Replaced with order {}", replaced.id.as_hyphenated());
-
-            let lot = lot.liquidate_with(&replaced).unwrap();
+            let replaced = match broker.place_order(&reqt).await {
+                Ok(replaced) => replaced,
+                Err(e) => {
+                    tracing::debug!("bad! {:?}", e);
+                    return broker_error(e);
+                }
+            };
+            tracing::debug!("Replaced with order {}", replaced.id);
 
-            (StatusCode::OK, Json(json!(lot)))
+            match lot.liquidate_with(&replaced) {
+                Ok(lot) => (StatusCode::OK, Json(json!(lot))),
+                Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+            }
         }
     }
 }
 
-async fn cancel_order(Path(client_id): Path<String>) -> impl IntoResponse {
-    let lot = Lot::get_by_client_id(&client_id).unwrap();
-    if lot.status != Some(LotStatus::Pending) || lot.open_order_id.is_none() {
-        return json_error(StatusCode::BAD_REQUEST, "Lot cannot be cancelled");
+#[derive(Debug, Deserialize)]
+struct SellInput {
+    bucket_id: i64,
+    sym: String,
+    qty: Num,
+    /// How the lots to sell are chosen
+    method: LotSelection,
+    /// Client IDs of the lots to sell, in order, for a Specific selection
+    lot_ids: Option<Vec<String>>,
+    /// Side of the lots, long ones are sold and short ones bought back; long if not given
+    side: Option<lot::PositionType>,
+    #[serde(rename = "orderType")]
+    type_: Option<OrderType>,
+    limit: Option<Num>,
+    time_in_force: Option<lot::OrderTimeInForce>,
+}
+
+/// Sell part of a position held across several lots in a bucket, or cover part of a short one.
+/// The lots are chosen by the method given and closed with one order; a lot closed in part is
+/// split, and the rest of it gets its exits back.
+async fn sell_shares(
+    Extension(state): Extension<State>,
+    Json(input): Json<SellInput>,
+) -> impl IntoResponse {
+    let position_type = input.side.unwrap_or(lot::PositionType::Long);
+    let chosen = match select_lots(
+        input.bucket_id,
+        &input.sym,
+        position_type,
+        &input.qty,
+        input.method,
+        input.lot_ids.as_deref().unwrap_or_default(),
+    ) {
+        Ok(chosen) => chosen,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let type_ = input.type_.unwrap_or(OrderType::Market);
+    if type_ == OrderType::Limit && input.limit.is_none() {
+        return json_error(
+            StatusCode::BAD_REQUEST,
+            "A limit sale needs its limit price",
+        );
+    }
+    if !matches!(type_, OrderType::Market | OrderType::Limit) {
+        return json_error(StatusCode::BAD_REQUEST, "A sale is a market or limit order");
     }
 
-    let response = alpaca_client()
-        .issue::<order::Delete>(&lot.open_order_id.unwrap())
-        .await;
-    match response {
-        Err(e) => json_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    let request = OrderRequest {
+        sym: input.sym.clone(),
+        side: position_type.exit_side(),
+        qty: input.qty.clone(),
+        type_,
+        time_in_force: Some(input.time_in_force.unwrap_or(lot::OrderTimeInForce::Day)),
+        limit_price: if type_ == OrderType::Limit {
+            input.limit.clone()
+        } else {
+            None
+        },
+        ..Default::default()
+    };
+    let (order, lots) =
+        match tax_lot::sell(state.broker.as_ref(), chosen, &request, input.method).await {
+            Ok(sale) => sale,
+            Err(e) => return broker_error(e),
+        };
+    for lot in &lots {
+        notify(&state, lot, LotUpdateEvent::Replaced).await;
+    }
+    (
+        StatusCode::OK,
+        Json(json!({ "order": order, "lots": lots })),
+    )
+}
+
+async fn cancel_order(
+    Extension(state): Extension<State>,
+    Path(client_id): Path<String>,
+) -> impl IntoResponse {
+    let lot = match Lot::get_by_client_id(&client_id) {
+        Ok(lot) => lot,
+        Err(e) => return json_error(StatusCode::NOT_FOUND, &e.to_string()),
+    };
+    let open_order_id = match (&lot.status, &lot.open_order_id) {
+        (Some(LotStatus::Pending), Some(id)) => id,
+        _ => return json_error(StatusCode::BAD_REQUEST, "Lot cannot be cancelled"),
+    };
+
+    match state.broker.cancel_order(open_order_id).await {
+        Err(e) => broker_error(e),
         Ok(_) => {
             // lot.cancel().unwrap();
             (StatusCode::OK, Json(json!(lot)))
@@ -373,8 +1159,10 @@ async fn cancel_order(Path(client_id): Path<String>) -> impl IntoResponse {
 }
 
 async fn list_buckets() -> impl IntoResponse {
-    let buckets = Bucket::list().unwrap();
-    (StatusCode::OK, Json(json!(buckets)))
+    match Bucket::list() {
+        Ok(buckets) => (StatusCode::OK, Json(json!(buckets))),
+        Err(e) => json_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
 }
 
 #[derive(Debug, Deserialize)]
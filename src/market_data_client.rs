@@ -0,0 +1,212 @@
+use apca::ApiInfo;
+use chrono::{DateTime, Utc};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use num_decimal::Num;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeSet;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+use crate::broker::{SymbolDrain, Trade, TradeSink};
+
+type WssStream = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Longest wait between attempts to reconnect, the wait doubling from a second up to it.
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+
+/// One message of the market data stream. Only trades, `T` of "t", carry the symbol, price and
+/// time; control messages are logged and otherwise ignored.
+#[derive(Debug, Deserialize)]
+struct DataMessage {
+    #[serde(rename = "T")]
+    type_: String,
+    #[serde(rename = "S")]
+    sym: Option<String>,
+    #[serde(rename = "p")]
+    price: Option<Num>,
+    #[serde(rename = "t")]
+    timestamp: Option<DateTime<Utc>>,
+    msg: Option<String>,
+}
+
+/// Connect to the market data stream of `feed`, iex or sip, and forward the trades of the symbols
+/// last received on `syms` to `sink`. The account has one market data connection, so a change of
+/// symbols is sent as a subscribe and unsubscribe on it rather than a new connection. When the
+/// connection drops it is made again, and the symbols watched subscribed to again.
+pub async fn listen_for_trades(
+    api_info: &ApiInfo,
+    feed: &str,
+    syms: SymbolDrain,
+    sink: TradeSink,
+) -> Result<(), tungstenite::Error> {
+    let mut url = api_info.data_stream_base_url.clone();
+    url.set_path(&format!("/v2/{}", feed));
+
+    let (reader, writer) = connect_and_authorize(&url, api_info).await?;
+    tracing::info!("Connected to market data stream");
+
+    tokio::task::spawn(stream_trades(
+        api_info.clone(),
+        url,
+        reader,
+        writer,
+        syms,
+        sink,
+    ));
+    Ok(())
+}
+
+async fn connect_and_authorize(
+    url: &url::Url,
+    api_info: &ApiInfo,
+) -> Result<(SplitStream<WssStream>, SplitSink<WssStream, Message>), tungstenite::Error> {
+    let (socket, _response) = connect_async(url).await?;
+    let (mut writer, reader) = socket.split();
+    let auth =
+        json!({ "action": "auth", "key": api_info.key_id, "secret": api_info.secret }).to_string();
+    writer.send(Message::Text(auth)).await?;
+    Ok((reader, writer))
+}
+
+async fn stream_trades(
+    api_info: ApiInfo,
+    url: url::Url,
+    mut reader: SplitStream<WssStream>,
+    mut writer: SplitSink<WssStream, Message>,
+    syms: SymbolDrain,
+    sink: TradeSink,
+) {
+    let mut watched: BTreeSet<String> = BTreeSet::new();
+    loop {
+        if !forward_trades(&mut reader, &mut writer, &syms, &sink, &mut watched).await {
+            return;
+        }
+        // soft exits fall back to polling until the stream is back
+        let mut delay = Duration::from_secs(1);
+        loop {
+            tokio::time::sleep(delay).await;
+            match connect_and_authorize(&url, &api_info).await {
+                Ok((next_reader, next_writer)) => {
+                    reader = next_reader;
+                    writer = next_writer;
+                    break;
+                }
+                Err(e) => {
+                    tracing::error!("market data reconnect: {:?}", e);
+                    delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+                }
+            }
+        }
+        tracing::info!("Reconnected to market data stream");
+        if !watched.is_empty() {
+            let subscribe = json!({ "action": "subscribe", "trades": watched });
+            if let Err(e) = writer.send(Message::Text(subscribe.to_string())).await {
+                tracing::error!("market data send: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Forward trades from one connection until it drops, returning true, or until the trade channel
+/// closes, returning false.
+async fn forward_trades(
+    reader: &mut SplitStream<WssStream>,
+    writer: &mut SplitSink<WssStream, Message>,
+    syms: &SymbolDrain,
+    sink: &TradeSink,
+    watched: &mut BTreeSet<String>,
+) -> bool {
+    let mut ping = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        let sent = tokio::select! {
+            _ = ping.tick() => writer.send(Message::Ping(vec![])).await,
+            Ok(next) = syms.recv() => {
+                let next: BTreeSet<String> = next.into_iter().collect();
+                let added: Vec<&String> = next.difference(watched).collect();
+                let removed: Vec<&String> = watched.difference(&next).collect();
+                let mut sent = Ok(());
+                if !added.is_empty() {
+                    let subscribe = json!({ "action": "subscribe", "trades": added });
+                    sent = writer.send(Message::Text(subscribe.to_string())).await;
+                }
+                if sent.is_ok() && !removed.is_empty() {
+                    let unsubscribe = json!({ "action": "unsubscribe", "trades": removed });
+                    sent = writer.send(Message::Text(unsubscribe.to_string())).await;
+                }
+                *watched = next;
+                sent
+            }
+            message = reader.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Binary(bytes))) => String::from_utf8_lossy(&bytes).to_string(),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        tracing::error!("market data recv: {:?}", e);
+                        return true;
+                    }
+                    None => {
+                        tracing::error!("market data recv: closed");
+                        return true;
+                    }
+                };
+                for trade in parse_trades(&text) {
+                    if sink.send(trade).await.is_err() {
+                        tracing::error!("market data: trade channel closed");
+                        return false;
+                    }
+                }
+                Ok(())
+            }
+        };
+        if let Err(e) = sent {
+            tracing::error!("market data send: {:?}", e);
+            return true;
+        }
+    }
+}
+
+/// The trades in a message of the stream, which sends a JSON array of messages at a time.
+fn parse_trades(text: &str) -> Vec<Trade> {
+    let messages: Vec<DataMessage> = match serde_json::from_str(text) {
+        Ok(messages) => messages,
+        Err(e) => {
+            tracing::error!("market data: {:?} in {}", e, text);
+            return vec![];
+        }
+    };
+    let mut trades = vec![];
+    for message in messages {
+        match (
+            message.type_.as_str(),
+            message.sym,
+            message.price,
+            message.timestamp,
+        ) {
+            ("t", Some(sym), Some(price), Some(timestamp)) => trades.push(Trade {
+                sym,
+                price,
+                timestamp,
+            }),
+            ("error", _, _, _) => tracing::error!("market data: {:?}", message.msg),
+            _ => tracing::debug!("market data: {} {:?}", message.type_, message.msg),
+        }
+    }
+    trades
+}
+
+#[test]
+fn test_parse_trades() {
+    let text = r#"[{"T":"success","msg":"authenticated"},
+        {"T":"t","S":"AAPL","i":52983525029461,"x":"V","p":126.55,"s":1,"t":"2021-02-22T15:51:44.208Z","c":["@","I"],"z":"C"}]"#;
+    let trades = parse_trades(text);
+    assert_eq!(trades.len(), 1);
+    assert_eq!(trades[0].sym, "AAPL");
+    assert_eq!(trades[0].price, Num::new(12655, 100));
+    assert!(parse_trades("not json").is_empty());
+}
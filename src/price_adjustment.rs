@@ -0,0 +1,77 @@
+use chrono::{DateTime, Utc};
+use num_decimal::Num;
+use serde::{Deserialize, Serialize};
+use turbosql::{select, Turbosql};
+
+use crate::lot::Lot;
+
+/// Which of a lot's prices was changed.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum PriceField {
+    Limit,
+    Stop,
+    Target,
+}
+
+/// Why a lot's price was changed.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum AdjustmentReason {
+    /// Edited by the user.
+    Manual,
+    /// Stop following the price.
+    Trailing,
+    /// The broker reported a price different from the one on the lot.
+    Broker,
+    /// Restated for a stock split or reverse split.
+    Split,
+}
+
+/// One change to the limit, stop or target price of a lot. Rows are never updated.
+#[derive(Debug, Serialize, Turbosql, Default, Clone)]
+pub struct PriceAdjustment {
+    /// DB row ID
+    pub rowid: Option<i64>,
+    /// Row ID of the lot
+    pub lot_id: Option<i64>,
+    /// Local ID of the lot
+    pub client_id: Option<String>,
+    pub field: Option<PriceField>,
+    pub old_price: Option<Num>,
+    pub new_price: Option<Num>,
+    pub reason: Option<AdjustmentReason>,
+    pub adjusted_at: Option<DateTime<Utc>>,
+}
+
+impl PriceAdjustment {
+    pub fn record(
+        lot: &Lot,
+        field: PriceField,
+        old_price: Option<Num>,
+        new_price: Option<Num>,
+        reason: AdjustmentReason,
+    ) -> Result<i64, turbosql::Error> {
+        tracing::debug!(
+            "price_adjustment: {:?} {:?} {:?} => {:?} ({:?})",
+            lot.client_id,
+            field,
+            old_price,
+            new_price,
+            reason
+        );
+        Self {
+            lot_id: lot.rowid,
+            client_id: lot.client_id.clone(),
+            field: Some(field),
+            old_price,
+            new_price,
+            reason: Some(reason),
+            adjusted_at: Some(Utc::now()),
+            ..Default::default()
+        }
+        .insert()
+    }
+
+    pub fn history(client_id: &str) -> Result<Vec<Self>, turbosql::Error> {
+        select!(Vec<PriceAdjustment> "WHERE client_id = ? ORDER BY rowid", client_id)
+    }
+}
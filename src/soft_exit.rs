@@ -0,0 +1,191 @@
+use num_decimal::Num;
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::broker::{Broker, BrokerError, BrokerOrder, OrderId, OrderRequest, OrderType, Trade};
+use crate::exit_order::cancel_and_wait;
+use crate::lot::{DisposeReason, Lot, OrderTimeInForce};
+use crate::sync_lots::{LotUpdateEvent, LotUpdateNotice};
+use crate::trade_update_client::ChannelSink;
+
+/// How often the latest trades are polled against soft stops and targets, and the symbols of the
+/// trade stream refreshed.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Open lots with a soft stop or target that has not fired yet.
+pub fn armed_lots() -> Result<Vec<Lot>, Box<dyn Error>> {
+    Ok(Lot::get_open_lots(None)?
+        .into_iter()
+        .filter(|lot| lot.has_soft_exits() && lot.soft_triggered_at.is_none())
+        .collect())
+}
+
+/// The symbols of `lots`, each once and sorted.
+fn syms_of(lots: &[Lot]) -> Vec<String> {
+    lots.iter()
+        .filter_map(|lot| lot.sym.clone())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Send the exit for a lot whose soft level was crossed. Exits still working on the broker are
+/// canceled first, and the exit only sent once they are done, since the broker will not hold the
+/// shares for two of them. Fails without sending it if one of them filled in the meantime.
+pub async fn fire(
+    broker: &dyn Broker,
+    lot: &Lot,
+    reason: DisposeReason,
+) -> Result<BrokerOrder, BrokerError> {
+    let mut live_exits: Vec<OrderId> = lot.exit_order_id.iter().cloned().collect();
+    if let Some(open_order_id) = &lot.open_order_id {
+        let order = broker.get_order(open_order_id).await?;
+        live_exits.extend(
+            order
+                .legs
+                .iter()
+                .filter(|leg| !leg.status.is_terminal())
+                .map(|leg| leg.id.clone()),
+        );
+        live_exits.extend(lot.standalone_exits(&order));
+    }
+    let canceled = cancel_and_wait(broker, &live_exits).await?;
+    if canceled
+        .iter()
+        .flat_map(BrokerOrder::flatten)
+        .any(|exit| exit.filled_quantity > Num::from(0))
+    {
+        return Err(BrokerError::Rejected(
+            "an exit of the lot filled before it could be canceled".into(),
+        ));
+    }
+
+    let level = match reason {
+        DisposeReason::Profit => lot.target_price.clone(),
+        _ => lot.stop_price.clone(),
+    };
+    let type_ = lot.soft_exit_type.unwrap_or(OrderType::Market);
+    let request = OrderRequest {
+        sym: lot.sym.clone().unwrap_or_default(),
+        side: lot.exit_side(),
+        qty: lot
+            .open_qty()
+            .or_else(|| lot.qty.clone())
+            .unwrap_or_default(),
+        type_,
+        time_in_force: Some(OrderTimeInForce::UntilCanceled),
+        limit_price: if type_ == OrderType::Limit {
+            level
+        } else {
+            None
+        },
+        ..Default::default()
+    };
+    broker.place_order(&request).await
+}
+
+/// Check the latest trade of every symbol with an armed lot, and fire the exit of each lot whose
+/// soft level was crossed. Returns a notice for every lot that fired.
+pub async fn check(broker: &dyn Broker) -> Result<Vec<LotUpdateNotice>, Box<dyn Error>> {
+    let lots = armed_lots()?;
+    if lots.is_empty() {
+        return Ok(vec![]);
+    }
+    let prices: HashMap<String, Num> = broker.latest_trades(&syms_of(&lots)).await?;
+    fire_crossed(broker, lots, &prices).await
+}
+
+/// Fire the exit of each armed lot of the streamed trade's symbol whose soft level it crossed.
+pub async fn on_trade(
+    broker: &dyn Broker,
+    trade: &Trade,
+) -> Result<Vec<LotUpdateNotice>, Box<dyn Error>> {
+    let lots: Vec<Lot> = armed_lots()?
+        .into_iter()
+        .filter(|lot| lot.sym.as_deref() == Some(trade.sym.as_str()))
+        .collect();
+    let prices = HashMap::from([(trade.sym.clone(), trade.price.clone())]);
+    fire_crossed(broker, lots, &prices).await
+}
+
+async fn fire_crossed(
+    broker: &dyn Broker,
+    lots: Vec<Lot>,
+    prices: &HashMap<String, Num>,
+) -> Result<Vec<LotUpdateNotice>, Box<dyn Error>> {
+    let mut notices = vec![];
+    for mut lot in lots {
+        let price = match lot.sym.as_ref().and_then(|sym| prices.get(sym)) {
+            Some(price) => price,
+            None => continue,
+        };
+        let reason = match lot.soft_exit_crossed(price) {
+            Some(reason) => reason,
+            None => continue,
+        };
+        tracing::debug!(
+            "soft_exit: {:?} {:?} crossed at {}",
+            lot.client_id,
+            reason,
+            price
+        );
+        match fire(broker, &lot, reason).await {
+            Ok(order) => {
+                lot.record_soft_trigger(reason, price.clone(), &order)?;
+                notices.push(LotUpdateNotice::new(lot, LotUpdateEvent::SoftExitTriggered));
+            }
+            Err(e) => tracing::error!("error firing soft exit for {:?}: {}", lot.client_id, e),
+        }
+    }
+    Ok(notices)
+}
+
+/// Watch soft stops and targets until the process exits. Trades of the symbols with armed lots are
+/// streamed from the broker and fire exits as they print. The latest trades are still polled every
+/// `POLL_INTERVAL`, which also resubscribes the stream to the armed symbols, so a lot armed between
+/// polls or a dropped stream delays an exit by at most one interval.
+pub async fn watch(broker: Arc<dyn Broker>, sink: ChannelSink) {
+    let (syms_tx, syms_rx) = async_channel::unbounded();
+    let (trade_tx, trade_rx) = async_channel::unbounded();
+    if let Err(e) = broker.stream_trades(syms_rx, trade_tx).await {
+        tracing::error!("soft_exit: no trade stream, polling only: {}", e);
+    }
+
+    let mut streamed: Vec<String> = vec![];
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        let checked = tokio::select! {
+            _ = poll.tick() => {
+                let syms = match armed_lots() {
+                    Ok(lots) => Some(syms_of(&lots)),
+                    Err(e) => {
+                        tracing::error!("error loading armed lots: {}", e);
+                        None
+                    }
+                };
+                if let Some(syms) = syms.filter(|syms| *syms != streamed) {
+                    // fails only once the stream is gone, when polling carries on alone
+                    let _ = syms_tx.send(syms.clone()).await;
+                    streamed = syms;
+                }
+                check(broker.as_ref()).await
+            }
+            Ok(trade) = trade_rx.recv() => on_trade(broker.as_ref(), &trade).await,
+        };
+        let notices = match checked {
+            Ok(notices) => notices,
+            Err(e) => {
+                tracing::error!("error checking soft exits: {}", e);
+                continue;
+            }
+        };
+        for notice in notices {
+            if sink.send(notice).await.is_err() {
+                tracing::error!("soft_exit: lot update channel closed, no longer watching");
+                return;
+            }
+        }
+    }
+}
@@ -1,32 +1,89 @@
-use apca::api::v2::order;
-use apca::{ApiInfo, Client};
+use chrono::{DateTime, Utc};
 use futures::future::join_all;
+use num_decimal::Num;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use turbosql::{select, Turbosql};
 
-use crate::lot::{Lot, LotStatus};
+use crate::broker::{Broker, BrokerError, BrokerOrder};
+use crate::execution::{Execution, ExecutionSide};
+use crate::exit_order::place_entry_exits;
+use crate::fee::Fee;
+use crate::lot::{FillProgress, Lot, LotStatus};
+use crate::lot_event::{LotEvent, LotTransition};
+use crate::tranche::Tranche;
 
-#[derive(Deserialize)]
-struct TradeUpdateMessageRoot {
-    stream: String,
-    data: TradeUpdateMessageData,
+#[derive(Deserialize, Serialize)]
+pub struct TradeUpdateMessageRoot {
+    pub stream: String,
+    pub data: TradeUpdate,
 }
 
-#[derive(Deserialize)]
-struct TradeUpdateMessageData {
-    event: LotUpdateEvent,
-    // execution_id: String,
-    // timestamp: DateTime<Utc>,
-    // price: Num,
-    // qty: u32,
-    order: order::Order,
+/// A single update to an order from the broker's trade update stream.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TradeUpdate {
+    pub event: LotUpdateEvent,
+    pub execution_id: Option<String>,
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Price of this execution, for fill events
+    pub price: Option<Num>,
+    /// Quantity of this execution, for fill events
+    pub qty: Option<Num>,
+    pub order: BrokerOrder,
+    /// The message as received from the broker, if it came off the wire
+    #[serde(skip)]
+    pub raw: Option<String>,
+}
+
+impl TradeUpdate {
+    pub fn new(event: LotUpdateEvent, order: BrokerOrder) -> Self {
+        Self {
+            event,
+            execution_id: None,
+            timestamp: None,
+            price: None,
+            qty: None,
+            order,
+            raw: None,
+        }
+    }
+
+    /// Render as a `trade_updates` stream message, the format `sync_trade_update` parses.
+    pub fn to_message(&self) -> String {
+        serde_json::json!(TradeUpdateMessageRoot {
+            stream: "trade_updates".to_string(),
+            data: self.clone(),
+        })
+        .to_string()
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct LotUpdateNotice {
     pub lot: Lot,
     pub event: LotUpdateEvent,
+    /// Filled vs ordered quantity of the opening order
+    pub fill_progress: Option<FillProgress>,
+    /// The update was for one of the lot's exits rather than its opening order
+    pub exit: bool,
+}
+
+impl LotUpdateNotice {
+    pub fn new(lot: Lot, event: LotUpdateEvent) -> Self {
+        Self {
+            fill_progress: lot.fill_progress(),
+            lot,
+            event,
+            exit: false,
+        }
+    }
+
+    pub fn for_exit(lot: Lot, event: LotUpdateEvent) -> Self {
+        Self {
+            exit: true,
+            ..Self::new(lot, event)
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
@@ -47,83 +104,302 @@ pub enum LotUpdateEvent {
     DoneForDay,
     #[serde(rename = "replaced")]
     Replaced,
+    /// Sent by zoocarp rather than the broker: a soft stop or target was crossed and its exit sent.
+    #[serde(rename = "soft_exit_triggered")]
+    SoftExitTriggered,
 }
 
 // process a trade_update message
-pub fn sync_trade_update(msg: &str) -> Result<Option<LotUpdateNotice>, Box<dyn Error>> {
-    let update_message: TradeUpdateMessageRoot = serde_json::from_str(msg)?;
+pub fn sync_trade_update(msg: &str) -> Result<Vec<LotUpdateNotice>, Box<dyn Error>> {
+    let mut update_message: TradeUpdateMessageRoot = serde_json::from_str(msg)?;
     if update_message.stream != "trade_updates" {
-        return Ok(None);
+        return Ok(vec![]);
     }
-    match update_message.data.event {
+    update_message.data.raw = Some(msg.to_string());
+    apply_trade_update(update_message.data)
+}
+
+/// Apply an update to the lot whose order it is, or to the lots it is an exit of. Returns a
+/// notice for each lot, as one sale can close several; none when the update was already applied.
+pub fn apply_trade_update(update: TradeUpdate) -> Result<Vec<LotUpdateNotice>, Box<dyn Error>> {
+    let payload = update.raw.clone().unwrap_or_else(|| update.to_message());
+    let order = &update.order;
+    let mut lot = match Lot::get_by_client_id(&order.client_order_id) {
+        Ok(lot) => lot,
+        // exits, like the legs of a bracket, have client IDs of their own
+        Err(e) => match apply_exit_update(&update)? {
+            Some(notices) => return Ok(notices),
+            None => return Err(e),
+        },
+    };
+    match update.event {
         LotUpdateEvent::Fill | LotUpdateEvent::PartialFill => {
-            let order = update_message.data.order;
-            let mut lot = Lot::get_by_client_id(&order.client_order_id)?;
-            lot.set_status_from(&order.status);
+            if Execution::exists(&lot, &update)? {
+                tracing::debug!(
+                    "sync_trade_update: duplicate execution {:?}",
+                    update.execution_id
+                );
+                return Ok(vec![]);
+            }
+            let previous_status = lot.status;
+            if lot.set_status_from(&order.status).is_err() {
+                return Ok(vec![]);
+            }
             tracing::info!(
                 "sync_trade_update: order status {:?}: {:?} {:?}",
                 order.status,
                 lot.sym,
                 order.id
             );
-            lot.qty = Some(order.filled_quantity.clone());
-            lot.filled_avg_price = order.average_fill_price.clone();
-            lot.set_cost_basis(&order.filled_quantity, &order.average_fill_price);
-            lot.update().expect("failed to update lot");
-            Ok(Some(LotUpdateNotice {
-                lot,
-                event: update_message.data.event,
-            }))
+            lot.record_fill(
+                &order.filled_quantity,
+                &order.average_fill_price,
+                update.timestamp.or(order.filled_at),
+            );
+            if Execution::record(&lot, ExecutionSide::Entry, &update)?.is_some() {
+                Fee::record_regulatory(&lot, ExecutionSide::Entry, &update)?;
+            }
+            lot.apply_fees()?;
+            lot.apply_executions()?;
+            lot.update()?;
+            LotEvent::record(
+                &lot,
+                LotTransition::TradeUpdate,
+                previous_status,
+                Some(update.event),
+                Some(payload),
+            )?;
+            Ok(vec![LotUpdateNotice::new(lot, update.event)])
         }
         _ => {
-            tracing::warn!(
-                "sync_trade_update: ignoring event {:?}",
-                update_message.data.event
+            let previous_status = lot.status;
+            tracing::info!(
+                "sync_trade_update: {:?} {:?} {:?}",
+                update.event,
+                lot.sym,
+                order.id
             );
-            Ok(None)
+            // clients hear about the event even when the lot already reflects it
+            if lot.apply_order_update(update.event, order, None)? {
+                lot.update()?;
+                LotEvent::record(
+                    &lot,
+                    LotTransition::TradeUpdate,
+                    previous_status,
+                    Some(update.event),
+                    Some(payload),
+                )?;
+            }
+            Ok(vec![LotUpdateNotice::new(lot, update.event)])
+        }
+    }
+}
+
+/// Record an exit execution against the lot that took it, with `share` of its quantity when one
+/// order closed several lots, along with the SEC and TAF fees on a sale. A lot disposed of takes
+/// its exit price from its executions.
+fn record_exit_execution(
+    lot: &mut Lot,
+    update: &TradeUpdate,
+    share: &Num,
+) -> Result<(), Box<dyn Error>> {
+    let update = TradeUpdate {
+        qty: update.qty.as_ref().map(|qty| qty * share),
+        ..update.clone()
+    };
+    if Execution::record(lot, ExecutionSide::Exit, &update)?.is_some() {
+        Fee::record_regulatory(lot, ExecutionSide::Exit, &update)?;
+    }
+    lot.apply_fees()?;
+    if lot.status == Some(LotStatus::Disposed) {
+        lot.apply_executions()?;
+    }
+    lot.update()?;
+    Ok(())
+}
+
+/// Apply an update on an exit order to the lots it belongs to, found by the exit's order ID.
+/// Returns a notice per lot, none for a fill already applied, or None if the order is not an
+/// exit of any lot.
+fn apply_exit_update(update: &TradeUpdate) -> Result<Option<Vec<LotUpdateNotice>>, Box<dyn Error>> {
+    let order = &update.order;
+    let filled = matches!(
+        update.event,
+        LotUpdateEvent::Fill | LotUpdateEvent::PartialFill
+    );
+    if let Some(mut tranche) = Tranche::get_by_order_id(&order.id)? {
+        let mut lot = Lot::get(tranche.lot_id.unwrap_or_default())?;
+        if filled {
+            if Execution::exit_exists(update)? {
+                tracing::debug!(
+                    "sync_trade_update: duplicate exit execution {:?}",
+                    update.execution_id
+                );
+                return Ok(Some(vec![]));
+            }
+            lot.track_tranche(&mut tranche, order)?;
+            record_exit_execution(&mut lot, update, &Num::from(1))?;
+        }
+        return Ok(Some(vec![LotUpdateNotice::for_exit(lot, update.event)]));
+    }
+    let lots = Lot::for_exit_order(&order.id)?;
+    if lots.is_empty() {
+        return Ok(None);
+    }
+    tracing::info!(
+        "sync_trade_update: exit {:?} {:?} for {} lots",
+        update.event,
+        order.id,
+        lots.len()
+    );
+    if filled && Execution::exit_exists(update)? {
+        tracing::debug!(
+            "sync_trade_update: duplicate exit execution {:?}",
+            update.execution_id
+        );
+        return Ok(Some(vec![]));
+    }
+    // a sale out of several lots shares its executions by the quantity each lot sold
+    let sold: Num = lots
+        .iter()
+        .filter_map(|lot| lot.qty.clone())
+        .fold(Num::from(0), |sold, qty| sold + qty);
+    let several = lots.len() > 1;
+    let mut notices = vec![];
+    for mut lot in lots {
+        if filled {
+            let share = match &lot.qty {
+                Some(qty) if several && sold > Num::from(0) => qty / &sold,
+                _ => Num::from(1),
+            };
+            lot.apply_exit_fill(order)?;
+            // a partial exit splits the lot, and the part disposed of took the execution
+            let mut disposed = match lot.status {
+                Some(LotStatus::Split) => lot
+                    .children()?
+                    .into_iter()
+                    .find(|child| child.status == Some(LotStatus::Disposed))
+                    .unwrap_or_else(|| lot.clone()),
+                _ => lot.clone(),
+            };
+            record_exit_execution(&mut disposed, update, &share)?;
+            if disposed.rowid == lot.rowid {
+                lot = disposed;
+            }
+            lot = carried_on(lot)?;
         }
+        notices.push(LotUpdateNotice::for_exit(lot, update.event));
+    }
+    Ok(Some(notices))
+}
+
+/// The lot that carries on after an exit filled: the open child if a partial fill split it.
+fn carried_on(lot: Lot) -> Result<Lot, turbosql::Error> {
+    if lot.status != Some(LotStatus::Split) {
+        return Ok(lot);
     }
+    Ok(lot
+        .children()?
+        .into_iter()
+        .find(|child| child.status == Some(LotStatus::Open))
+        .unwrap_or(lot))
 }
 
-pub async fn startup_sync() -> Result<(), Box<dyn Error>> {
-    let api_info = ApiInfo::from_env().unwrap();
-    let client = Client::new(api_info);
+/// Bring every lot not yet closed up to date with the broker. A lot that fails to sync is logged
+/// and skipped; an error is returned only when the lots cannot be loaded.
+pub async fn startup_sync(broker: &dyn Broker) -> Result<(), Box<dyn Error>> {
     let mut open_lots = select!(
-        Vec<Lot> "WHERE status != ? AND status != ? AND client_id IS NOT NULL",
+        Vec<Lot> "WHERE status != ? AND status != ? AND status != ? AND client_id IS NOT NULL",
         LotStatus::Canceled,
-        LotStatus::Disposed
-    )
-    .unwrap_or_default();
+        LotStatus::Disposed,
+        LotStatus::Split
+    )?;
     tracing::info!("Syncing {} open lots", open_lots.len());
 
-    join_all(open_lots.iter_mut().map(|lot| {
+    // a lot that fails to sync is logged and left for the next run, the others carry on
+    join_all(open_lots.iter_mut().map(|lot| async move {
         tracing::debug!("startup_sync: {:?}", lot);
+        if let Err(e) = sync_lot(broker, lot).await {
+            tracing::error!("startup_sync: {:?}: {}", lot.client_id, e);
+        }
+    }))
+    .await;
+    Ok(())
+}
 
-        async {
-            let alpaca_order = client
-                .issue::<order::GetByClientId>(&lot.client_id.as_ref().unwrap().clone())
-                .await;
-            match alpaca_order {
-                Ok(order) => {
-                    lot.fill_with(&order)
-                        .expect("failed to fill lot with order");
+/// Bring one lot up to date with its orders on the broker.
+async fn sync_lot(broker: &dyn Broker, lot: &mut Lot) -> Result<(), Box<dyn Error>> {
+    // a lot split from another has an ID of its own, the broker only knows the original
+    let broker_order = match (&lot.parent_id, &lot.open_order_id) {
+        (Some(_), Some(open_order_id)) => broker.get_order(open_order_id).await,
+        _ => {
+            broker
+                .get_order_by_client_id(lot.client_id.as_deref().unwrap_or_default())
+                .await
+        }
+    };
+    let order = match broker_order {
+        Ok(order) => order,
+        Err(e) => {
+            if let BrokerError::NotFound(_) = e {
+                let previous_status = lot.status;
+                if lot.transition_to(LotStatus::Canceled).is_ok() {
+                    lot.update()?;
+                    LotEvent::record(
+                        lot,
+                        LotTransition::SyncCanceled,
+                        previous_status,
+                        None,
+                        Some(e.to_string()),
+                    )?;
                 }
-                Err(e) => {
-                    // : startup_sync: Endpoint(NotFound(Ok(ApiError { code: 40410000, message: "order not found for e131881b-d6b0-4378-a5d5-cd419c4d3d34" })))
-                    match e.source() {
-                        Some(source) => {
-                            if source.to_string().contains("order not found") {
-                                lot.status = Some(LotStatus::Canceled);
-                                lot.update().expect("failed to update lot");
-                            }
-                        }
-                        None => {}
-                    }
-                    tracing::error!("startup_sync: {:?}", e);
+            }
+            return Err(e.into());
+        }
+    };
+    lot.fill_with(&order)?;
+    *lot = carried_on(lot.clone())?;
+    // exits placed outside the bracket, like a trailing stop or an oco pair, are not part of the
+    // opening order and have to be fetched on their own
+    if let Some(exit_order_id) = lot.exit_order_id.clone() {
+        match broker.get_order(&exit_order_id).await {
+            Ok(exit) => {
+                lot.track_oco(&exit)?;
+            }
+            Err(e) => tracing::error!("startup_sync: {:?}", e),
+        }
+    }
+    if lot.scales_out() {
+        let tranches = Tranche::for_lot(lot.rowid.unwrap_or_default())?;
+        for mut tranche in tranches {
+            let order_id = match &tranche.order_id {
+                Some(order_id) if !tranche.is_disposed() => order_id.clone(),
+                _ => continue,
+            };
+            match broker.get_order(&order_id).await {
+                Ok(exit) => {
+                    lot.track_tranche(&mut tranche, &exit)?;
                 }
+                Err(e) => tracing::error!("startup_sync: {:?}", e),
             }
         }
-    }))
-    .await;
+    }
+    for exit_id in lot.standalone_exits(&order) {
+        if lot.exit_order_id.is_some() {
+            break;
+        }
+        if lot.status != Some(LotStatus::Open) {
+            break;
+        }
+        match broker.get_order(&exit_id).await {
+            Ok(exit) => {
+                lot.track_exit(&exit)?;
+            }
+            Err(e) => tracing::error!("startup_sync: {:?}", e),
+        }
+    }
+    // the fill may have been missed on the stream, leaving the lot without the exits that wait
+    // for it
+    place_entry_exits(broker, lot).await?;
     Ok(())
 }
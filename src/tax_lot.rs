@@ -0,0 +1,253 @@
+use num_decimal::Num;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::error::Error;
+use turbosql::{ToSql, ToSqlOutput};
+
+use crate::broker::{Broker, BrokerError, BrokerOrder, OrderRequest};
+use crate::exit_order::{cancel_exits, reattach_exits};
+use crate::lot::{Lot, PositionType};
+
+/// How the lots to dispose of are chosen when selling part of a position.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum LotSelection {
+    /// First in, first out: the oldest lot first.
+    Fifo,
+    /// Last in, first out: the newest lot first.
+    Lifo,
+    /// Highest cost per share first, realizing the smallest gain.
+    HighestCost,
+    /// Lowest cost per share first, realizing the largest gain.
+    LowestCost,
+    /// The lots named in the request, in the order named.
+    Specific,
+}
+
+impl ToSql for LotSelection {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, turbosql::rusqlite::Error> {
+        Ok(ToSqlOutput::from(serde_json::json!(self).to_string()))
+    }
+}
+
+/// Cost basis per share.
+fn unit_cost(lot: &Lot) -> Option<Num> {
+    let qty = lot.qty.as_ref().filter(|qty| **qty > Num::from(0))?;
+    Some(lot.cost_basis.as_ref()? / qty)
+}
+
+/// Put `lots` in the order `selection` disposes of them. Lots of the same cost go oldest first.
+pub fn order_lots(lots: &mut [Lot], selection: LotSelection) {
+    match selection {
+        LotSelection::Fifo => lots.sort_by_key(|lot| lot.opened_at),
+        LotSelection::Lifo => lots.sort_by_key(|lot| Reverse(lot.opened_at)),
+        LotSelection::HighestCost => lots.sort_by(|a, b| {
+            unit_cost(b)
+                .cmp(&unit_cost(a))
+                .then(a.opened_at.cmp(&b.opened_at))
+        }),
+        LotSelection::LowestCost => lots.sort_by(|a, b| {
+            unit_cost(a)
+                .cmp(&unit_cost(b))
+                .then(a.opened_at.cmp(&b.opened_at))
+        }),
+        LotSelection::Specific => (),
+    }
+}
+
+/// Take `qty` from `lots` in order, returning each lot chosen with the quantity sold from it.
+/// Only the last lot chosen can be sold in part.
+pub fn allocate(lots: Vec<Lot>, qty: &Num) -> Result<Vec<(Lot, Num)>, String> {
+    let zero = Num::from(0);
+    if *qty <= zero {
+        return Err("qty must be > 0".into());
+    }
+    let mut remaining = qty.clone();
+    let mut chosen = vec![];
+    for lot in lots {
+        if remaining <= zero {
+            break;
+        }
+        let held = match lot.open_qty() {
+            Some(held) if held > zero => held,
+            _ => continue,
+        };
+        let take = if held < remaining {
+            held
+        } else {
+            remaining.clone()
+        };
+        remaining = &remaining - &take;
+        chosen.push((lot, take));
+    }
+    if remaining > zero {
+        return Err(format!(
+            "only {} shares are held in the lots to choose from",
+            qty - &remaining
+        ));
+    }
+    Ok(chosen)
+}
+
+/// Choose the open lots of `sym` in a bucket on one side of it, long to sell or short to cover,
+/// to take `qty` shares from. A Specific selection takes the lots with the client IDs given; lots
+/// that scale out are left to their tranches.
+pub fn select_lots(
+    bucket_id: i64,
+    sym: &str,
+    position_type: PositionType,
+    qty: &Num,
+    selection: LotSelection,
+    client_ids: &[String],
+) -> Result<Vec<(Lot, Num)>, Box<dyn Error>> {
+    let open: Vec<Lot> = Lot::get_open_lots(Some(bucket_id))?
+        .into_iter()
+        .filter(|lot| lot.sym.as_deref() == Some(sym))
+        .filter(|lot| lot.position_type.unwrap_or_default() == position_type)
+        .filter(|lot| !lot.scales_out())
+        .collect();
+    let mut lots = match selection {
+        LotSelection::Specific => {
+            if client_ids.is_empty() {
+                return Err("lot_ids are required for a Specific selection".into());
+            }
+            let mut lots = vec![];
+            for client_id in client_ids {
+                match open
+                    .iter()
+                    .find(|lot| lot.client_id.as_ref() == Some(client_id))
+                {
+                    Some(lot) => lots.push(lot.clone()),
+                    None => {
+                        return Err(format!(
+                            "{} is not an open lot of {} in the bucket",
+                            client_id, sym
+                        )
+                        .into())
+                    }
+                }
+            }
+            lots
+        }
+        _ => open,
+    };
+    order_lots(&mut lots, selection);
+    Ok(allocate(lots, qty)?)
+}
+
+/// Sell `request` out of the lots chosen for it, or buy it back for short lots. The broker will
+/// not hold the shares for a lot's exits and the sale at once, so the exits are canceled first,
+/// and put back if they cannot all be canceled or the sale is refused. A lot sold in part is split, and the part left open gets exits of its own. Returns
+/// the sale with the lots it touched, those left open included.
+pub async fn sell(
+    broker: &dyn Broker,
+    chosen: Vec<(Lot, Num)>,
+    request: &OrderRequest,
+    selection: LotSelection,
+) -> Result<(BrokerOrder, Vec<Lot>), BrokerError> {
+    request.validate()?;
+    let mut open_orders = vec![];
+    for (lot, _) in &chosen {
+        if let Some(open_order_id) = &lot.open_order_id {
+            open_orders.push((lot, broker.get_order(open_order_id).await?));
+        }
+    }
+    for (i, (lot, open_order)) in open_orders.iter().enumerate() {
+        if let Err(e) = cancel_exits(broker, lot, open_order).await {
+            // the lots after this one still have theirs
+            put_back_exits(broker, open_orders[..=i].iter().map(|(lot, _)| *lot)).await;
+            return Err(e);
+        }
+    }
+
+    let order = match broker.place_order(request).await {
+        Ok(order) => order,
+        Err(e) => {
+            put_back_exits(broker, chosen.iter().map(|(lot, _)| lot)).await;
+            return Err(e);
+        }
+    };
+    tracing::info!(
+        "tax_lot: sold {} {} from {} lots by {:?}, order {}",
+        request.qty,
+        request.sym,
+        chosen.len(),
+        selection,
+        order.id
+    );
+
+    let mut lots = vec![];
+    for (mut lot, qty) in chosen {
+        let open = match lot.sell_with(&qty, &order, selection) {
+            Ok(open) => open,
+            Err(e) => {
+                tracing::error!("error selling {:?}: {}", lot.client_id, e);
+                continue;
+            }
+        };
+        lots.push(lot.clone());
+        if let Some(mut open) = open {
+            if let Err(e) = reattach_exits(broker, &mut open).await {
+                tracing::error!("error reattaching exits to {:?}: {:?}", open.client_id, e);
+            }
+            lots.push(open);
+        }
+    }
+    Ok((order, lots))
+}
+
+async fn put_back_exits<'a>(broker: &dyn Broker, lots: impl Iterator<Item = &'a Lot>) {
+    for lot in lots {
+        let mut lot = lot.clone();
+        if let Err(e) = reattach_exits(broker, &mut lot).await {
+            tracing::error!("error reattaching exits to {:?}: {:?}", lot.client_id, e);
+        }
+    }
+}
+
+#[test]
+fn test_order_and_allocate() {
+    use chrono::{Duration, Utc};
+
+    let now = Utc::now();
+    let lot = |client_id: &str, days_ago: i64, qty: i64, cost: i64| Lot {
+        client_id: Some(client_id.to_string()),
+        opened_at: Some(now - Duration::days(days_ago)),
+        qty: Some(Num::from(qty)),
+        filled_qty: Some(Num::from(qty)),
+        cost_basis: Some(Num::from(qty * cost)),
+        ..Default::default()
+    };
+    let lots = vec![
+        lot("b", 20, 10, 12),
+        lot("a", 30, 10, 10),
+        lot("c", 10, 10, 11),
+    ];
+    let order = |selection| {
+        let mut lots = lots.clone();
+        order_lots(&mut lots, selection);
+        lots.into_iter()
+            .map(|lot| lot.client_id.unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(order(LotSelection::Fifo), vec!["a", "b", "c"]);
+    assert_eq!(order(LotSelection::Lifo), vec!["c", "b", "a"]);
+    assert_eq!(order(LotSelection::HighestCost), vec!["b", "c", "a"]);
+    assert_eq!(order(LotSelection::LowestCost), vec!["a", "c", "b"]);
+    assert_eq!(order(LotSelection::Specific), vec!["b", "a", "c"]);
+
+    let chosen = allocate(lots.clone(), &Num::from(15)).unwrap();
+    assert_eq!(chosen.len(), 2);
+    assert_eq!(chosen[0].1, Num::from(10));
+    assert_eq!(chosen[1].1, Num::from(5));
+
+    // first in, first out sells the oldest lot whole and the next in part
+    let mut fifo = lots.clone();
+    order_lots(&mut fifo, LotSelection::Fifo);
+    let chosen = allocate(fifo, &Num::from(15)).unwrap();
+    assert_eq!(chosen[0].0.client_id.as_deref(), Some("a"));
+    assert_eq!(chosen[0].1, Num::from(10));
+    assert_eq!(chosen[1].0.client_id.as_deref(), Some("b"));
+    assert_eq!(chosen[1].1, Num::from(5));
+    assert!(allocate(lots.clone(), &Num::from(31)).is_err());
+    assert!(allocate(lots, &Num::from(0)).is_err());
+}
@@ -7,20 +7,22 @@ use serde_json::json;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 
-use crate::sync_lots::{sync_trade_update, LotUpdateNotice};
+use crate::broker::TradeUpdateSink;
+use crate::sync_lots::{LotUpdateNotice, TradeUpdate, TradeUpdateMessageRoot};
 
 type WssStream = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
 
 pub type ChannelSink = async_channel::Sender<LotUpdateNotice>;
 pub type ChannelDrain = async_channel::Receiver<LotUpdateNotice>;
 
-pub async fn listen_for_trade_updates(tx: ChannelSink) -> Result<(), tungstenite::Error> {
-    let api_info = ApiInfo::from_env().unwrap();
-
+pub async fn listen_for_trade_updates(
+    api_info: &ApiInfo,
+    tx: TradeUpdateSink,
+) -> Result<(), tungstenite::Error> {
     let mut url = api_info.data_stream_base_url.clone();
     url.set_path("/stream");
 
-    match connect_and_authorize(&url, &api_info).await {
+    match connect_and_authorize(&url, api_info).await {
         Ok((reader, writer)) => {
             tracing::info!("Connected to trade updates stream");
             let _handle = tokio::task::spawn(read_messages(reader, tx));
@@ -44,18 +46,21 @@ async fn ping_loop(mut writer: SplitSink<WssStream, Message>) {
     }
 }
 
-async fn process_json_message<'a>(
+fn process_json_message(
     text_msg: &str,
-    update_sink: &'a ChannelSink,
-) -> Option<Result<impl Send + 'a, Box<dyn std::error::Error>>> {
+) -> Option<Result<TradeUpdate, Box<dyn std::error::Error + Send + Sync>>> {
     tracing::info!("websocket recv: {}", text_msg);
     let resp: Result<serde_json::Value, serde_json::Error> = serde_json::from_str(&text_msg);
     match resp {
         Ok(content) => {
             if content["data"].get("event").is_some() {
-                match sync_trade_update(&text_msg) {
-                    Err(e) => Some(Err(e)),
-                    Ok(notice) => Some(Ok(update_sink.send(notice.unwrap()))),
+                match serde_json::from_value::<TradeUpdateMessageRoot>(content) {
+                    Err(e) => Some(Err(Box::new(e))),
+                    Ok(mut update) if update.stream == "trade_updates" => {
+                        update.data.raw = Some(text_msg.to_string());
+                        Some(Ok(update.data))
+                    }
+                    Ok(_) => None,
                 }
             } else {
                 None
@@ -65,7 +70,7 @@ async fn process_json_message<'a>(
     }
 }
 
-async fn read_messages(mut read_sink: SplitStream<WssStream>, update_sink: ChannelSink) {
+async fn read_messages(mut read_sink: SplitStream<WssStream>, update_sink: TradeUpdateSink) {
     while let Some(message) = read_sink.next().await {
         let res = match message {
             Ok(Message::Ping(_msg)) => {
@@ -78,12 +83,12 @@ async fn read_messages(mut read_sink: SplitStream<WssStream>, update_sink: Chann
             }
             Ok(Message::Text(msg)) => {
                 let text_msg = msg.to_string();
-                process_json_message(&text_msg, &update_sink).await
+                process_json_message(&text_msg)
             }
             Ok(Message::Binary(msg)) => {
                 tracing::info!("websocket recv: {} bytes", msg.len());
                 let text = String::from_utf8_lossy(&msg);
-                process_json_message(&text, &update_sink).await
+                process_json_message(&text)
             }
             Ok(Message::Close(_msg)) => {
                 // TODO reconnect, duh
@@ -98,7 +103,11 @@ async fn read_messages(mut read_sink: SplitStream<WssStream>, update_sink: Chann
 
         match res {
             Some(Err(e)) => tracing::error!("error processing message: {:?}", e),
-            Some(_result) => (),
+            Some(Ok(update)) => {
+                if update_sink.send(update).await.is_err() {
+                    tracing::error!("read_messages: trade update channel closed");
+                }
+            }
             None => (),
         }
     }
@@ -0,0 +1,178 @@
+use chrono::{DateTime, Utc};
+use num_decimal::Num;
+use serde::Serialize;
+use turbosql::{select, Turbosql};
+
+use crate::broker::{BrokerOrder, OrderId, OrderStatus, OrderType};
+use crate::lot::{DisposeReason, Lot};
+
+/// One take-profit level of a lot that scales out. Each tranche goes to the broker as its own
+/// one-cancels-other exit, a limit at its target with a stop for the same quantity, so the stop
+/// left working shrinks as tranches fill.
+#[derive(Debug, Serialize, Turbosql, Default, Clone, PartialEq)]
+pub struct Tranche {
+    /// DB row ID
+    pub rowid: Option<i64>,
+    /// Row ID of the lot
+    pub lot_id: Option<i64>,
+    /// Local ID of the lot
+    pub client_id: Option<String>,
+    /// Position among the lot's tranches, 1 for the nearest target
+    pub seq: Option<i64>,
+    pub target_price: Option<Num>,
+    /// Quantity taken off at the target
+    pub qty: Option<Num>,
+    /// ID of the one-cancels-other exit in the broker system
+    pub order_id: Option<OrderId>,
+    pub disposed_qty: Option<Num>,
+    pub disposed_fill_price: Option<Num>,
+    pub dispose_reason: Option<DisposeReason>,
+    pub disposed_at: Option<DateTime<Utc>>,
+    /// ID of the order that disposed of the tranche: its target, its stop or a liquidation
+    pub disposing_order_id: Option<OrderId>,
+    /// ID of the stop leg of the exit in the broker system
+    pub stop_order_id: Option<OrderId>,
+}
+
+impl Tranche {
+    /// Split `qty` across `targets`, given as price and quantity, nearest target first. The last
+    /// target takes whatever the others leave, so its quantity can be left out.
+    pub fn plan(qty: &Num, targets: &[(Num, Option<Num>)]) -> Result<Vec<(Num, Num)>, String> {
+        let zero = Num::from(0);
+        let (last, rest) = match targets.split_last() {
+            Some(split) => split,
+            None => return Err("at least one target is required".into()),
+        };
+        let mut plan = vec![];
+        let mut remaining = qty.clone();
+        for (price, target_qty) in rest {
+            let target_qty = match target_qty {
+                Some(target_qty) if target_qty > &zero => target_qty,
+                _ => return Err("every target but the last needs a qty > 0".into()),
+            };
+            remaining = &remaining - target_qty;
+            plan.push((price.clone(), target_qty.clone()));
+        }
+        if remaining <= zero
+            || last
+                .1
+                .as_ref()
+                .map_or(false, |last_qty| last_qty != &remaining)
+        {
+            return Err("target quantities must add up to the lot qty".into());
+        }
+        plan.push((last.0.clone(), remaining));
+        Ok(plan)
+    }
+
+    pub fn create_for(lot: &Lot, plan: &[(Num, Num)]) -> Result<Vec<Self>, turbosql::Error> {
+        let mut tranches = vec![];
+        for (i, (price, qty)) in plan.iter().enumerate() {
+            let mut tranche = Self {
+                lot_id: lot.rowid,
+                client_id: lot.client_id.clone(),
+                seq: Some(i as i64 + 1),
+                target_price: Some(price.clone()),
+                qty: Some(qty.clone()),
+                ..Default::default()
+            };
+            tranche.rowid = Some(tranche.insert()?);
+            tranches.push(tranche);
+        }
+        Ok(tranches)
+    }
+
+    pub fn for_lot(lot_id: i64) -> Result<Vec<Self>, turbosql::Error> {
+        select!(Vec<Tranche> "WHERE lot_id = ? ORDER BY seq", lot_id)
+    }
+
+    /// The tranche whose exit, or the stop leg of it, is the order `id`.
+    pub fn get_by_order_id(id: &OrderId) -> Result<Option<Self>, turbosql::Error> {
+        let tranches = select!(Vec<Tranche> "WHERE order_id = ? OR stop_order_id = ?", id, id)?;
+        Ok(tranches.into_iter().next())
+    }
+
+    pub fn is_disposed(&self) -> bool {
+        self.disposed_at.is_some()
+    }
+
+    /// Sync the tranche with its exit. Whatever the target and the stop have filled, in part or
+    /// in full, counts as disposed, and the tranche is disposed of once one of them fills. Returns
+    /// whether the disposed quantity changed.
+    pub fn track(&mut self, order: &BrokerOrder) -> Result<bool, turbosql::Error> {
+        if self.is_disposed() {
+            return Ok(false);
+        }
+        let zero = Num::from(0);
+        let exits: Vec<BrokerOrder> = order
+            .flatten()
+            .into_iter()
+            .filter(|exit| exit.filled_quantity > zero)
+            .collect();
+        let disposed_qty = exits
+            .iter()
+            .fold(zero.clone(), |qty, exit| &qty + &exit.filled_quantity);
+        if disposed_qty == zero || self.disposed_qty.as_ref() == Some(&disposed_qty) {
+            return Ok(false);
+        }
+        let value = exits.iter().fold(Some(zero), |value, exit| {
+            match (value, &exit.average_fill_price) {
+                (Some(value), Some(price)) => Some(value + price * &exit.filled_quantity),
+                _ => None,
+            }
+        });
+        self.disposed_fill_price = value.map(|value| &value / &disposed_qty);
+        self.disposed_qty = Some(disposed_qty);
+        if let Some(exit) = exits.iter().find(|exit| exit.status == OrderStatus::Filled) {
+            self.dispose_reason = Some(match exit.type_ {
+                OrderType::Limit => DisposeReason::Profit,
+                _ => DisposeReason::StopOut,
+            });
+            self.disposed_at = Some(exit.filled_at.unwrap_or_else(Utc::now));
+            self.disposing_order_id = Some(exit.id.clone());
+        }
+        self.update()?;
+        Ok(true)
+    }
+
+    pub fn dispose_with(
+        &mut self,
+        reason: DisposeReason,
+        order: &BrokerOrder,
+    ) -> Result<(), turbosql::Error> {
+        self.disposed_qty = self.qty.clone();
+        self.disposed_fill_price = order.average_fill_price.clone();
+        self.dispose_reason = Some(reason);
+        self.disposed_at = Some(order.filled_at.unwrap_or_else(Utc::now));
+        self.disposing_order_id = Some(order.id.clone());
+        self.update()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_plan() {
+    let targets = [
+        (Num::from(11), Some(Num::from(30))),
+        (Num::from(12), Some(Num::from(30))),
+        (Num::from(13), None),
+    ];
+    let plan = Tranche::plan(&Num::from(100), &targets).unwrap();
+    assert_eq!(
+        plan,
+        vec![
+            (Num::from(11), Num::from(30)),
+            (Num::from(12), Num::from(30)),
+            (Num::from(13), Num::from(40)),
+        ]
+    );
+
+    let short = [
+        (Num::from(11), Some(Num::from(30))),
+        (Num::from(12), Some(Num::from(30))),
+    ];
+    assert!(Tranche::plan(&Num::from(100), &short).is_err());
+    let over = [(Num::from(11), Some(Num::from(100))), (Num::from(12), None)];
+    assert!(Tranche::plan(&Num::from(100), &over).is_err());
+    assert!(Tranche::plan(&Num::from(100), &[]).is_err());
+}
@@ -0,0 +1,266 @@
+use chrono::{DateTime, Duration, Utc};
+use num_decimal::Num;
+use serde::Serialize;
+use std::error::Error;
+use turbosql::{select, Turbosql};
+
+use crate::lot::{Lot, LotStatus, PositionType};
+use crate::lot_event::{LotEvent, LotTransition};
+
+/// Days before and after a sale at a loss within which buying the same symbol makes it a wash
+/// sale.
+pub const WASH_SALE_DAYS: i64 = 30;
+
+/// A loss disallowed because the same symbol was bought within `WASH_SALE_DAYS` of the sale. The
+/// loss moves onto the basis of the replacement lot, share for share.
+#[derive(Debug, Serialize, Turbosql, Default, Clone)]
+pub struct WashSale {
+    /// DB row ID
+    pub rowid: Option<i64>,
+    /// Row ID of the lot sold at a loss
+    pub lot_id: Option<i64>,
+    /// Row ID of the lot bought around the sale
+    pub replacement_id: Option<i64>,
+    pub sym: Option<String>,
+    /// Shares of the sale matched with shares of the replacement
+    pub qty: Option<Num>,
+    /// Loss disallowed on the matched shares, which is added to the replacement's basis
+    pub disallowed_loss: Option<Num>,
+    pub sold_at: Option<DateTime<Utc>>,
+    pub replaced_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl WashSale {
+    /// Wash sales the lot was sold in or bought as the replacement for.
+    pub fn for_lot(lot_id: i64) -> Result<Vec<Self>, turbosql::Error> {
+        select!(Vec<WashSale> "WHERE lot_id = ? OR replacement_id = ? ORDER BY rowid", lot_id, lot_id)
+    }
+
+    pub fn all() -> Result<Vec<Self>, turbosql::Error> {
+        select!(Vec<WashSale> "ORDER BY sold_at DESC")
+    }
+}
+
+fn total_qty(sales: &[WashSale]) -> Num {
+    sales
+        .iter()
+        .filter_map(|sale| sale.qty.clone())
+        .fold(Num::from(0), |total, qty| total + qty)
+}
+
+/// Shares of the lot already used as the replacement in a wash sale, including its share of
+/// those of the lots it was split from.
+fn replaced_qty(lot: &Lot) -> Result<Num, turbosql::Error> {
+    let mut qty = total_qty(&select!(Vec<WashSale> "WHERE replacement_id = ?", lot.rowid)?);
+    for (id, share) in lot.ancestor_shares()? {
+        let replaced = total_qty(&select!(Vec<WashSale> "WHERE replacement_id = ?", id)?);
+        qty = qty + replaced * share;
+    }
+    Ok(qty)
+}
+
+/// Row ID of the lot the lot was first split from, or its own.
+fn root_id(lot: &Lot) -> Result<Option<i64>, turbosql::Error> {
+    let mut root_id = lot.rowid;
+    let mut parent_id = lot.parent_id;
+    while let Some(id) = parent_id {
+        root_id = Some(id);
+        parent_id = select!(Lot "WHERE rowid = ?", id)?.parent_id;
+    }
+    Ok(root_id)
+}
+
+/// Match the shares of a long lot sold at a loss against lots of the same symbol opened within
+/// `WASH_SALE_DAYS` of the sale, in any bucket, earliest first. Each share of a replacement
+/// covers one share of one sale. Shares matched before are left alone, so a lot can be checked
+/// again as replacements are bought. Lots split from the same lot do not replace one another.
+pub fn detect(lot: &Lot) -> Result<Vec<WashSale>, Box<dyn Error>> {
+    let zero = Num::from(0);
+    if lot.position_type.unwrap_or_default() != PositionType::Long {
+        return Ok(vec![]);
+    }
+    // a loss moved onto the lot by an earlier wash sale carries on to this one
+    let gain = match (lot.realized(), &lot.wash_basis_adjustment) {
+        (Some(realized), Some(adjustment)) => &realized.gain - adjustment,
+        (Some(realized), None) => realized.gain,
+        (None, _) => return Ok(vec![]),
+    };
+    if gain >= zero {
+        return Ok(vec![]);
+    }
+    let (rowid, sym, sold_at, qty) = match (lot.rowid, &lot.sym, lot.disposed_at, &lot.qty) {
+        (Some(rowid), Some(sym), Some(sold_at), Some(qty)) if *qty > zero => {
+            (rowid, sym, sold_at, qty)
+        }
+        _ => return Ok(vec![]),
+    };
+    let loss_per_share = (&zero - &gain) / qty;
+    let mut remaining = qty - &total_qty(&select!(Vec<WashSale> "WHERE lot_id = ?", rowid)?);
+    if remaining <= zero {
+        return Ok(vec![]);
+    }
+
+    let window = Duration::days(WASH_SALE_DAYS);
+    let mut candidates: Vec<Lot> = select!(Vec<Lot> "WHERE sym = ? AND rowid != ?", sym, rowid)?
+        .into_iter()
+        .filter(|candidate| {
+            matches!(
+                candidate.status,
+                Some(LotStatus::Open) | Some(LotStatus::Disposed)
+            )
+        })
+        .filter(|candidate| candidate.position_type.unwrap_or_default() == PositionType::Long)
+        .filter(|candidate| {
+            candidate.opened_at.map_or(false, |opened_at| {
+                opened_at >= sold_at - window && opened_at <= sold_at + window
+            })
+        })
+        .collect();
+    candidates.sort_by_key(|candidate| candidate.opened_at);
+
+    let root = root_id(lot)?;
+    let mut sales = vec![];
+    for mut candidate in candidates {
+        if remaining <= zero {
+            break;
+        }
+        if root_id(&candidate)? == root {
+            continue;
+        }
+        let available = candidate.qty.clone().unwrap_or_default() - replaced_qty(&candidate)?;
+        if available <= zero {
+            continue;
+        }
+        let matched = if available < remaining {
+            available
+        } else {
+            remaining.clone()
+        };
+        remaining = &remaining - &matched;
+
+        let disallowed_loss = &loss_per_share * &matched;
+        let mut sale = WashSale {
+            lot_id: Some(rowid),
+            replacement_id: candidate.rowid,
+            sym: Some(sym.clone()),
+            qty: Some(matched),
+            disallowed_loss: Some(disallowed_loss.clone()),
+            sold_at: Some(sold_at),
+            replaced_at: candidate.opened_at,
+            created_at: Some(Utc::now()),
+            ..Default::default()
+        };
+        sale.rowid = Some(sale.insert()?);
+        tracing::info!(
+            "wash_sale: {} lot {} replaced by lot {:?}, {} disallowed",
+            sym,
+            rowid,
+            candidate.rowid,
+            disallowed_loss
+        );
+
+        candidate.wash_basis_adjustment = Some(match &candidate.wash_basis_adjustment {
+            Some(adjustment) => adjustment + &disallowed_loss,
+            None => disallowed_loss,
+        });
+        candidate.update()?;
+        let payload = serde_json::to_string(&sale).ok();
+        LotEvent::record(
+            &candidate,
+            LotTransition::WashSale,
+            candidate.status,
+            None,
+            payload,
+        )?;
+        sales.push(sale);
+    }
+
+    if !sales.is_empty() {
+        // the lot may have taken on a loss as a replacement since it was read
+        let mut lot = Lot::get(rowid)?;
+        let disallowed: Num = sales
+            .iter()
+            .filter_map(|sale| sale.disallowed_loss.clone())
+            .fold(
+                lot.wash_disallowed_loss.clone().unwrap_or_default(),
+                |total, loss| total + loss,
+            );
+        lot.wash_disallowed_loss = Some(disallowed);
+        lot.update()?;
+        LotEvent::record(
+            &lot,
+            LotTransition::WashSale,
+            lot.status,
+            None,
+            serde_json::to_string(&sales).ok(),
+        )?;
+    }
+    Ok(sales)
+}
+
+/// Check every disposed lot with a realized loss for wash sales. Returns the ones found by this
+/// call.
+pub fn scan() -> Result<Vec<WashSale>, Box<dyn Error>> {
+    let mut sales = vec![];
+    for lot in select!(Vec<Lot> "WHERE status = ?", LotStatus::Disposed)? {
+        sales.extend(detect(&lot)?);
+    }
+    Ok(sales)
+}
+
+#[test]
+fn test_detect_wash_sale() {
+    use crate::bucket::Bucket;
+    use crate::lot::{DisposeReason, OrderTimeInForce};
+
+    let _res = std::panic::catch_unwind(|| {
+        turbosql::execute!("DELETE FROM lot WHERE sym = ?", "WASH").unwrap();
+        turbosql::execute!("DELETE FROM washsale WHERE sym = ?", "WASH").unwrap()
+    });
+    let now = Utc::now();
+    let filled_lot = |qty: i64, price: i64, days_ago: i64| {
+        let rowid = Lot::create(
+            "WASH".to_string(),
+            Num::from(qty),
+            PositionType::Long,
+            Bucket::new("test"),
+            Some(Num::from(price)),
+            None,
+            None,
+            Some(OrderTimeInForce::Day),
+        );
+        let mut lot = Lot::get(rowid).unwrap();
+        lot.status = Some(LotStatus::Open);
+        lot.opened_at = Some(now - Duration::days(days_ago));
+        lot.record_fill(&Num::from(qty), &Some(Num::from(price)), lot.opened_at);
+        lot.update().unwrap();
+        lot
+    };
+
+    let mut sold = filled_lot(10, 100, 60);
+    sold.status = Some(LotStatus::Disposed);
+    sold.disposed_at = Some(now - Duration::days(5));
+    sold.disposed_fill_price = Some(Num::from(90));
+    sold.dispose_reason = Some(DisposeReason::Liquidation);
+    sold.update().unwrap();
+    let replacement = filled_lot(4, 91, 3);
+    let outside_window = filled_lot(10, 95, 40);
+
+    let sales = detect(&sold).unwrap();
+    assert_eq!(sales.len(), 1);
+    assert_eq!(sales[0].replacement_id, replacement.rowid);
+    assert_eq!(sales[0].qty, Some(Num::from(4)));
+    assert_eq!(sales[0].disallowed_loss, Some(Num::from(40)));
+
+    let sold = Lot::get(sold.rowid.unwrap()).unwrap();
+    assert_eq!(sold.wash_disallowed_loss, Some(Num::from(40)));
+    assert_eq!(sold.realized().unwrap().tax_gain, Num::from(-60));
+    let replacement = Lot::get(replacement.rowid.unwrap()).unwrap();
+    assert_eq!(replacement.wash_basis_adjustment, Some(Num::from(40)));
+    let outside_window = Lot::get(outside_window.rowid.unwrap()).unwrap();
+    assert_eq!(outside_window.wash_basis_adjustment, None);
+
+    // the replacement is used up, and no other lot was opened within the window
+    assert!(detect(&sold).unwrap().is_empty());
+}
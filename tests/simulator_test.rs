@@ -0,0 +1,784 @@
+use chrono::Utc;
+use num_decimal::Num;
+use turbosql::{execute, select};
+use zoocarp::broker::simulator::{SimulatedBroker, Tick};
+use zoocarp::broker::{
+    Broker, BrokerError, BrokerOrder, CashActivity, OrderClass, OrderRequest, OrderStatus,
+    OrderType, ReplaceRequest, Side,
+};
+use zoocarp::bucket::Bucket;
+use zoocarp::cash_event::{self, CashEvent, CashEventKind};
+use zoocarp::exit_order::place_entry_exits;
+use zoocarp::lot::{DisposeReason, Lot, LotStatus, OrderTimeInForce, PositionType};
+use zoocarp::price_adjustment::{AdjustmentReason, PriceAdjustment, PriceField};
+use zoocarp::soft_exit;
+use zoocarp::sync_lots::*;
+use zoocarp::tax_lot::{self, select_lots, LotSelection};
+use zoocarp::tranche::Tranche;
+
+#[cfg(test)]
+fn setup() {
+    let _res = std::panic::catch_unwind(|| execute!("DELETE FROM lot").unwrap());
+}
+
+/// A pending long lot of 100 `sym` entered at `price`, with its target 2 above and its stop 1
+/// below.
+#[cfg(test)]
+fn create_lot(sym: &str, price: i64) -> Lot {
+    let bucket = Bucket::new("test");
+    let rowid = Lot::create(
+        sym.to_string(),
+        Num::from(100),
+        PositionType::Long,
+        bucket,
+        Some(Num::from(price)),
+        Some(Num::from(price + 2)),
+        Some(Num::from(price - 1)),
+        Some(OrderTimeInForce::Day),
+    );
+    select!(Lot "WHERE rowid = ?", rowid).unwrap()
+}
+
+/// Create a lot of `sym` at `price` as `create_lot` does, and open it on the simulator with an
+/// entry of `class` filled at that price. Returns the lot with its filled entry.
+#[cfg(test)]
+async fn open_lot(
+    broker: &SimulatedBroker,
+    sym: &str,
+    price: i64,
+    class: OrderClass,
+) -> (Lot, BrokerOrder) {
+    let mut lot = create_lot(sym, price);
+    // the exits the class carries are the lot's, a one-triggers-other entry carries its target
+    let (take_profit, stop_loss) = match class {
+        OrderClass::Bracket => (lot.target_price.clone(), lot.stop_price.clone()),
+        OrderClass::OneTriggersOther => (lot.target_price.clone(), None),
+        _ => (None, None),
+    };
+    let entry = OrderRequest {
+        client_order_id: lot.client_id.clone(),
+        sym: sym.to_string(),
+        class,
+        limit_price: lot.limit_price.clone(),
+        take_profit,
+        stop_loss,
+        ..bracket_request(None)
+    };
+    let order = broker.place_order(&entry).await.unwrap();
+    broker.on_tick(sym_tick(sym, price));
+    let order = broker.get_order(&order.id).await.unwrap();
+    lot.fill_with(&order).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Open));
+    (lot, order)
+}
+
+#[cfg(test)]
+fn tick(price: i64, size: Option<i64>) -> Tick {
+    Tick {
+        timestamp: Utc::now(),
+        sym: "TEST".to_string(),
+        price: Num::from(price),
+        bid: None,
+        ask: None,
+        size: size.map(Num::from),
+    }
+}
+
+#[cfg(test)]
+fn sym_tick(sym: &str, price: i64) -> Tick {
+    Tick {
+        sym: sym.to_string(),
+        ..tick(price, None)
+    }
+}
+
+#[cfg(test)]
+fn market_sale(sym: &str, qty: i64) -> OrderRequest {
+    OrderRequest {
+        sym: sym.to_string(),
+        side: Side::Sell,
+        qty: Num::from(qty),
+        type_: OrderType::Market,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+fn bracket_request(client_id: Option<String>) -> OrderRequest {
+    OrderRequest {
+        client_order_id: client_id,
+        sym: "TEST".to_string(),
+        side: Side::Buy,
+        qty: Num::from(100),
+        class: OrderClass::Bracket,
+        type_: OrderType::Limit,
+        limit_price: Some(Num::from(10)),
+        take_profit: Some(Num::from(12)),
+        stop_loss: Some(Num::from(9)),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_simulator_fills_bracket_and_cancels_other_leg() {
+    let broker = SimulatedBroker::new();
+    let (tx, rx) = async_channel::unbounded();
+    broker.stream_updates(tx).await.unwrap();
+
+    let order = broker.place_order(&bracket_request(None)).await.unwrap();
+    assert_eq!(rx.try_recv().unwrap().event, LotUpdateEvent::New);
+
+    broker.on_tick(tick(11, None));
+    assert!(rx.try_recv().is_err());
+
+    broker.on_tick(tick(10, None));
+    let update = rx.try_recv().unwrap();
+    assert_eq!(update.event, LotUpdateEvent::Fill);
+    assert_eq!(update.order.average_fill_price, Some(Num::from(10)));
+    assert_eq!(update.order.legs[0].status, OrderStatus::New);
+
+    broker.on_tick(tick(12, None));
+    let target = rx.try_recv().unwrap();
+    assert_eq!(target.event, LotUpdateEvent::Fill);
+    assert_eq!(target.order.type_, OrderType::Limit);
+    assert_eq!(target.order.side, Side::Sell);
+    let stop = rx.try_recv().unwrap();
+    assert_eq!(stop.event, LotUpdateEvent::Canceled);
+    assert_eq!(stop.order.type_, OrderType::Stop);
+
+    let order = broker.get_order(&order.id).await.unwrap();
+    assert_eq!(order.legs[0].status, OrderStatus::Filled);
+    assert_eq!(order.legs[1].status, OrderStatus::Canceled);
+    assert!(broker.list_positions().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_simulator_updates_open_lot() {
+    setup();
+    let lot = create_lot("TEST", 10);
+    let broker = SimulatedBroker::new();
+    let (tx, rx) = async_channel::unbounded();
+    broker.stream_updates(tx).await.unwrap();
+
+    broker
+        .place_order(&bracket_request(lot.client_id.clone()))
+        .await
+        .unwrap();
+    broker.on_tick(tick(10, Some(40)));
+    broker.on_tick(tick(10, None));
+
+    let mut events = vec![];
+    while let Ok(update) = rx.try_recv() {
+        events.push(update.event);
+        sync_trade_update(&update.to_message()).unwrap();
+    }
+    assert_eq!(
+        events,
+        vec![
+            LotUpdateEvent::New,
+            LotUpdateEvent::PartialFill,
+            LotUpdateEvent::Fill
+        ]
+    );
+
+    let lot = Lot::get_by_client_id(lot.client_id.as_ref().unwrap()).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Open));
+    assert_eq!(lot.filled_avg_price, Some(Num::from(10)));
+    assert_eq!(lot.cost_basis, Some(Num::from(1000)));
+
+    let positions = broker.list_positions().await.unwrap();
+    assert_eq!(positions[0].qty, Num::from(100));
+
+    let prices = broker
+        .latest_trades(&["TEST".to_string(), "NONE".to_string()])
+        .await
+        .unwrap();
+    assert_eq!(prices.get("TEST"), Some(&Num::from(10)));
+    assert!(!prices.contains_key("NONE"));
+}
+
+#[tokio::test]
+async fn test_simulator_replaces_bracket_legs() {
+    setup();
+    let broker = SimulatedBroker::new();
+    let (mut lot, order) = open_lot(&broker, "TEST", 10, OrderClass::Bracket).await;
+
+    let request = ReplaceRequest {
+        stop_price: Some(Num::new(85, 10)),
+        ..Default::default()
+    };
+    let stop = broker
+        .replace_order(lot.stop_order_id.as_ref().unwrap(), &request)
+        .await
+        .unwrap();
+    lot.modify_bracket(Some(&stop), None, AdjustmentReason::Manual)
+        .unwrap();
+
+    let lot = Lot::get_by_client_id(lot.client_id.as_ref().unwrap()).unwrap();
+    assert_eq!(lot.stop_price, Some(Num::new(85, 10)));
+    assert_eq!(lot.stop_order_id, Some(stop.id));
+    assert_eq!(lot.target_price, Some(Num::from(12)));
+
+    let adjustments = PriceAdjustment::history(lot.client_id.as_ref().unwrap()).unwrap();
+    let stop_adjustment = adjustments.last().unwrap();
+    assert_eq!(stop_adjustment.field, Some(PriceField::Stop));
+    assert_eq!(stop_adjustment.old_price, Some(Num::from(9)));
+    assert_eq!(stop_adjustment.new_price, Some(Num::new(85, 10)));
+    assert_eq!(stop_adjustment.reason, Some(AdjustmentReason::Manual));
+
+    // the old stop no longer triggers, the new one does
+    broker.on_tick(tick(9, None));
+    let order = broker.get_order(&order.id).await.unwrap();
+    assert!(!order.legs[1].status.is_terminal());
+    broker.on_tick(tick(8, None));
+    let order = broker.get_order(&order.id).await.unwrap();
+    assert_eq!(order.legs[1].status, OrderStatus::Filled);
+}
+
+#[tokio::test]
+async fn test_simulator_stop_moved_to_entry_keeps_reason() {
+    setup();
+    let broker = SimulatedBroker::new();
+    let (mut lot, _) = open_lot(&broker, "TEST", 10, OrderClass::Bracket).await;
+    broker.on_tick(tick(11, None));
+
+    let request = ReplaceRequest {
+        stop_price: lot.filled_avg_price.clone(),
+        ..Default::default()
+    };
+    let stop = broker
+        .replace_order(lot.stop_order_id.as_ref().unwrap(), &request)
+        .await
+        .unwrap();
+    lot.modify_bracket(Some(&stop), None, AdjustmentReason::Manual)
+        .unwrap();
+
+    let adjustments = PriceAdjustment::history(lot.client_id.as_ref().unwrap()).unwrap();
+    let stop_adjustment = adjustments.last().unwrap();
+    assert_eq!(stop_adjustment.new_price, Some(Num::from(10)));
+    assert_eq!(stop_adjustment.reason, Some(AdjustmentReason::Manual));
+}
+
+#[tokio::test]
+async fn test_simulator_trailing_stop_follows_price() {
+    let broker = SimulatedBroker::new();
+    broker.on_tick(tick(10, None));
+
+    let request = OrderRequest {
+        sym: "TEST".to_string(),
+        side: Side::Sell,
+        qty: Num::from(100),
+        type_: OrderType::TrailingStop,
+        trail_price: Some(Num::from(1)),
+        ..Default::default()
+    };
+    let order = broker.place_order(&request).await.unwrap();
+    assert_eq!(order.stop_price, Some(Num::from(9)));
+
+    broker.on_tick(tick(12, None));
+    broker.on_tick(tick(11, None));
+    let order = broker.get_order(&order.id).await.unwrap();
+    assert_eq!(order.high_water_mark, Some(Num::from(12)));
+    assert_eq!(order.stop_price, Some(Num::from(11)));
+    assert_eq!(order.status, OrderStatus::Filled);
+    assert_eq!(order.average_fill_price, Some(Num::from(11)));
+}
+
+#[tokio::test]
+async fn test_simulator_oco_exit_disposes_lot() {
+    setup();
+    let broker = SimulatedBroker::new();
+    let (mut lot, _) = open_lot(&broker, "TEST", 10, OrderClass::Simple).await;
+
+    let oco = OrderRequest {
+        sym: "TEST".to_string(),
+        side: Side::Sell,
+        qty: Num::from(100),
+        class: OrderClass::OneCancelsOther,
+        type_: OrderType::Limit,
+        take_profit: Some(Num::from(12)),
+        stop_loss: Some(Num::from(9)),
+        ..Default::default()
+    };
+    let exit = broker.place_order(&oco).await.unwrap();
+    lot.track_oco(&exit).unwrap();
+    assert_eq!(lot.target_order_id, Some(exit.id.clone()));
+    assert_eq!(lot.stop_order_id, Some(exit.legs[0].id.clone()));
+
+    broker.on_tick(tick(9, None));
+    let exit = broker.get_order(&exit.id).await.unwrap();
+    assert_eq!(exit.status, OrderStatus::Canceled);
+    assert_eq!(exit.legs[0].status, OrderStatus::Filled);
+
+    lot.track_oco(&exit).unwrap();
+    let lot = Lot::get_by_client_id(lot.client_id.as_ref().unwrap()).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Disposed));
+    assert_eq!(lot.dispose_reason, Some(DisposeReason::StopOut));
+    assert_eq!(lot.disposed_fill_price, Some(Num::from(9)));
+}
+
+#[tokio::test]
+async fn test_simulator_soft_stop_fires_exit() {
+    setup();
+    let broker = SimulatedBroker::new();
+    // the target rests on the broker, the stop stays with us
+    let (mut lot, order) = open_lot(&broker, "TEST", 10, OrderClass::OneTriggersOther).await;
+    lot.soft_stop = Some(true);
+    lot.update().unwrap();
+    assert_eq!(order.legs.len(), 1);
+    assert!(lot.target_order_id.is_some());
+    assert_eq!(lot.stop_order_id, None);
+
+    broker.on_tick(tick(10, None));
+    assert!(soft_exit::check(&broker).await.unwrap().is_empty());
+
+    broker.on_tick(tick(9, None));
+    let notices = soft_exit::check(&broker).await.unwrap();
+    assert_eq!(notices.len(), 1);
+    assert_eq!(notices[0].event, LotUpdateEvent::SoftExitTriggered);
+    let mut lot = Lot::get_by_client_id(lot.client_id.as_ref().unwrap()).unwrap();
+    assert_eq!(lot.soft_trigger, Some(DisposeReason::StopOut));
+    assert_eq!(lot.soft_trigger_price, Some(Num::from(9)));
+    let order = broker.get_order(&order.id).await.unwrap();
+    assert_eq!(order.legs[0].status, OrderStatus::Canceled);
+    // fired once only
+    assert!(soft_exit::check(&broker).await.unwrap().is_empty());
+
+    broker.on_tick(tick(9, None));
+    let exit = broker
+        .get_order(lot.stop_order_id.as_ref().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(exit.type_, OrderType::Market);
+    assert_eq!(exit.status, OrderStatus::Filled);
+    lot.track_exit(&exit).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Disposed));
+    assert_eq!(lot.dispose_reason, Some(DisposeReason::StopOut));
+}
+
+#[tokio::test]
+async fn test_simulator_soft_stop_fires_on_streamed_trade() {
+    setup();
+    let broker = SimulatedBroker::new();
+    let (mut lot, _) = open_lot(&broker, "TEST", 10, OrderClass::OneTriggersOther).await;
+    lot.soft_stop = Some(true);
+    lot.update().unwrap();
+
+    let (syms_tx, syms_rx) = async_channel::unbounded();
+    let (trade_tx, trade_rx) = async_channel::unbounded();
+    broker.stream_trades(syms_rx, trade_tx).await.unwrap();
+    broker.on_tick(tick(9, None));
+    assert!(trade_rx.try_recv().is_err());
+
+    syms_tx.send(vec!["TEST".to_string()]).await.unwrap();
+    broker.on_tick(tick(10, None));
+    let trade = trade_rx.try_recv().unwrap();
+    assert!(soft_exit::on_trade(&broker, &trade)
+        .await
+        .unwrap()
+        .is_empty());
+
+    broker.on_tick(tick(9, None));
+    let trade = trade_rx.try_recv().unwrap();
+    assert_eq!(trade.price, Num::from(9));
+    let notices = soft_exit::on_trade(&broker, &trade).await.unwrap();
+    assert_eq!(notices.len(), 1);
+    let lot = Lot::get_by_client_id(lot.client_id.as_ref().unwrap()).unwrap();
+    assert_eq!(lot.soft_trigger, Some(DisposeReason::StopOut));
+    assert!(lot.stop_order_id.is_some());
+}
+
+#[tokio::test]
+async fn test_simulator_scales_out_in_tranches() {
+    setup();
+    let broker = SimulatedBroker::new();
+    let (mut lot, _) = open_lot(&broker, "TEST", 10, OrderClass::Simple).await;
+    let plan = Tranche::plan(
+        &Num::from(100),
+        &[(Num::from(11), Some(Num::from(40))), (Num::from(12), None)],
+    )
+    .unwrap();
+    Tranche::create_for(&lot, &plan).unwrap();
+    lot.tranche_count = Some(2);
+    lot.update().unwrap();
+
+    // each tranche gets its one-cancels-other exit with the lot's stop, once
+    assert!(place_entry_exits(&broker, &mut lot).await.unwrap());
+    assert!(!place_entry_exits(&broker, &mut lot).await.unwrap());
+    let mut tranches = Tranche::for_lot(lot.rowid.unwrap()).unwrap();
+    assert!(tranches
+        .iter()
+        .all(|tranche| tranche.order_id.is_some() && tranche.stop_order_id.is_some()));
+
+    // the first target fills in part, then in full
+    broker.on_tick(tick(11, Some(10)));
+    for tranche in tranches.iter_mut() {
+        let exit = broker
+            .get_order(tranche.order_id.as_ref().unwrap())
+            .await
+            .unwrap();
+        lot.track_tranche(tranche, &exit).unwrap();
+    }
+    assert!(!tranches[0].is_disposed());
+    assert_eq!(tranches[0].disposed_qty, Some(Num::from(10)));
+    assert_eq!(lot.disposed_qty, Some(Num::from(10)));
+    assert_eq!(lot.open_qty(), Some(Num::from(90)));
+
+    broker.on_tick(tick(11, None));
+    for tranche in tranches.iter_mut() {
+        let exit = broker
+            .get_order(tranche.order_id.as_ref().unwrap())
+            .await
+            .unwrap();
+        lot.track_tranche(tranche, &exit).unwrap();
+    }
+    assert_eq!(lot.status, Some(LotStatus::Open));
+    assert_eq!(tranches[0].dispose_reason, Some(DisposeReason::Profit));
+    assert_eq!(lot.disposed_qty, Some(Num::from(40)));
+    assert_eq!(lot.open_qty(), Some(Num::from(60)));
+
+    // the stop left working is the one of the second tranche
+    broker.on_tick(tick(9, None));
+    for tranche in tranches.iter_mut() {
+        let exit = broker
+            .get_order(tranche.order_id.as_ref().unwrap())
+            .await
+            .unwrap();
+        lot.track_tranche(tranche, &exit).unwrap();
+    }
+    assert_eq!(tranches[1].dispose_reason, Some(DisposeReason::StopOut));
+    assert_eq!(tranches[1].disposed_fill_price, Some(Num::from(9)));
+
+    let lot = Lot::get_by_client_id(lot.client_id.as_ref().unwrap()).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Disposed));
+    assert_eq!(lot.dispose_reason, Some(DisposeReason::StopOut));
+    assert_eq!(lot.disposed_qty, Some(Num::from(100)));
+    // 40 at 11 and 60 at 9
+    assert_eq!(lot.disposed_fill_price, Some(Num::new(98, 10)));
+}
+
+#[tokio::test]
+async fn test_simulator_dividend_counts_toward_total_return() {
+    setup();
+    let broker = SimulatedBroker::new();
+    let (lot, _) = open_lot(&broker, "DIVI", 10, OrderClass::Simple).await;
+    let rowid = lot.rowid.unwrap();
+
+    broker.on_tick(sym_tick("DIVI", 10));
+    broker.pay_dividend("DIVI", Num::new(1, 4));
+    let events = cash_event::import_from(&broker).await.unwrap();
+    let events: Vec<CashEvent> = events
+        .into_iter()
+        .filter(|event| event.lot_id == Some(rowid))
+        .collect();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind, Some(CashEventKind::Dividend));
+    assert_eq!(events[0].amount, Some(Num::from(25)));
+    // a second import finds nothing new
+    assert!(cash_event::import_from(&broker)
+        .await
+        .unwrap()
+        .iter()
+        .all(|event| event.lot_id != Some(rowid)));
+
+    let mut lot = Lot::get(rowid).unwrap();
+    assert_eq!(lot.cash_income, Some(Num::from(25)));
+
+    let exit = broker.place_order(&market_sale("DIVI", 100)).await.unwrap();
+    broker.on_tick(sym_tick("DIVI", 11));
+    lot.liquidate_with(&broker.get_order(&exit.id).await.unwrap())
+        .unwrap();
+    let realized = lot.realized().unwrap();
+    assert_eq!(realized.gain, Num::from(100));
+    assert_eq!(realized.total_return, Num::from(125));
+}
+
+#[tokio::test]
+async fn test_simulator_dividend_paid_after_sale_is_kept_for_account() {
+    setup();
+    let broker = SimulatedBroker::new();
+    let (mut lot, _) = open_lot(&broker, "DIVS", 10, OrderClass::Simple).await;
+    let rowid = lot.rowid.unwrap();
+    let exit = broker.place_order(&market_sale("DIVS", 100)).await.unwrap();
+    broker.on_tick(sym_tick("DIVS", 11));
+    lot.liquidate_with(&broker.get_order(&exit.id).await.unwrap())
+        .unwrap();
+
+    // went ex while the lot was held, paid after it was sold
+    let activity = CashActivity {
+        id: uuid::Uuid::new_v4().to_string(),
+        kind: CashEventKind::Dividend,
+        sym: Some("DIVS".to_string()),
+        qty: Some(Num::from(100)),
+        per_share_amount: Some(Num::new(1, 4)),
+        net_amount: Num::from(25),
+        description: None,
+        date: Utc::now() + chrono::Duration::days(14),
+    };
+    let events = cash_event::import_activities(&[activity]).unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].lot_id, None);
+    assert_eq!(events[0].amount, Some(Num::from(25)));
+    assert_eq!(Lot::get(rowid).unwrap().cash_income, None);
+}
+
+#[tokio::test]
+async fn test_simulator_dividend_skips_tranche_already_taken_off() {
+    setup();
+    let broker = SimulatedBroker::new();
+    let (mut lot, _) = open_lot(&broker, "DIVT", 10, OrderClass::Simple).await;
+    let rowid = lot.rowid.unwrap();
+    let plan = Tranche::plan(
+        &Num::from(100),
+        &[(Num::from(11), Some(Num::from(40))), (Num::from(12), None)],
+    )
+    .unwrap();
+    let mut tranches = Tranche::create_for(&lot, &plan).unwrap();
+    tranches[0].disposed_qty = Some(Num::from(40));
+    tranches[0].disposed_fill_price = Some(Num::from(11));
+    tranches[0].dispose_reason = Some(DisposeReason::Profit);
+    tranches[0].disposed_at = Some(Utc::now());
+    tranches[0].update().unwrap();
+    lot.tranche_count = Some(2);
+    lot.update().unwrap();
+
+    let activity = CashActivity {
+        id: uuid::Uuid::new_v4().to_string(),
+        kind: CashEventKind::Dividend,
+        sym: Some("DIVT".to_string()),
+        qty: Some(Num::from(60)),
+        per_share_amount: Some(Num::new(1, 4)),
+        net_amount: Num::from(15),
+        description: None,
+        date: Utc::now() + chrono::Duration::days(14),
+    };
+    let events = cash_event::import_activities(&[activity]).unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].lot_id, Some(rowid));
+    assert_eq!(events[0].qty, Some(Num::from(60)));
+    assert_eq!(events[0].amount, Some(Num::from(15)));
+}
+
+#[tokio::test]
+async fn test_simulator_sells_across_lots_first_in_first_out() {
+    setup();
+    let broker = SimulatedBroker::new();
+    let bucket_id = 9022;
+    let mut rowids = vec![];
+    for (days_ago, price) in [(60, 10), (30, 12)] {
+        let (mut lot, _) = open_lot(&broker, "TAXL", price, OrderClass::Simple).await;
+        lot.bucket_id = Some(bucket_id);
+        lot.opened_at = Some(Utc::now() - chrono::Duration::days(days_ago));
+        lot.update().unwrap();
+        rowids.push(lot.rowid.unwrap());
+    }
+
+    let chosen = select_lots(
+        bucket_id,
+        "TAXL",
+        PositionType::Long,
+        &Num::from(150),
+        LotSelection::Fifo,
+        &[],
+    )
+    .unwrap();
+    let sale = broker.place_order(&market_sale("TAXL", 150)).await.unwrap();
+    broker.on_tick(sym_tick("TAXL", 13));
+    let sale = broker.get_order(&sale.id).await.unwrap();
+    let mut opens = vec![];
+    for (mut lot, qty) in chosen {
+        opens.push(lot.sell_with(&qty, &sale, LotSelection::Fifo).unwrap());
+    }
+    assert!(opens[0].is_none());
+
+    let first = Lot::get(rowids[0]).unwrap();
+    assert_eq!(first.status, Some(LotStatus::Disposed));
+    assert_eq!(first.lot_selection, Some(LotSelection::Fifo));
+    assert_eq!(first.realized().unwrap().gain, Num::from(300));
+
+    let second = Lot::get(rowids[1]).unwrap();
+    assert_eq!(second.status, Some(LotStatus::Split));
+    let children = second.children().unwrap();
+    assert_eq!(children[0].status, Some(LotStatus::Disposed));
+    assert_eq!(children[0].qty, Some(Num::from(50)));
+    assert_eq!(children[0].lot_selection, Some(LotSelection::Fifo));
+    assert_eq!(children[0].realized().unwrap().gain, Num::from(50));
+    assert_eq!(children[1].status, Some(LotStatus::Open));
+    assert_eq!(children[1].qty, Some(Num::from(50)));
+    assert_eq!(children[1].lot_selection, None);
+    assert_eq!(opens[1].as_ref().unwrap().rowid, children[1].rowid);
+}
+
+#[tokio::test]
+async fn test_simulator_stop_leg_update_disposes_lot() {
+    setup();
+    let lot = create_lot("TEST", 10);
+    let client_id = lot.client_id.clone().unwrap();
+    let broker = SimulatedBroker::new();
+    let (tx, rx) = async_channel::unbounded();
+    broker.stream_updates(tx).await.unwrap();
+
+    broker
+        .place_order(&bracket_request(lot.client_id.clone()))
+        .await
+        .unwrap();
+    broker.on_tick(tick(10, None));
+    while let Ok(update) = rx.try_recv() {
+        apply_trade_update(update).unwrap();
+    }
+    let lot = Lot::get_by_client_id(&client_id).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Open));
+    assert!(lot.stop_order_id.is_some());
+
+    // the stop leg has a client ID of its own, the update finds the lot by its order ID
+    broker.on_tick(tick(9, None));
+    let stop = rx.try_recv().unwrap();
+    assert_eq!(stop.event, LotUpdateEvent::Fill);
+    assert_ne!(stop.order.client_order_id, client_id);
+    let notice = apply_trade_update(stop).unwrap().remove(0);
+    assert!(notice.exit);
+    assert_eq!(notice.lot.status, Some(LotStatus::Disposed));
+
+    let target = rx.try_recv().unwrap();
+    assert_eq!(target.event, LotUpdateEvent::Canceled);
+    assert!(apply_trade_update(target).unwrap()[0].exit);
+
+    let lot = Lot::get_by_client_id(&client_id).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Disposed));
+    assert_eq!(lot.dispose_reason, Some(DisposeReason::StopOut));
+    assert_eq!(lot.disposed_fill_price, Some(Num::from(9)));
+
+    // an order that belongs to no lot is still an error
+    let stray = broker.place_order(&bracket_request(None)).await.unwrap();
+    let stray = TradeUpdate::new(LotUpdateEvent::New, stray);
+    assert!(apply_trade_update(stray).is_err());
+}
+
+#[tokio::test]
+async fn test_simulator_refused_sale_keeps_exits() {
+    setup();
+    let broker = SimulatedBroker::new();
+    let (lot, _) = open_lot(&broker, "TEST", 10, OrderClass::Bracket).await;
+
+    let bucket_id = lot.bucket_id.unwrap();
+    let chosen = select_lots(
+        bucket_id,
+        "TEST",
+        PositionType::Long,
+        &Num::from(40),
+        LotSelection::Fifo,
+        &[],
+    )
+    .unwrap();
+    let sale = market_sale("TEST", 40);
+    broker.reject_next("TEST is halted");
+    let refused = tax_lot::sell(&broker, chosen, &sale, LotSelection::Fifo).await;
+    assert!(matches!(refused, Err(BrokerError::Rejected(_))));
+
+    // the bracket legs were canceled for the sale, and a stop and target put back in their place
+    let lot = Lot::get_by_client_id(lot.client_id.as_ref().unwrap()).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Open));
+    for id in [&lot.stop_order_id, &lot.target_order_id] {
+        let exit = broker.get_order(id.as_ref().unwrap()).await.unwrap();
+        assert!(!exit.status.is_terminal());
+    }
+    let stop = broker
+        .get_order(lot.stop_order_id.as_ref().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(stop.stop_price, Some(Num::from(9)));
+    assert_eq!(stop.qty, Some(Num::from(100)));
+}
+
+#[tokio::test]
+async fn test_simulator_entry_filled_on_placement_gets_trailing_stop() {
+    setup();
+    let mut lot = create_lot("TEST", 10);
+    lot.trail_price = Some(Num::from(1));
+    lot.update().unwrap();
+    let broker = SimulatedBroker::new();
+    broker.on_tick(tick(10, None));
+
+    // marketable against the last print, so the entry is filled when placed
+    let entry = OrderRequest {
+        class: OrderClass::Simple,
+        take_profit: None,
+        stop_loss: None,
+        ..bracket_request(lot.client_id.clone())
+    };
+    let order = broker.place_order(&entry).await.unwrap();
+    assert_eq!(order.status, OrderStatus::Filled);
+    lot.fill_with(&order).unwrap();
+    assert!(lot.entry_complete());
+
+    assert!(place_entry_exits(&broker, &mut lot).await.unwrap());
+    let stop = broker
+        .get_order(lot.stop_order_id.as_ref().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(stop.type_, OrderType::TrailingStop);
+    assert_eq!(stop.qty, Some(Num::from(100)));
+    // a later sync leaves the stop alone
+    assert!(!place_entry_exits(&broker, &mut lot).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_simulator_covers_part_of_short_lot() {
+    setup();
+    let broker = SimulatedBroker::new();
+    let rowid = Lot::create(
+        "SHRT".to_string(),
+        Num::from(100),
+        PositionType::Short,
+        Bucket::new("test"),
+        Some(Num::from(10)),
+        None,
+        None,
+        Some(OrderTimeInForce::Day),
+    );
+    let mut lot = Lot::get(rowid).unwrap();
+    let entry = OrderRequest {
+        client_order_id: lot.client_id.clone(),
+        sym: "SHRT".to_string(),
+        side: Side::Sell,
+        class: OrderClass::Simple,
+        take_profit: None,
+        stop_loss: None,
+        ..bracket_request(None)
+    };
+    let order = broker.place_order(&entry).await.unwrap();
+    broker.on_tick(sym_tick("SHRT", 10));
+    lot.fill_with(&broker.get_order(&order.id).await.unwrap())
+        .unwrap();
+
+    let bucket_id = lot.bucket_id.unwrap();
+    assert!(select_lots(
+        bucket_id,
+        "SHRT",
+        PositionType::Long,
+        &Num::from(40),
+        LotSelection::Fifo,
+        &[]
+    )
+    .is_err());
+    let chosen = select_lots(
+        bucket_id,
+        "SHRT",
+        PositionType::Short,
+        &Num::from(40),
+        LotSelection::Fifo,
+        &[],
+    )
+    .unwrap();
+    let cover = OrderRequest {
+        side: lot.exit_side(),
+        ..market_sale("SHRT", 40)
+    };
+    let (cover, lots) = tax_lot::sell(&broker, chosen, &cover, LotSelection::Fifo)
+        .await
+        .unwrap();
+    assert_eq!(cover.side, Side::Buy);
+    assert_eq!(lots.len(), 2);
+    assert_eq!(lots[1].position_type, Some(PositionType::Short));
+    assert_eq!(lots[1].open_qty(), Some(Num::from(60)));
+}
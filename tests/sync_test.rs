@@ -1,15 +1,20 @@
-use apca::api::v2::order::Order;
 use num_decimal::Num;
 use std::fs::read_to_string;
 use turbosql::{execute, select, Turbosql};
 use uuid::Uuid;
+use zoocarp::broker::{BrokerOrder, OrderClass, OrderId, OrderStatus, OrderType, Side};
 use zoocarp::bucket::Bucket;
-use zoocarp::lot::{Lot, LotStatus, OrderTimeInForce, PositionType};
+use zoocarp::execution::{Execution, ExecutionSide};
+use zoocarp::fee::{regulatory_fees, Fee};
+use zoocarp::lot::{DisposeReason, Lot, LotStatus, OrderTimeInForce, PositionType};
+use zoocarp::lot_event::{LotEvent, LotTransition};
 use zoocarp::sync_lots::*;
 
 #[cfg(test)]
 fn setup() {
     let _res = std::panic::catch_unwind(|| execute!("DELETE FROM lot").unwrap());
+    let _res = std::panic::catch_unwind(|| execute!("DELETE FROM execution").unwrap());
+    let _res = std::panic::catch_unwind(|| execute!("DELETE FROM fee").unwrap());
 }
 
 #[cfg(test)]
@@ -64,58 +69,344 @@ fn test_sync_trade_update_fill() {
     assert_eq!(lot.filled_avg_price, Some(Num::new(1354, 100)));
     assert_eq!(lot.cost_basis, Some(Num::new(121860, 100)));
     assert_eq!(lot.status, Some(LotStatus::Open));
+
+    let events = LotEvent::history(&fixture_client_id).unwrap();
+    let event = events.last().unwrap();
+    assert_eq!(event.transition, Some(LotTransition::TradeUpdate));
+    assert_eq!(event.previous_status, Some(LotStatus::Pending));
+    assert_eq!(event.status, Some(LotStatus::Open));
+    assert_eq!(event.event, Some(LotUpdateEvent::Fill));
+    assert_eq!(event.payload.as_ref(), Some(&message));
+}
+
+#[test]
+fn test_sync_trade_update_ignores_stale_partial_fill() {
+    setup();
+    let mut lot = create_lot();
+    let fixture_client_id = "c4390a00-cc88-4979-840c-7feeb08278c5".to_string();
+    lot.client_id = Some(fixture_client_id.clone());
+    lot.update().unwrap();
+
+    // the fill completing the order, for the last 50 of 90 shares
+    let message = read_to_string("tests/fixtures/update_fill.json")
+        .unwrap()
+        .replace(
+            "\"qty\" : \"90\",\n      \"timestamp\"",
+            "\"qty\" : \"50\",\n      \"timestamp\"",
+        );
+    sync_trade_update(&message).expect("sync_trade_update failed");
+
+    // the partial fill for the first 40 shares, delivered after the complete fill
+    let stale = read_to_string("tests/fixtures/update_fill.json")
+        .unwrap()
+        .replace(r#""event" : "fill""#, r#""event" : "partial_fill""#)
+        .replace(
+            "a7705ece-bb30-4f5c-9d28-843d5a6288b5",
+            "b1c2a0e4-33f1-4b9e-a8a4-6a1f0c2a9d11",
+        )
+        .replace(r#""filled_qty" : "90""#, r#""filled_qty" : "40""#)
+        .replace(
+            "\"qty\" : \"90\",\n      \"timestamp\"",
+            "\"qty\" : \"40\",\n      \"timestamp\"",
+        )
+        .replace(r#""status" : "filled""#, r#""status" : "partially_filled""#);
+    let notice = sync_trade_update(&stale)
+        .expect("sync_trade_update failed")
+        .remove(0);
+
+    let lot = Lot::get_by_client_id(&fixture_client_id).unwrap();
+    assert_eq!(lot.ordered_qty, Some(Num::from(11)));
+    assert_eq!(lot.filled_qty, Some(Num::from(90)));
+    assert_eq!(lot.qty, Some(Num::from(90)));
+    assert_eq!(lot.cost_basis, Some(Num::new(121860, 100)));
+    assert_eq!(notice.fill_progress.unwrap().filled_qty, Num::from(90));
+}
+
+#[test]
+fn test_sync_trade_update_derives_prices_from_executions() {
+    setup();
+    let mut lot = create_lot();
+    let fixture_client_id = "c4390a00-cc88-4979-840c-7feeb08278c5".to_string();
+    lot.client_id = Some(fixture_client_id.clone());
+    lot.update().unwrap();
+
+    let fill = read_to_string("tests/fixtures/update_fill.json").unwrap();
+    let partial = fill
+        .replace(r#""event" : "fill""#, r#""event" : "partial_fill""#)
+        .replace(
+            "a7705ece-bb30-4f5c-9d28-843d5a6288b5",
+            "b1c2a0e4-33f1-4b9e-a8a4-6a1f0c2a9d11",
+        )
+        .replace(r#""filled_qty" : "90""#, r#""filled_qty" : "30""#)
+        .replace(
+            r#""filled_avg_price" : "13.54""#,
+            r#""filled_avg_price" : "13""#,
+        )
+        .replace(r#""price" : "13.54""#, r#""price" : "13""#)
+        .replace(
+            "\"qty\" : \"90\",\n      \"timestamp\"",
+            "\"qty\" : \"30\",\n      \"timestamp\"",
+        )
+        .replace(r#""status" : "filled""#, r#""status" : "partially_filled""#);
+    let fill = fill
+        .replace(
+            r#""filled_avg_price" : "13.54""#,
+            r#""filled_avg_price" : "13.67""#,
+        )
+        .replace(r#""price" : "13.54""#, r#""price" : "14""#)
+        .replace(
+            "\"qty\" : \"90\",\n      \"timestamp\"",
+            "\"qty\" : \"60\",\n      \"timestamp\"",
+        );
+
+    sync_trade_update(&partial).unwrap();
+    sync_trade_update(&fill).unwrap();
+    // redelivered execution
+    assert!(sync_trade_update(&fill).unwrap().is_empty());
+
+    let lot = Lot::get_by_client_id(&fixture_client_id).unwrap();
+    assert_eq!(lot.filled_qty, Some(Num::from(90)));
+    assert_eq!(lot.cost_basis, Some(Num::from(1230)));
+    assert_eq!(lot.filled_avg_price, Some(Num::new(1230, 90)));
+}
+
+#[test]
+fn test_apply_trade_update_handles_order_events() {
+    setup();
+    let update_lot = |event: LotUpdateEvent, status: OrderStatus, filled: i64| {
+        let mut lot = create_lot();
+        lot.client_id = Some(Uuid::new_v4().to_string());
+        lot.update().unwrap();
+        let mut order = broker_order();
+        order.client_order_id = lot.client_id.clone().unwrap();
+        order.status = status;
+        order.filled_quantity = Num::from(filled);
+        if filled == 0 {
+            order.average_fill_price = None;
+            order.filled_at = None;
+        }
+        order.limit_price = Some(Num::from(100));
+        let notice = apply_trade_update(TradeUpdate::new(event, order.clone()))
+            .unwrap()
+            .remove(0);
+        assert_eq!(notice.event, event);
+        (
+            Lot::get_by_client_id(lot.client_id.as_ref().unwrap()).unwrap(),
+            order,
+        )
+    };
+
+    let (lot, _) = update_lot(LotUpdateEvent::Canceled, OrderStatus::Canceled, 0);
+    assert_eq!(lot.status, Some(LotStatus::Canceled));
+    assert_eq!(lot.status_reason.as_deref(), Some("canceled"));
+
+    let (lot, _) = update_lot(LotUpdateEvent::Rejected, OrderStatus::Rejected, 0);
+    assert_eq!(lot.status, Some(LotStatus::Canceled));
+    assert_eq!(lot.broker_status, Some(OrderStatus::Rejected));
+    assert_eq!(lot.status_reason.as_deref(), Some("rejected by the broker"));
+
+    let (lot, _) = update_lot(LotUpdateEvent::Expired, OrderStatus::Expired, 40);
+    assert_eq!(lot.status, Some(LotStatus::Open));
+    assert_eq!(lot.qty, Some(Num::from(40)));
+    assert_eq!(lot.status_reason, None);
+
+    let (lot, order) = update_lot(LotUpdateEvent::Replaced, OrderStatus::New, 0);
+    assert_eq!(lot.status, Some(LotStatus::Pending));
+    assert_eq!(lot.open_order_id, Some(order.id));
+    assert_eq!(lot.limit_price, Some(Num::from(100)));
+    let events = LotEvent::history(lot.client_id.as_ref().unwrap()).unwrap();
+    assert_eq!(events.last().unwrap().event, Some(LotUpdateEvent::Replaced));
+
+    let (lot, _) = update_lot(LotUpdateEvent::DoneForDay, OrderStatus::DoneForDay, 0);
+    assert_eq!(lot.status, Some(LotStatus::Pending));
+    assert_eq!(lot.broker_status, Some(OrderStatus::DoneForDay));
+}
+
+#[test]
+fn test_apply_trade_update_skips_duplicate_exit_fill() {
+    setup();
+    let (lot, stop) = open_lot_with_stop();
+    let update = stop_fill(&stop);
+
+    let notice = apply_trade_update(update.clone()).unwrap().remove(0);
+    assert!(notice.exit);
+    assert_eq!(notice.lot.status, Some(LotStatus::Disposed));
+    // redelivered execution
+    assert!(apply_trade_update(update).unwrap().is_empty());
+
+    let lot = Lot::get(lot.rowid.unwrap()).unwrap();
+    assert_eq!(lot.disposed_fill_price, Some(Num::new(985, 10)));
+    let exits = Execution::for_lot(lot.rowid.unwrap(), ExecutionSide::Exit).unwrap();
+    assert_eq!(exits.len(), 1);
+    assert_eq!(exits[0].qty, Some(Num::from(11)));
+}
+
+#[test]
+fn test_apply_trade_update_charges_regulatory_fees_on_stop_out() {
+    setup();
+    let (lot, stop) = open_lot_with_stop();
+    apply_trade_update(stop_fill(&stop)).unwrap();
+
+    let lot = Lot::get(lot.rowid.unwrap()).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Disposed));
+    let fees = Fee::for_lot(lot.rowid.unwrap()).unwrap();
+    assert!(fees.iter().all(|fee| fee.side == Some(ExecutionSide::Exit)));
+    let (sec, taf) = regulatory_fees(&Num::new(985, 10), &Num::from(11));
+    assert_eq!(lot.exit_fees, Some(sec + taf));
+    assert_eq!(lot.entry_fees, None);
+}
+
+#[test]
+fn test_apply_trade_update_notifies_every_lot_of_a_sale() {
+    setup();
+    let (first, stop) = open_lot_with_stop();
+    let (second, _) = open_lot_with_stop();
+    // one sale of 22 out of both lots, which disposed of them when it was sent
+    let mut sale = stop.clone();
+    sale.id = OrderId(Uuid::new_v4().to_string());
+    sale.type_ = OrderType::Market;
+    sale.qty = Some(Num::from(22));
+    for lot in [&first, &second] {
+        let mut lot = Lot::get(lot.rowid.unwrap()).unwrap();
+        lot.status = Some(LotStatus::Disposed);
+        lot.dispose_reason = Some(DisposeReason::Liquidation);
+        lot.disposing_order_id = Some(sale.id.clone());
+        lot.update().unwrap();
+    }
+    let mut update = stop_fill(&sale);
+    update.order.filled_quantity = Num::from(22);
+    update.qty = Some(Num::from(22));
+
+    let notices = apply_trade_update(update).unwrap();
+    assert_eq!(notices.len(), 2);
+    for notice in notices {
+        assert!(notice.exit);
+        assert_eq!(notice.lot.disposed_fill_price, Some(Num::new(985, 10)));
+        let exits = Execution::for_lot(notice.lot.rowid.unwrap(), ExecutionSide::Exit).unwrap();
+        assert_eq!(exits[0].qty, Some(Num::from(11)));
+    }
+}
+
+#[test]
+fn test_apply_trade_update_partial_stop_notifies_open_child() {
+    setup();
+    let (lot, stop) = open_lot_with_stop();
+    let mut update = stop_fill(&stop);
+    update.order.status = OrderStatus::PartiallyFilled;
+    update.order.filled_quantity = Num::from(4);
+    update.qty = Some(Num::from(4));
+    update.event = LotUpdateEvent::PartialFill;
+
+    let notice = apply_trade_update(update).unwrap().remove(0);
+    assert!(notice.exit);
+    assert_eq!(notice.lot.status, Some(LotStatus::Open));
+    assert_eq!(notice.lot.parent_id, lot.rowid);
+    assert_eq!(notice.lot.qty, Some(Num::from(7)));
+
+    let parent = Lot::get(lot.rowid.unwrap()).unwrap();
+    assert_eq!(parent.status, Some(LotStatus::Split));
+}
+
+#[test]
+fn test_fill_with_partial_stop_leg_records_split_once() {
+    setup();
+    let mut lot = create_lot();
+    lot.client_id = Some(Uuid::new_v4().to_string());
+    lot.update().unwrap();
+    let mut order = broker_bracket_order();
+    order.legs[1].status = OrderStatus::PartiallyFilled;
+    order.legs[1].filled_quantity = Num::from(40);
+    order.legs[1].average_fill_price = Some(Num::from(99));
+
+    lot.fill_with(&order).unwrap();
+    assert_eq!(lot.status, Some(LotStatus::Split));
+    let events = LotEvent::history(lot.client_id.as_ref().unwrap()).unwrap();
+    let transitions: Vec<_> = events.iter().filter_map(|event| event.transition).collect();
+    assert_eq!(transitions, vec![LotTransition::Split]);
+}
+
+/// An open lot of 11 bought at 101, with a standalone stop at 99.
+#[cfg(test)]
+fn open_lot_with_stop() -> (Lot, BrokerOrder) {
+    let mut lot = create_lot();
+    lot.client_id = Some(Uuid::new_v4().to_string());
+    lot.status = Some(LotStatus::Open);
+    lot.record_fill(
+        &Num::from(11),
+        &Some(Num::from(101)),
+        Some(chrono::Utc::now()),
+    );
+    let mut stop = broker_order();
+    stop.client_order_id = Uuid::new_v4().to_string();
+    stop.side = Side::Sell;
+    stop.type_ = OrderType::Stop;
+    stop.class = OrderClass::Simple;
+    stop.qty = Some(Num::from(11));
+    stop.stop_price = Some(Num::from(99));
+    lot.stop_order_id = Some(stop.id.clone());
+    lot.update().unwrap();
+    (lot, stop)
+}
+
+/// The stop filling in full, slipping to 98.50.
+#[cfg(test)]
+fn stop_fill(stop: &BrokerOrder) -> TradeUpdate {
+    let mut order = stop.clone();
+    order.status = OrderStatus::Filled;
+    order.filled_quantity = Num::from(11);
+    order.average_fill_price = Some(Num::new(985, 10));
+    TradeUpdate {
+        execution_id: Some(Uuid::new_v4().to_string()),
+        timestamp: order.filled_at,
+        price: order.average_fill_price.clone(),
+        qty: Some(Num::from(11)),
+        ..TradeUpdate::new(LotUpdateEvent::Fill, order)
+    }
 }
 
 #[cfg(test)]
-fn apca_order() -> Order {
-    Order {
-        id: apca::api::v2::order::Id(Uuid::new_v4()),
+fn broker_order() -> BrokerOrder {
+    BrokerOrder {
+        id: OrderId(Uuid::new_v4().to_string()),
         client_order_id: "c4390a00-cc88-4979-840c-7feeb08278c5".to_string(),
-        status: apca::api::v2::order::Status::Filled,
-        created_at: chrono::Utc::now(),
-        updated_at: Some(chrono::Utc::now()),
-        submitted_at: Some(chrono::Utc::now()),
-        filled_at: Some(chrono::Utc::now()),
-        expired_at: None,
-        canceled_at: None,
-        asset_class: apca::api::v2::asset::Class::UsEquity,
-        asset_id: apca::api::v2::asset::Id(Uuid::new_v4()),
-        symbol: "TEST".to_string(),
-        amount: apca::api::v2::order::Amount::quantity(100),
+        status: OrderStatus::Filled,
+        sym: "TEST".to_string(),
+        side: Side::Buy,
+        type_: OrderType::Limit,
+        class: OrderClass::Bracket,
+        qty: Some(Num::from(100)),
         filled_quantity: Num::from(100),
-        type_: apca::api::v2::order::Type::Limit,
-        class: apca::api::v2::order::Class::Bracket,
-        side: apca::api::v2::order::Side::Buy,
-        time_in_force: apca::api::v2::order::TimeInForce::UntilCanceled,
-        stop_price: Some(Num::from(0)),
+        average_fill_price: Some(Num::from(101)),
         limit_price: Some(Num::from(0)),
+        stop_price: Some(Num::from(0)),
         trail_price: None,
         trail_percent: None,
-        average_fill_price: Some(Num::from(101)),
-        extended_hours: false,
+        high_water_mark: None,
+        created_at: chrono::Utc::now(),
+        filled_at: Some(chrono::Utc::now()),
         legs: vec![],
     }
 }
 
 #[cfg(test)]
-fn apca_bracket_order() -> Order {
-    let mut order = apca_order();
+fn broker_bracket_order() -> BrokerOrder {
+    let mut order = broker_order();
 
-    let mut stop = apca_order();
-    stop.side = apca::api::v2::order::Side::Sell;
-    stop.type_ = apca::api::v2::order::Type::Stop;
+    let mut stop = broker_order();
+    stop.side = Side::Sell;
+    stop.type_ = OrderType::Stop;
     stop.filled_quantity = Num::from(0);
     stop.filled_at = None;
     stop.stop_price = Some(Num::from(99));
-    stop.status = apca::api::v2::order::Status::Held;
+    stop.status = OrderStatus::Held;
 
-    let mut limit = apca_order();
-    limit.side = apca::api::v2::order::Side::Sell;
-    limit.type_ = apca::api::v2::order::Type::Limit;
+    let mut limit = broker_order();
+    limit.side = Side::Sell;
+    limit.type_ = OrderType::Limit;
     limit.filled_quantity = Num::from(0);
     limit.filled_at = None;
     limit.limit_price = Some(Num::from(103));
-    limit.status = apca::api::v2::order::Status::New;
+    limit.status = OrderStatus::New;
 
     order.legs = vec![limit, stop];
     order